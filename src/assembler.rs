@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use super::opcodes::{self, OperandKind};
+use super::expression::parseNumber;
+
+// Assembles RGBDS style source so CPU tests don't have to be written in hex:
+//         ld b, 3
+//     .loop:
+//         dec b
+//         jr nz, .loop
+//         ld [wResult], a
+//         db "done", 0
+// Opcodes are found by matching against the operand kinds of the opcode descriptors.
+// Labels end in a colon, local ones start with a dot and belong to the last global label.
+// db, dw and ds are the only directives, there are no sections or macros.
+
+// where programs start in the ROM images built here, right after the header
+pub const ROM_ENTRY: u16 = 0x0150;
+
+const REGISTERS: [&str; 15] = ["A", "B", "C", "D", "E", "H", "L", "AF", "BC", "DE", "HL", "SP", "NZ", "Z", "NC"];
+// A is implied when these are given a single operand, "add b" is "add a, b"
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+
+#[derive(Clone, PartialEq, Debug)]
+enum Pattern {
+    // register, condition or fixed memory operand like (HL+)
+    Literal(String),
+    // bit index or rst vector
+    Number(i64),
+    Imm8,
+    Imm16,
+    Relative,
+    Signed8,
+    // (0xFF00+u8)
+    HighImm8,
+    // (u16)
+    Mem16,
+    // SP+i8
+    SpOffset,
+}
+
+struct Opcode {
+    prefixed: bool,
+    opcode: u8,
+    mnemonic: &'static str,
+    operands: Vec<Pattern>,
+    length: u16,
+}
+
+enum Operand {
+    Literal(String),
+    Memory(String),
+    SpOffset(String),
+    Expr(String),
+}
+
+pub struct Program {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+fn pattern(kind: OperandKind) -> Pattern {
+    match kind {
+        OperandKind::U8 => {Pattern::Imm8},
+        OperandKind::U16 | OperandKind::Addr16 => {Pattern::Imm16},
+        OperandKind::I8 => {Pattern::Signed8},
+        OperandKind::Rel8 => {Pattern::Relative},
+        OperandKind::AtHighU8 => {Pattern::HighImm8},
+        OperandKind::AtC => {Pattern::Literal(String::from("(C)"))},
+        OperandKind::AtU16 => {Pattern::Mem16},
+        OperandKind::SpPlusI8 => {Pattern::SpOffset},
+        OperandKind::Bit(n) | OperandKind::Vector(n) => {Pattern::Number(n as i64)},
+        _ => {Pattern::Literal(kind.name())},
+    }
+}
+
+// Every opcode there is, the CB prefix and the illegal ones left out
+fn opcodes() -> Vec<Opcode> {
+    let mut list = Vec::new();
+    for prefixed in [false, true] {
+        for i in 0..=255u8 {
+            let o = opcodes::get(prefixed, i);
+            if o.isIllegal() || o.mnemonic == "PREFIX" {
+                continue;
+            }
+            let operands = o.operands.iter().map(|k| pattern(*k)).collect();
+            list.push(Opcode {prefixed, opcode: i, mnemonic: o.mnemonic, operands, length: o.length as u16});
+        }
+    }
+    list
+}
+
+fn operand(text: &str) -> Operand {
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = compact.to_ascii_uppercase();
+    if upper.len() >= 2 && upper.starts_with('[') && upper.ends_with(']') {
+        let inner = &upper[1..upper.len() - 1];
+        return match inner {
+            "HL" | "BC" | "DE" => Operand::Literal(format!("({})", inner)),
+            "HL+" | "HLI" => Operand::Literal(String::from("(HL+)")),
+            "HL-" | "HLD" => Operand::Literal(String::from("(HL-)")),
+            "C" | "$FF00+C" | "0XFF00+C" => Operand::Literal(String::from("(C)")),
+            _ => Operand::Memory(String::from(&compact[1..compact.len() - 1])),
+        };
+    }
+    if REGISTERS.contains(&upper.as_str()) {
+        return Operand::Literal(upper);
+    }
+    if upper.starts_with("SP+") || upper.starts_with("SP-") {
+        return Operand::SpOffset(String::from(&compact[2..]));
+    }
+    Operand::Expr(compact)
+}
+
+// Splits at commas outside of string literals
+fn splitOperands(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let (mut current, mut quoted) = (String::new(), false);
+    for ch in text.chars() {
+        match ch {
+            '"' => {quoted = !quoted; current.push(ch)},
+            ',' if !quoted => {parts.push(current.trim().to_string()); current.clear()},
+            _ => {current.push(ch)},
+        }
+    }
+    if !current.trim().is_empty() || !parts.is_empty() {
+        parts.push(current.trim().to_string());
+    }
+    parts
+}
+
+fn stripComment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, ch) in line.char_indices() {
+        match ch {
+            '"' => {quoted = !quoted},
+            ';' if !quoted => {return &line[..i]},
+            _ => {},
+        }
+    }
+    line
+}
+
+struct Assembler {
+    opcodes: Vec<Opcode>,
+    labels: HashMap<String, u16>,
+    scope: String,
+    addr: u16,
+    // the first pass only sizes instructions, unknown labels count as 0 there
+    strict: bool,
+}
+
+impl Assembler {
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {format!("{}{}", self.scope, name)} else {String::from(name)}
+    }
+
+    // Numbers, labels and @ for the current address, joined by + and -
+    fn evaluate(&self, text: &str) -> Result<i64, String> {
+        let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let (mut total, mut i) = (0, 0);
+        if chars.is_empty() {
+            return Err(String::from("missing value"));
+        }
+        while i < chars.len() {
+            let mut sign = 1;
+            while i < chars.len() && (chars[i] == '+' || chars[i] == '-') {
+                if chars[i] == '-' {
+                    sign = -sign;
+                }
+                i += 1;
+            }
+            let start = i;
+            while i < chars.len() && chars[i] != '+' && chars[i] != '-' {
+                i += 1;
+            }
+            if start == i {
+                return Err(format!("bad expression {}", text));
+            }
+            let term: String = chars[start..i].iter().collect();
+            total += sign * self.term(&term)?;
+        }
+        Ok(total)
+    }
+
+    fn term(&self, text: &str) -> Result<i64, String> {
+        if text == "@" {
+            return Ok(self.addr as i64);
+        }
+        if let Some(n) = parseNumber(text) {
+            return Ok(n);
+        }
+        match self.labels.get(&self.qualify(text)) {
+            Some(a) => Ok(*a as i64),
+            None if !self.strict => Ok(0),
+            None => Err(format!("unknown label {}", text)),
+        }
+    }
+
+    fn checked(&self, value: i64, min: i64, max: i64, what: &str) -> Result<i64, String> {
+        if self.strict && (value < min || value > max) {
+            return Err(format!("{} out of range: {}", what, value));
+        }
+        Ok(value)
+    }
+
+    // Operand bytes when the operand fits the pattern, None when it's a different shape
+    fn matchOperand(&self, p: &Pattern, o: &Operand, length: u16, high: bool) -> Result<Option<Vec<u8>>, String> {
+        let bytes = match (p, o) {
+            (Pattern::Literal(a), Operand::Literal(b)) if a == b => {Vec::new()},
+            (Pattern::Number(n), Operand::Expr(e)) => {
+                if self.evaluate(e)? != *n {
+                    return Ok(None);
+                }
+                Vec::new()
+            },
+            (Pattern::Imm8, Operand::Expr(e)) => {vec![self.checked(self.evaluate(e)?, -128, 255, "byte")? as u8]},
+            (Pattern::Imm16, Operand::Expr(e)) => {
+                (self.checked(self.evaluate(e)?, -32768, 65535, "word")? as u16).to_le_bytes().to_vec()
+            },
+            (Pattern::Signed8, Operand::Expr(e)) | (Pattern::SpOffset, Operand::SpOffset(e)) => {
+                vec![self.checked(self.evaluate(e)?, -128, 127, "offset")? as i8 as u8]
+            },
+            (Pattern::Relative, Operand::Expr(e)) => {
+                let offset = self.evaluate(e)? - (self.addr as i64 + length as i64);
+                vec![self.checked(offset, -128, 127, "jump")? as i8 as u8]
+            },
+            (Pattern::Mem16, Operand::Memory(e)) if !high => {
+                (self.checked(self.evaluate(e)?, 0, 65535, "address")? as u16).to_le_bytes().to_vec()
+            },
+            // ldh takes FF00-FFFF or just the low byte
+            (Pattern::HighImm8, Operand::Memory(e)) if high => {
+                let v = self.evaluate(e)?;
+                let v = if v >= 0xFF00 {v - 0xFF00} else {v};
+                vec![self.checked(v, 0, 255, "ldh address")? as u8]
+            },
+            _ => {return Ok(None)},
+        };
+        Ok(Some(bytes))
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Vec<u8>, String> {
+        let upper = mnemonic.to_ascii_uppercase();
+        let high = upper == "LDH";
+        let name = upper.as_str();
+        let mut given: Vec<Operand> = operands.iter().map(|o| operand(o)).collect();
+        if ALU.contains(&name) && given.len() == 1 {
+            given.insert(0, Operand::Literal(String::from("A")));
+        }
+        // ld [c], a is ldh [c], a
+        let candidates = self.opcodes.iter().filter(|o| (o.mnemonic == name || (name == "LD" && o.mnemonic == "LDH"))
+            && o.operands.len() == given.len());
+        let mut lastError = None;
+        for o in candidates {
+            let (prefix, opcode) = if o.prefixed {(vec![0xCB], o.opcode)} else {(Vec::new(), o.opcode)};
+            let length = o.length;
+            let mut bytes = prefix;
+            bytes.push(opcode);
+            let mut matched = true;
+            for (p, g) in o.operands.iter().zip(given.iter()) {
+                match self.matchOperand(p, g, length, high) {
+                    Ok(Some(b)) => {bytes.extend(b)},
+                    Ok(None) => {matched = false; break},
+                    Err(e) => {lastError = Some(e); matched = false; break},
+                }
+            }
+            if matched {
+                // stop is followed by a padding byte
+                bytes.resize(length as usize, 0x00);
+                return Ok(bytes);
+            }
+        }
+        Err(lastError.unwrap_or(String::from("no such instruction")))
+    }
+
+    fn data(&self, directive: &str, operands: &[String]) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        match directive {
+            "DB" => {
+                for o in operands.iter() {
+                    if o.len() >= 2 && o.starts_with('"') && o.ends_with('"') {
+                        bytes.extend(o[1..o.len() - 1].bytes());
+                    } else {
+                        bytes.push(self.checked(self.evaluate(o)?, -128, 255, "byte")? as u8);
+                    }
+                }
+            },
+            "DW" => {
+                for o in operands.iter() {
+                    bytes.extend((self.checked(self.evaluate(o)?, -32768, 65535, "word")? as u16).to_le_bytes());
+                }
+            },
+            _ => {
+                // ds count[, fill]
+                let count = match operands.first() {
+                    Some(c) => self.evaluate(c)?,
+                    None => return Err(String::from("ds needs a size")),
+                };
+                let fill = match operands.get(1) {
+                    Some(f) => self.evaluate(f)? as u8,
+                    None => 0,
+                };
+                bytes.extend(std::iter::repeat_n(fill, count.max(0) as usize));
+            },
+        }
+        Ok(bytes)
+    }
+
+    // Labels are defined, the rest of the line assembled
+    fn line(&mut self, text: &str) -> Result<Vec<u8>, String> {
+        let mut rest = stripComment(text).trim();
+        if let Some(colon) = rest.find(':') {
+            let name = rest[..colon].trim();
+            if !name.is_empty() && !name.contains(char::is_whitespace) && !name.contains('"') {
+                if !name.starts_with('.') {
+                    self.scope = String::from(name);
+                }
+                let name = self.qualify(name);
+                if self.strict || !self.labels.contains_key(&name) {
+                    self.labels.insert(name, self.addr);
+                } else {
+                    return Err(format!("label {} defined twice", name));
+                }
+                rest = rest[colon + 1..].trim_start_matches(':').trim();
+            }
+        }
+        if rest.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (mnemonic, operands) = match rest.find(char::is_whitespace) {
+            Some(i) => (&rest[..i], splitOperands(&rest[i..])),
+            None => (rest, Vec::new()),
+        };
+        match mnemonic.to_ascii_uppercase().as_str() {
+            d @ ("DB" | "DW" | "DS") => self.data(d, &operands),
+            _ => self.instruction(mnemonic, &operands),
+        }
+    }
+}
+
+pub fn assemble(source: &str, origin: u16) -> Result<Program, String> {
+    let mut a = Assembler {
+        opcodes: opcodes(),
+        labels: HashMap::new(),
+        scope: String::new(),
+        addr: origin,
+        strict: false,
+    };
+    let mut bytes = Vec::new();
+    // the first pass finds the labels, the second one has them all
+    for strict in [false, true].iter() {
+        a.strict = *strict;
+        a.addr = origin;
+        a.scope.clear();
+        bytes.clear();
+        for (i, line) in source.lines().enumerate() {
+            let b = a.line(line).map_err(|e| format!("line {}: {}: {}", i + 1, e, line.trim()))?;
+            a.addr = a.addr.wrapping_add(b.len() as u16);
+            bytes.extend(b);
+        }
+    }
+    Ok(Program {origin, bytes, labels: a.labels})
+}
+
+// A 32 KiB ROM-only image, the entry point jumps to the program at ROM_ENTRY
+pub fn romImage(program: &Program) -> Result<Vec<u8>, String> {
+    let start = program.origin as usize;
+    if start < ROM_ENTRY as usize || start + program.bytes.len() > 0x8000 {
+        return Err(format!("program at {:04X} doesn't fit between {:04X} and 8000", start, ROM_ENTRY));
+    }
+    let mut rom = vec![0; 0x8000];
+    let [lo, hi] = program.origin.to_le_bytes();
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, lo, hi]);
+    rom[0x0134..0x013C].copy_from_slice(b"ASM TEST");
+    rom[0x014D] = rom[0x0134..= 0x014C].iter().fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+    rom[start..start + program.bytes.len()].copy_from_slice(&program.bytes);
+    Ok(rom)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{assemble, romImage, ROM_ENTRY};
+    use crate::cpu::{Z80, Flags};
+    use crate::flatram::FlatRam;
+    use crate::memory::Memory;
+    use crate::cartridge::Cartridge;
+    use crate::bus::Model;
+
+    // Assembles the snippet at ROM_ENTRY on flat RAM, returns the CPU about to run it
+    // and the address right after it
+    pub(crate) fn load(source: &str) -> (Z80<FlatRam>, u16) {
+        let program = assemble(source, ROM_ENTRY).unwrap();
+        let start = ROM_ENTRY as usize;
+        let mut ram = FlatRam::new();
+        ram.ram[start..start + program.bytes.len()].copy_from_slice(&program.bytes);
+        let mut c = Z80::withMemory(ram);
+        c.sp = 0xD000;
+        c.jump(ROM_ENTRY);
+        (c, ROM_ENTRY + program.bytes.len() as u16)
+    }
+
+    // The same on a whole machine, with the snippet in a ROM-only cartridge
+    pub(crate) fn boot(source: &str, model: Model) -> (Z80, u16) {
+        let program = assemble(source, ROM_ENTRY).unwrap();
+        let mut c = Z80::new();
+        c.bus.model = model;
+        c.bus.insertCartridge(Cartridge::fromBytes(romImage(&program).unwrap()).unwrap());
+        c.reset();
+        c.jump(ROM_ENTRY);
+        (c, ROM_ENTRY + program.bytes.len() as u16)
+    }
+
+    pub(crate) fn runTo<M: Memory>(c: &mut Z80<M>, end: u16) {
+        for _i in 0..100000 {
+            if c.pc == end {
+                return;
+            }
+            c.step();
+        }
+        panic!("still running at {:04X}", c.pc);
+    }
+
+    // Assembles the snippet on flat RAM and runs it until PC falls off the end
+    pub(crate) fn run(source: &str) -> Z80<FlatRam> {
+        let (mut c, end) = load(source);
+        runTo(&mut c, end);
+        c
+    }
+
+    #[test]
+    fn localLabelsBecomeRelativeJumps() {
+        let program = assemble("
+        Sum:
+            ld b, 5
+            xor a
+        .loop:
+            add a, b
+            dec b
+            jr nz, .loop
+        ", ROM_ENTRY).unwrap();
+        assert_eq!(program.bytes, [0x06, 0x05, 0xAF, 0x80, 0x05, 0x20, 0xFC]);
+    }
+
+    #[test]
+    fn labelsCanBeUsedBeforeTheyAreDefined() {
+        let program = assemble("
+            ld hl, $1234
+            push hl
+            pop de
+            call Increment
+            jr Done
+        Increment:
+            inc e
+            ret
+        Done:
+        ", ROM_ENTRY).unwrap();
+        let [lo, hi] = (ROM_ENTRY + 10).to_le_bytes();
+        assert_eq!(program.bytes, [0x21, 0x34, 0x12, 0xE5, 0xD1, 0xCD, lo, hi, 0x18, 0x02, 0x1C, 0xC9]);
+    }
+
+    #[test]
+    fn memoryOperands() {
+        let program = assemble("
+            daa
+            ld [hl+], a
+            ld [hl], $99
+            ld a, [$C000]
+            ldh [$FF40], a
+        ", ROM_ENTRY).unwrap();
+        assert_eq!(program.bytes, [0x27, 0x22, 0x36, 0x99, 0xFA, 0x00, 0xC0, 0xE0, 0x40]);
+    }
+
+    #[test]
+    fn imageJumpsToTheProgram() {
+        let program = assemble("nop", ROM_ENTRY).unwrap();
+        let rom = romImage(&program).unwrap();
+        let [lo, hi] = ROM_ENTRY.to_le_bytes();
+        assert_eq!(rom[0x0100..0x0104], [0x00, 0xC3, lo, hi]);
+        assert!(romImage(&assemble("nop", 0x0100).unwrap()).is_err());
+    }
+
+    #[test]
+    fn addSetsAllFlags() {
+        let c = run("
+            ld a, $3A
+            add a, $C6
+        ");
+        assert_eq!(c.a, 0x00);
+        assert!(c.getFlag(Flags::Zero));
+        assert!(!c.getFlag(Flags::Sub));
+        assert!(c.getFlag(Flags::HCarry));
+        assert!(c.getFlag(Flags::Carry));
+    }
+
+    #[test]
+    fn loopWithLocalLabel() {
+        let c = run("
+        Sum:
+            ld b, 5
+            xor a
+        .loop:
+            add a, b
+            dec b
+            jr nz, .loop
+        ");
+        assert_eq!(c.a, 15);
+        assert_eq!(c.b, 0);
+        assert!(c.getFlag(Flags::Zero));
+    }
+
+    #[test]
+    fn callReturnAndStack() {
+        let c = run("
+            ld hl, $1234
+            push hl
+            pop de
+            call Increment
+            jr Done
+        Increment:
+            inc e
+            ret
+        Done:
+        ");
+        assert_eq!((c.d, c.e), (0x12, 0x35));
+        assert_eq!(c.sp, 0xD000);
+    }
+
+    #[test]
+    fn bcdAdditionAndMemory() {
+        let c = run("
+            ld a, $45
+            add a, $38
+            daa
+            ld hl, $C000
+            ld [hl+], a
+            ld [hl], $99
+            ld a, [$C000]
+        ");
+        assert_eq!(c.a, 0x83);
+        assert!(!c.getFlag(Flags::Carry));
+        assert_eq!((c.h, c.l), (0xC0, 0x01));
+        assert_eq!(c.bus.ram[0xC001], 0x99);
+    }
+}
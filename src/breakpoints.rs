@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+use std::fmt;
+use super::expression::Expression;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Trigger {
+    // bank None matches the address in any ROM bank
+    Pc {addr: u16, bank: Option<u16>},
+    Memory {start: u16, end: u16, access: Access},
+    // value None stops on every write to the register
+    IoWrite {addr: u16, value: Option<u8>},
+    // unusable memory, unmapped I/O and writes to carts without a mapper
+    Suspicious,
+}
+
+pub struct Breakpoint {
+    pub id: usize,
+    pub trigger: Trigger,
+    pub condition: Option<Expression>,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StopReason {
+    Breakpoint {id: usize, pc: u16, bank: Option<u16>},
+    Watchpoint {id: usize, addr: u16, value: u8, access: Access},
+    IoWrite {id: usize, addr: u16, value: u8},
+    // the CPU ran an illegal opcode and hangs, not caused by a breakpoint
+    Lockup {pc: u16, opcode: u8},
+}
+
+impl StopReason {
+    // The breakpoint that fired, if any
+    pub fn id(&self) -> Option<usize> {
+        match self {
+            StopReason::Breakpoint {id, ..} => {Some(*id)},
+            StopReason::Watchpoint {id, ..} => {Some(*id)},
+            StopReason::IoWrite {id, ..} => {Some(*id)},
+            StopReason::Lockup {..} => {None},
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint {id, pc, bank: Some(b)} => write!(f, "breakpoint {} at {:02X}:{:04X}", id, b, pc),
+            StopReason::Breakpoint {id, pc, bank: None} => write!(f, "breakpoint {} at {:04X}", id, pc),
+            StopReason::Watchpoint {id, addr, value, access} => {
+                let kind = if *access == Access::Read {"read"} else {"write"};
+                write!(f, "watchpoint {}: {} of {:02X} at {:04X}", id, kind, value, addr)
+            },
+            StopReason::IoWrite {id, addr, value} => write!(f, "I/O breakpoint {}: wrote {:02X} to {:04X}", id, value, addr),
+            StopReason::Lockup {pc, opcode} => write!(f, "CPU locked up on illegal opcode {:02X} at {:04X}", opcode, pc),
+        }
+    }
+}
+
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    nextId: usize,
+    // memory and I/O hits recorded by the bus, the CPU checks their conditions once the instruction is done
+    hits: RefCell<Vec<StopReason>>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self {
+            list: Vec::new(),
+            nextId: 1,
+            hits: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&mut self, trigger: Trigger, condition: Option<Expression>) -> usize {
+        let id = self.nextId;
+        self.nextId += 1;
+        self.list.push(Breakpoint {id, trigger, condition, enabled: true});
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|b| b.id != id);
+        self.list.len() != len
+    }
+
+    pub fn setEnabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.list.iter_mut().find(|b| b.id == id) {
+            Some(b) => {b.enabled = enabled; true},
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.list.iter().find(|b| b.id == id)
+    }
+
+    pub fn onRead(&self, addr: u16, value: u8) {
+        self.onAccess(addr, value, Access::Read);
+    }
+
+    pub fn onWrite(&self, addr: u16, value: u8) {
+        self.onAccess(addr, value, Access::Write);
+    }
+
+    fn onAccess(&self, addr: u16, value: u8, access: Access) {
+        for b in self.list.iter().filter(|b| b.enabled) {
+            let hit = match b.trigger {
+                Trigger::Memory {start, end, access: watched} => {
+                    addr >= start && addr <= end && (watched == Access::ReadWrite || watched == access)
+                },
+                Trigger::IoWrite {addr: register, value: wanted} => {
+                    access == Access::Write && addr == register && wanted.is_none_or(|v| v == value)
+                },
+                Trigger::Pc {..} | Trigger::Suspicious => {false},
+            };
+            if !hit {
+                continue;
+            }
+            let reason = match b.trigger {
+                Trigger::IoWrite {..} => StopReason::IoWrite {id: b.id, addr, value},
+                _ => StopReason::Watchpoint {id: b.id, addr, value, access},
+            };
+            self.hits.borrow_mut().push(reason);
+        }
+    }
+
+    // Called by the bus on accesses that do nothing on hardware
+    pub fn onSuspicious(&self, addr: u16, value: u8, access: Access) {
+        for b in self.list.iter().filter(|b| b.enabled && b.trigger == Trigger::Suspicious) {
+            self.hits.borrow_mut().push(StopReason::Watchpoint {id: b.id, addr, value, access});
+        }
+    }
+
+    // Breakpoints on the instruction about to run
+    pub fn pcHits(&self, pc: u16, bank: Option<u16>) -> Vec<StopReason> {
+        self.list.iter()
+            .filter(|b| b.enabled)
+            .filter_map(|b| match b.trigger {
+                Trigger::Pc {addr, bank: wanted} if addr == pc && (wanted.is_none() || wanted == bank) => {
+                    Some(StopReason::Breakpoint {id: b.id, pc, bank})
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn takeHits(&self) -> Vec<StopReason> {
+        std::mem::take(&mut *self.hits.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breakpoints, Trigger, StopReason, Access};
+
+    #[test]
+    fn bankQualifiedPcBreak() {
+        let mut b = Breakpoints::new();
+        let banked = b.add(Trigger::Pc {addr: 0x4000, bank: Some(2)}, None);
+        let any = b.add(Trigger::Pc {addr: 0x0150, bank: None}, None);
+
+        assert_eq!(b.pcHits(0x4000, Some(2)), [StopReason::Breakpoint {id: banked, pc: 0x4000, bank: Some(2)}]);
+        assert!(b.pcHits(0x4000, Some(3)).is_empty());
+        assert_eq!(b.pcHits(0x0150, Some(0)).len(), 1);
+        assert_eq!(b.pcHits(0x0150, None).len(), 1);
+
+        b.setEnabled(any, false);
+        assert!(b.pcHits(0x0150, Some(0)).is_empty());
+        assert!(b.remove(banked));
+        assert!(b.pcHits(0x4000, Some(2)).is_empty());
+    }
+
+    #[test]
+    fn watchpointsMatchRangeAndAccess() {
+        let mut b = Breakpoints::new();
+        let id = b.add(Trigger::Memory {start: 0xC000, end: 0xC0FF, access: Access::Write}, None);
+        b.onRead(0xC010, 0x01);
+        b.onWrite(0xC100, 0x02);
+        assert!(b.takeHits().is_empty());
+
+        b.onWrite(0xC0FF, 0x03);
+        assert_eq!(b.takeHits(), [StopReason::Watchpoint {id, addr: 0xC0FF, value: 0x03, access: Access::Write}]);
+        // hits are only reported once
+        assert!(b.takeHits().is_empty());
+    }
+
+    #[test]
+    fn ioWriteWithValue() {
+        let mut b = Breakpoints::new();
+        let id = b.add(Trigger::IoWrite {addr: 0xFF40, value: Some(0x91)}, None);
+        b.onWrite(0xFF40, 0x11);
+        b.onRead(0xFF40, 0x91);
+        assert!(b.takeHits().is_empty());
+        b.onWrite(0xFF40, 0x91);
+        assert_eq!(b.takeHits(), [StopReason::IoWrite {id, addr: 0xFF40, value: 0x91}]);
+    }
+}
@@ -1,18 +1,61 @@
 use super::bit;
 use super::timer::{Timers};
 use super::cartridge::{Cartridge};
+use super::gpu::{Gpu, Mode};
+use super::hdma::{Hdma, BLOCK_STALL_CYCLES};
+use super::joypad::{Joypad, Button};
+use super::serial::Serial;
+use super::colorization;
+use super::sgb::{Sgb};
+use super::breakpoints::{Breakpoints, Access};
+use super::cdl::{self, CodeDataLog, Domain};
+use super::memory::Memory;
 pub struct Bus {
-    ram1: [u8; 4 * 1024],
-    ram2: [u8; 4 * 1024],
+    // bank 0 is fixed at C000, D000 maps bank 1 on DMG and banks 1-7 on CGB
+    wram: [[u8; 4 * 1024]; 8],
+    wramBank: u8,
     highRam: [u8; 127],
 
     cart: Option<Cartridge>,
+    pub model: Model,
+    pub cgbMode: bool,
+    pub gpu: Gpu,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    oamDmaSource: u8,
+    pub hdma: Hdma,
+    pub sgb: Sgb,
+    pub breakpoints: Breakpoints,
+    pub cdl: CodeDataLog,
+    // M-cycles the CPU has to wait for a CGB DMA transfer or speed switch to finish
+    pub stallCycles: u16,
+
+    // KEY1, the CPU and timers run twice as fast while the PPU keeps its pace
+    pub doubleSpeed: bool,
+    speedSwitchArmed: bool,
+    ppuPhase: bool,
 
     pub interruptEnableRegister: u8,
     pub interruptRequestRegister: u8,
     pub timerRegisters: Timers
 }
 
+// M-cycles the CPU is stopped for while the clock switches speed
+const SPEED_SWITCH_CYCLES: u16 = 2050;
+
+// FF10-FF26 with the sound off, unused bits and write-only registers read as 1
+const SOUND_READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF,
+    0x9F, 0xFF, 0xBF, 0xFF, 0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+    Sgb,
+}
+
 pub enum IntrFlags {
     VBlank = 0,
     LCD = 1,
@@ -24,11 +67,26 @@ pub enum IntrFlags {
 impl Bus {
     pub fn new() -> Self {
         Self {
-            ram1: [0; 4 * 1024],
-            ram2: [0; 4 * 1024],
+            wram: [[0; 4 * 1024]; 8],
+            wramBank: 1,
             highRam: [0; 127],
 
             cart: None,
+            model: Model::Cgb,
+            cgbMode: false,
+            gpu: Gpu::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            oamDmaSource: 0,
+            hdma: Hdma::new(),
+            sgb: Sgb::new(),
+            breakpoints: Breakpoints::new(),
+            cdl: CodeDataLog::new(),
+            stallCycles: 0,
+
+            doubleSpeed: false,
+            speedSwitchArmed: false,
+            ppuPhase: false,
 
             interruptEnableRegister: 0,
             interruptRequestRegister: 0,
@@ -37,69 +95,233 @@ impl Bus {
     }
 
     pub fn insertCartridge(&mut self, c: Cartridge) {
+        self.cgbMode = self.model == Model::Cgb && c.isCgb();
+        self.gpu.cgbMode = self.cgbMode;
         self.cart = Some(c);
     } 
 
-    pub fn cpuRead(&self, addr: u16) -> u8 {
-        match addr {
-            0x0000..= 0x3FFF => {
-                match &self.cart {
-                    Some(x) => x.readRom(addr),
-                    None => panic!("Cartridge not inserted"),
-                }
+    // Cartridge RAM as stored, empty without a cartridge or RAM
+    pub fn cartridgeRam(&self) -> &[u8] {
+        match &self.cart {
+            Some(x) => x.ram(),
+            None => &[],
+        }
+    }
+
+    // What the CGB boot ROM does for DMG cartridges, buttons held at boot override the title lookup
+    pub fn colorize(&mut self) {
+        if self.model != Model::Cgb || self.cgbMode {
+            return;
+        }
+        let combination = match colorization::combinationForButtons(self.joypad.pressed[0]) {
+            Some(c) => c,
+            None => match &self.cart {
+                Some(x) => colorization::combinationForTitle(x.title(), x.isNintendo()),
+                None => 0,
             },
-            0x4000..= 0x7FFF => {
-                match &self.cart {
-                    Some(x) => x.readRom(addr),
-                    None => panic!("Cartridge not inserted"),
+        };
+        let p = colorization::palettes(combination);
+        self.gpu.loadCompatibilityPalettes(&p.bg, &p.obj0, &p.obj1);
+    }
+
+    pub fn setButton(&mut self, player: usize, b: Button, down: bool) {
+        if self.joypad.setButton(player, b, down) {
+            self.requestInterrupt(IntrFlags::Joypad);
+        }
+    }
+
+    // Advances every component clocked by the bus by one CPU T-cycle
+    pub fn clock(&mut self) {
+        if self.timerRegisters.incrTimers() {
+            self.requestInterrupt(IntrFlags::Timer);
+        }
+        if self.serial.clock() {
+            self.requestInterrupt(IntrFlags::Serial);
+        }
+        // in double speed the PPU only sees every other CPU T-cycle
+        self.ppuPhase = !self.ppuPhase;
+        if self.doubleSpeed && self.ppuPhase {
+            return;
+        }
+        let irq = self.gpu.clock();
+        if self.model == Model::Sgb && bit::get(irq, IntrFlags::VBlank as usize) {
+            self.sgb.frameDone(&self.gpu);
+        }
+        self.interruptRequestRegister |= irq;
+        if self.gpu.hblankStarted {
+            self.gpu.hblankStarted = false;
+            if self.hdma.hblankActive {
+                self.hdmaBlock();
+            }
+        }
+    }
+
+    fn wramIndex(&self, addr: u16) -> usize {
+        if addr & 0x1000 == 0 {0} else {self.wramBank as usize}
+    }
+
+    // Called by STOP, returns true when a prepared speed switch happened
+    pub fn switchSpeed(&mut self) -> bool {
+        if !self.cgbMode || !self.speedSwitchArmed {
+            return false;
+        }
+        self.doubleSpeed = !self.doubleSpeed;
+        self.speedSwitchArmed = false;
+        self.timerRegisters.divRegister = 0;
+        self.stallCycles += SPEED_SWITCH_CYCLES;
+        true
+    }
+
+    fn startHdma(&mut self, data: u8) {
+        if self.hdma.writeControl(data) {
+            // general purpose DMA copies everything at once while the CPU waits
+            loop {
+                self.hdmaBlock();
+                if self.hdma.finished() {
+                    break;
                 }
+            }
+        }
+    }
+
+    fn hdmaBlock(&mut self) {
+        let (src, dst) = self.hdma.nextBlock();
+        for i in 0..0x10 {
+            let d = self.peek(src.wrapping_add(i));
+            self.gpu.writeVram(dst + i, d);
+        }
+        // the transfer takes the same time in both speeds, so twice the M-cycles in double speed
+        self.stallCycles += if self.doubleSpeed {BLOCK_STALL_CYCLES * 2} else {BLOCK_STALL_CYCLES};
+    }
+
+    fn oamDma(&mut self, source: u8) {
+        self.oamDmaSource = source;
+        let base = (source as u16) << 8;
+        for i in 0..0xA0 {
+            let d = self.peek(base + i);
+            self.gpu.writeOam(0xFE00 + i, d);
+        }
+    }
+
+    // ROM bank mapped at addr, None outside of ROM
+    pub fn romBank(&self, addr: u16) -> Option<u16> {
+        match (addr, &self.cart) {
+            (0x0000..= 0x3FFF, Some(x)) => Some(x.lowRomBank()),
+            (0x0000..= 0x3FFF, None) => Some(0),
+            (0x4000..= 0x7FFF, Some(x)) => Some(x.romBank()),
+            _ => None,
+        }
+    }
+
+    // Bank mapped at addr the way RGBDS numbers them, 0 where nothing is switched
+    pub fn memoryBank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..= 0x7FFF => {self.romBank(addr).unwrap_or(1)},
+            0x8000..= 0x9FFF => {self.gpu.vramBank as u16},
+            0xD000..= 0xDFFF => {self.wramBank as u16},
+            _ => {0},
+        }
+    }
+
+    // CPU read, flag tells the code/data log whether it's an opcode, an operand or data
+    pub fn cpuFetch(&self, addr: u16, flag: u8) -> u8 {
+        let d = self.peek(addr);
+        // read watchpoints are about data, not the code running through them
+        if flag == cdl::DATA {
+            self.breakpoints.onRead(addr, d);
+        }
+        if self.isSuspicious(addr, false) {
+            self.breakpoints.onSuspicious(addr, d, Access::Read);
+        }
+        self.logAccess(addr, flag);
+        d
+    }
+
+    // Logs to game.cdl next to the ROM from now on
+    pub fn startCodeDataLog(&mut self, romPath: &str) {
+        let (romLen, ramLen) = match &self.cart {
+            Some(x) => (x.romLen(), x.ramLen()),
+            None => (0, 0),
+        };
+        self.cdl = CodeDataLog::open(romPath, romLen, ramLen);
+    }
+
+    // Where addr lands in the code/data log, None for memory it doesn't cover
+    fn cdlLocation(&self, addr: u16) -> Option<(Domain, usize)> {
+        match addr {
+            0x0000..= 0x7FFF => {self.romBank(addr).map(|b| (Domain::Rom, b as usize * 0x4000 + (addr & 0x3FFF) as usize))},
+            0xA000..= 0xBFFF => {Some((Domain::CartRam, (addr - 0xA000) as usize))},
+            0xC000..= 0xFDFF => {Some((Domain::Wram, self.wramIndex(addr) * 0x1000 + (addr & 0x0FFF) as usize))},
+            0xFF80..= 0xFFFE => {Some((Domain::Hram, (addr - 0xFF80) as usize))},
+            _ => {None},
+        }
+    }
+
+    fn logAccess(&self, addr: u16, flag: u8) {
+        if !self.cdl.enabled() {
+            return;
+        }
+        if let Some((domain, offset)) = self.cdlLocation(addr) {
+            self.cdl.log(domain, offset, flag);
+        }
+    }
+
+    // Logged flags of the byte currently mapped at addr
+    pub fn cdlFlags(&self, addr: u16) -> u8 {
+        match self.cdlLocation(addr) {
+            Some((domain, offset)) => self.cdl.flags(domain, offset),
+            None => 0,
+        }
+    }
+
+    // Reads without triggering watchpoints, for DMA and the debug views
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            // with no cartridge inserted nothing drives the bus
+            0x0000..= 0x7FFF => {
+                self.cart.as_ref().map_or(0xFF, |x| x.readRom(addr))
             },
             0x8000..= 0x9FFF => {
-                todo!("Vram not implemented")
+                self.gpu.readVram(addr)
             },
             0xA000..= 0xBFFF => {
-                match &self.cart {
-                    Some(x) => x.readRam(addr),
-                    None => panic!("Cartridge not inserted"),
-                }
-            },
-            0xC000..= 0xCFFF => {self.ram1[(addr & 0x0fff) as usize]},
-            0xD000..= 0xDFFF => {self.ram2[(addr & 0x0fff) as usize]},
-            0xE000..= 0xFDFF => {
-                if addr <= 0xEFFF {
-                    self.ram1[(addr & 0x0fff) as usize]
-                } else {
-                    self.ram2[(addr & 0x0fff) as usize]
-                }
+                self.cart.as_ref().map_or(0xFF, |x| x.readRam(addr))
             },
+            0xC000..= 0xDFFF => {self.wram[self.wramIndex(addr)][(addr & 0x0fff) as usize]},
+            0xE000..= 0xFDFF => {self.wram[self.wramIndex(addr)][(addr & 0x0fff) as usize]},
             0xFE00..= 0xFE9F => {
-                todo!("Sprite table not implemented");
+                self.gpu.readOam(addr)
             },
             0xFEA0..= 0xFEFF => {
-                panic!("Unusable memory")
+                self.unusableRead(addr)
             },
             0xFF00..= 0xFF7F => {
                 match addr & 0x00FF {
-                    0x00 => {todo!("Controller not implemented")},
-                    0x01..= 0x02 => {todo!("Communication not implemented")},
+                    0x00 => {self.joypad.read()},
+                    0x01..= 0x02 => {self.serial.read(addr)},
                     0x04..= 0x07 => {
                         match addr & 0x000F {
                             0x4 => {((self.timerRegisters.divRegister & 0xFF00) >> 8) as u8},
                             0x5 => {self.timerRegisters.timaRegister},
                             0x6 => {self.timerRegisters.tmaRegister},
-                            0x7 => {self.timerRegisters.tacRegister},
+                            0x7 => {0xF8 | self.timerRegisters.tacRegister},
                             _ => {0}
                         }},
-                    0x0F => {self.interruptRequestRegister},
-                    0x10..= 0x26 => {/* Sound, not implementing*/0},
+                    0x0F => {0xE0 | self.interruptRequestRegister},
+                    0x10..= 0x26 => {/* Sound, not implementing*/SOUND_READ_MASKS[(addr & 0x00FF) as usize - 0x10]},
                     0x30..= 0x3F => {/* Waveform RAM, not implementing*/0},
-                    0x40..= 0x4B => {todo!("LCD register not implemented")},
-                    0x4F => {/* GBC VRAM Bank Select */0},
-                    0x50 => {/* Set to disable boot ROM ??*/0},
-                    0x51..= 0x55 => {/* GBC HDMA */0},
-                    0x68..= 0x69 => {/* GBC BCP/OCP */0},
-                    0x70 => {/* GBC WRAM Bank Select */0}
-                    _ => {panic!("Unknown write to {}", addr)}
+                    0x46 => {self.oamDmaSource},
+                    0x40..= 0x4B => {self.gpu.readRegister(addr)},
+                    0x4D if self.cgbMode => {
+                        0x7E | ((self.doubleSpeed as u8) << 7) | (self.speedSwitchArmed as u8)
+                    },
+                    0x4F => {self.gpu.readRegister(addr)},
+                    0x50 => {/* Set to disable boot ROM ??*/0xFF},
+                    0x51..= 0x55 if self.cgbMode => {self.hdma.read(addr)},
+                    0x68..= 0x6B => {self.gpu.readRegister(addr)},
+                    0x70 if self.cgbMode => {0xF8 | self.wramBank},
+                    // unmapped, and the CGB registers on DMG
+                    _ => {0xFF}
                 }
             },
             0xFF80..= 0xFFFE => {
@@ -111,42 +333,78 @@ impl Bus {
         }
     }
 
+    // FEA0-FEFF, what comes back depends on the model and on whether the PPU holds OAM
+    fn unusableRead(&self, addr: u16) -> u8 {
+        if self.gpu.lcdEnabled() && matches!(self.gpu.mode(), Mode::OamScan | Mode::Drawing) {
+            return 0xFF;
+        }
+        match self.model {
+            // CGB revision E repeats the high nibble of the low address byte
+            Model::Cgb => {let n = (addr as u8) >> 4; n << 4 | n},
+            _ => {0x00},
+        }
+    }
+
+    fn ioMapped(&self, addr: u16) -> bool {
+        match addr & 0x00FF {
+            0x00..= 0x02 | 0x04..= 0x07 | 0x0F | 0x10..= 0x14 | 0x16..= 0x1E | 0x20..= 0x26 | 0x30..= 0x4B | 0x50 => {true},
+            0x4D | 0x4F | 0x51..= 0x55 | 0x68..= 0x6B | 0x70 => {self.cgbMode},
+            _ => {false},
+        }
+    }
+
+    // Accesses real hardware shrugs off but that are likely bugs in the game,
+    // they stop the debugger when it watches for them
+    fn isSuspicious(&self, addr: u16, write: bool) -> bool {
+        match addr {
+            0x0000..= 0x7FFF => {write && self.cart.as_ref().is_some_and(|c| !c.hasMapper())},
+            0xFEA0..= 0xFEFF => {true},
+            0xFF00..= 0xFF7F => {!self.ioMapped(addr)},
+            _ => {false},
+        }
+    }
+
     pub fn cpuWrite(&mut self, addr: u16, data: u8) {
+        self.breakpoints.onWrite(addr, data);
+        if self.isSuspicious(addr, true) {
+            self.breakpoints.onSuspicious(addr, data, Access::Write);
+        }
+        // writes below 8000 go to the mapper, not to ROM
+        if addr >= 0x8000 {
+            self.logAccess(addr, cdl::WRITTEN);
+        }
         match addr {
-            0x0000..= 0x3FFF => {panic!("Tried to write to ROM")},
-            0x4000..= 0x7FFF => {panic!("Tried to write to ROM")},
+            0x0000..= 0x7FFF => {
+                if let Some(x) = &mut self.cart {
+                    x.writeRom(addr, data);
+                }
+            },
             0x8000..= 0x9FFF => {
-                todo!("Vram not implemented")
+                self.gpu.writeVram(addr, data);
             },
             0xA000..= 0xBFFF => {
-                match &mut self.cart {
-                    Some(x) => x.writeRam(addr, data),
-                    None => panic!("Cartridge not inserted"),
+                if let Some(x) = &mut self.cart {
+                    x.writeRam(addr, data);
                 }
             },
-            0xC000..= 0xCFFF => {
-                self.ram1[(addr & 0x0fff) as usize] = data;
-            },
-            0xD000..= 0xDFFF => {
-                self.ram2[(addr & 0x0fff) as usize] = data;
-            },
-            0xE000..= 0xFDFF => {
-                if addr <= 0xEFFF {
-                    self.ram1[(addr & 0x0fff) as usize] = data;
-                } else {
-                    self.ram2[(addr & 0x0fff) as usize] = data;
-                }
+            0xC000..= 0xFDFF => {
+                let bank = self.wramIndex(addr);
+                self.wram[bank][(addr & 0x0fff) as usize] = data;
             },
             0xFE00..= 0xFE9F => {
-                todo!("Sprite table not implemented");
-            },
-            0xFEA0..= 0xFEFF => {
-                panic!("Unusable memory");
+                self.gpu.writeOam(addr, data);
             },
+            0xFEA0..= 0xFEFF => {/* Unusable, writes are ignored */},
             0xFF00..= 0xFF7F => {
                 match addr & 0x00FF {
-                    0x00 => {todo!("Controller not implemented")},
-                    0x01..= 0x02 => {todo!("Communication not implemented")},
+                    0x00 => {
+                        self.joypad.write(data);
+                        if self.model == Model::Sgb && self.cart.as_ref().is_some_and(|c| c.supportsSgb()) {
+                            self.sgb.writeJoypad(data);
+                            self.joypad.setPlayers(self.sgb.players);
+                        }
+                    },
+                    0x01..= 0x02 => {self.serial.write(addr, data)},
                     0x04..= 0x07 => {
                         match addr & 0x000F {
                             0x4 => {self.timerRegisters.divRegister = 0},
@@ -158,13 +416,17 @@ impl Bus {
                     0x0F => {self.interruptRequestRegister = data},
                     0x10..= 0x26 => {/* Sound, not implementing*/},
                     0x30..= 0x3F => {/* Waveform RAM, not implementing*/},
-                    0x40..= 0x4B => {todo!("LCD register not implemented")},
-                    0x4F => {/* GBC VRAM Bank Select */},
+                    0x46 => {self.oamDma(data)},
+                    0x40..= 0x4B => {self.gpu.writeRegister(addr, data)},
+                    0x4D if self.cgbMode => {self.speedSwitchArmed = bit::get(data, 0)},
+                    0x4F => {self.gpu.writeRegister(addr, data)},
                     0x50 => {/* Set to disable boot ROM ??*/},
-                    0x51..= 0x55 => {/* GBC HDMA */},
-                    0x68..= 0x69 => {/* GBC BCP/OCP */},
-                    0x70 => {/* GBC WRAM Bank Select */}
-                    _ => {panic!("Unknown write to {}", addr)}
+                    0x51..= 0x54 if self.cgbMode => {self.hdma.writeAddress(addr, data)},
+                    0x55 if self.cgbMode => {self.startHdma(data)},
+                    0x68..= 0x6B => {self.gpu.writeRegister(addr, data)},
+                    // bank 0 can't be mapped at D000, writing 0 selects bank 1
+                    0x70 if self.cgbMode => {self.wramBank = if data & 0x07 == 0 {1} else {data & 0x07}},
+                    _ => {}
                 }
             },
             0xFF80..= 0xFFFE => {
@@ -179,17 +441,68 @@ impl Bus {
     pub fn requestInterrupt(&mut self, i: IntrFlags) {
         self.interruptRequestRegister = bit::set(self.interruptRequestRegister, i as usize);
     }
+}
+
+impl Memory for Bus {
+    fn read(&self, addr: u16, flag: u8) -> u8 {
+        self.cpuFetch(addr, flag)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.cpuWrite(addr, value);
+    }
 
-    pub fn getInterruptRequest(&self, i: IntrFlags) -> bool {
-        bit::get(self.interruptRequestRegister, i as usize)
+    fn peek(&self, addr: u16) -> u8 {
+        Bus::peek(self, addr)
     }
 
-    pub fn resetInterruptRequest(&mut self, i: IntrFlags) {
-        self.interruptRequestRegister = bit::clr(self.interruptRequestRegister, i as usize);
+    fn tick(&mut self) {
+        self.clock();
     }
 
-    pub fn getInterruptEnable(&self, i: IntrFlags) -> bool{
-        bit::get(self.interruptEnableRegister, i as usize)
+    fn interruptEnable(&self) -> u8 {
+        self.interruptEnableRegister
+    }
+
+    fn interruptRequest(&self) -> u8 {
+        self.interruptRequestRegister
+    }
+
+    fn setInterruptRequest(&mut self, value: u8) {
+        self.interruptRequestRegister = value;
+    }
+
+    fn stall(&mut self) -> bool {
+        if self.stallCycles == 0 {
+            return false;
+        }
+        self.stallCycles -= 1;
+        true
+    }
+
+    fn switchSpeed(&mut self) -> bool {
+        Bus::switchSpeed(self)
+    }
+
+    fn stop(&mut self) {
+        // the CPU stops clocking the bus, so the LCD and timers halt where they are
+        self.timerRegisters.divRegister = 0;
+    }
+
+    fn joypadActive(&self) -> bool {
+        self.joypad.read() & 0x0F != 0x0F
+    }
+
+    fn romBank(&self, addr: u16) -> Option<u16> {
+        Bus::romBank(self, addr)
+    }
+
+    fn memoryBank(&self, addr: u16) -> u16 {
+        Bus::memoryBank(self, addr)
+    }
+
+    fn breakpoints(&self) -> Option<&Breakpoints> {
+        Some(&self.breakpoints)
     }
 }
 
@@ -206,4 +519,107 @@ FEA0 	FEFF 	Not Usable 	Nintendo says use of this area is prohibited
 FF00 	FF7F 	I/O Registers 	
 FF80 	FFFE 	High RAM (HRAM) 	
 FFFF 	FFFF 	Interrupts Enable Register (IE)
-*/
\ No newline at end of file
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Model, BLOCK_STALL_CYCLES};
+    use crate::assembler::tests::{boot, runTo};
+    use crate::breakpoints::{Trigger, StopReason, Access};
+
+    fn cgbBus() -> Bus {
+        let mut b = Bus::new();
+        b.cgbMode = true;
+        b.gpu.cgbMode = true;
+        b
+    }
+
+    #[test]
+    fn generalPurposeDmaCopiesAndStalls() {
+        let mut b = cgbBus();
+        for i in 0..0x20 {
+            b.cpuWrite(0xC000 + i, i as u8 + 1);
+        }
+        for (addr, data) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x01), (0xFF54, 0x00), (0xFF55, 0x01)] {
+            b.cpuWrite(addr, data);
+        }
+        for i in 0..0x20 {
+            assert_eq!(b.gpu.readVram(0x8100 + i), i as u8 + 1);
+        }
+        assert_eq!(b.stallCycles, 2 * BLOCK_STALL_CYCLES);
+        assert_eq!(b.peek(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn hblankDmaWaitsForHBlank() {
+        let mut b = cgbBus();
+        for (addr, data) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x00), (0xFF54, 0x00), (0xFF55, 0x81)] {
+            b.cpuWrite(addr, data);
+        }
+        assert_eq!(b.stallCycles, 0);
+        assert_eq!(b.peek(0xFF55), 0x01);
+        // one block per HBlank, the first one comes on the first line
+        while b.stallCycles == 0 {
+            b.clock();
+        }
+        assert_eq!(b.peek(0xFF55), 0x00);
+        b.cpuWrite(0xFF55, 0x00);
+        assert_eq!(b.peek(0xFF55), 0x80);
+    }
+
+    // FEA0 and FEB7 read with the LCD off
+    fn unusableReads(model: Model) -> (u8, u8) {
+        let (mut c, end) = boot("
+            xor a
+            ldh [$FF40], a
+            ld [$FEA0], a
+            ld a, [$FEA0]
+            ld b, a
+            ld a, [$FEB7]
+            ld c, a
+        ", model);
+        runTo(&mut c, end);
+        (c.b, c.c)
+    }
+
+    #[test]
+    fn unusableMemoryDependsOnModel() {
+        assert_eq!(unusableReads(Model::Dmg), (0x00, 0x00));
+        assert_eq!(unusableReads(Model::Cgb), (0xAA, 0xBB));
+    }
+
+    #[test]
+    fn unmappedIoReadsOnes() {
+        let (mut c, end) = boot("
+            ld a, $12
+            ldh [$FF03], a
+            ldh a, [$FF03]
+            ld b, a
+            xor a
+            ldh [$FF0F], a
+            ldh a, [$FF0F]
+        ", Model::Dmg);
+        runTo(&mut c, end);
+        assert_eq!(c.b, 0xFF);
+        // only the low five bits of IF exist
+        assert_eq!(c.a, 0xE0);
+    }
+
+    #[test]
+    fn romWritesAreIgnored() {
+        let (mut c, end) = boot("
+            ld hl, $0134
+            ld [hl], $55
+            ld a, [hl]
+        ", Model::Dmg);
+        let id = c.bus.breakpoints.add(Trigger::Suspicious, None);
+        while c.stopReason.is_none() {
+            c.step();
+        }
+        assert_eq!(c.stopReason.take(), Some(StopReason::Watchpoint {id, addr: 0x0134, value: 0x55, access: Access::Write}));
+        runTo(&mut c, end);
+        // first letter of the title
+        assert_eq!(c.a, b'A');
+        assert_eq!(c.bus.peek(0x0134), b'A');
+    }
+}
@@ -0,0 +1,98 @@
+// Shadow call stack kept next to the real one, only the debugger looks at it.
+// Frames are matched by the stack slot holding their return address, so code that
+// drops a return address (pop; jp) or returns through a pushed address is noticed.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // address of the CALL/RST, or of the interrupted instruction
+    pub caller: u16,
+    pub target: u16,
+    pub returnAddr: u16,
+    // SP after the return address was pushed
+    pub sp: u16,
+}
+
+// A return that did not match the innermost frame
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Mismatch {
+    pub at: u16,
+    pub expected: Option<u16>,
+    pub target: u16,
+    // frames dropped because the code unwound them by hand
+    pub dropped: usize,
+}
+
+pub struct CallStack {
+    frames: Vec<Frame>,
+    pub lastMismatch: Option<Mismatch>,
+    pub mismatches: usize,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self {
+            frames: Vec::new(),
+            lastMismatch: None,
+            mismatches: 0,
+        }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn enter(&mut self, frame: Frame) {
+        // frames at or below the new slot were abandoned, their return addresses are gone
+        let abandoned = self.frames.iter().rev().take_while(|f| f.sp <= frame.sp).count();
+        if abandoned > 0 {
+            let top = self.frames[self.frames.len() - 1];
+            self.mismatch(Mismatch {at: frame.caller, expected: Some(top.returnAddr), target: frame.target, dropped: abandoned});
+            self.frames.truncate(self.frames.len() - abandoned);
+        }
+        self.frames.push(frame);
+    }
+
+    // at is the address of the RET/RETI, sp the value after popping
+    pub fn ret(&mut self, at: u16, target: u16, sp: u16) {
+        let slot = sp.wrapping_sub(2);
+        let expected = self.frames.last().map(|f| f.returnAddr);
+        match self.frames.iter().rposition(|f| f.sp == slot) {
+            Some(i) => {
+                let dropped = self.frames.len() - 1 - i;
+                let frame = self.frames[i];
+                self.frames.truncate(i);
+                if dropped > 0 || frame.returnAddr != target {
+                    // returned past frames that were unwound by hand, or to a patched return address
+                    self.mismatch(Mismatch {at, expected, target, dropped});
+                }
+            },
+            None => {
+                // the slot was never written by a call, e.g. push hl; ret used as a jump
+                let dropped = self.frames.iter().rev().take_while(|f| f.sp < slot).count();
+                self.frames.truncate(self.frames.len() - dropped);
+                self.mismatch(Mismatch {at, expected, target, dropped});
+            },
+        }
+    }
+
+    fn mismatch(&mut self, m: Mismatch) {
+        self.lastMismatch = Some(m);
+        self.mismatches += 1;
+    }
+}
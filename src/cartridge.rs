@@ -10,6 +10,13 @@ pub struct Cartridge {
     romSize: u8,
 
     ram: Option<Vec<u8>>,
+
+    // MBC1 registers
+    ramEnabled: bool,
+    bank1: u8,
+    bank2: u8,
+    // 1 lets bank2 switch 0000-3FFF and the RAM bank too
+    bankingMode: u8,
 }
 
 #[derive(Debug)]
@@ -31,28 +38,35 @@ enum RamType {
 }
 
 impl Cartridge {
-    pub fn new(path: String) -> Self {
-        let d = fs::read(path).unwrap();
+    pub fn new(path: String) -> Result<Self, String> {
+        Self::fromBytes(fs::read(path).map_err(|e| e.to_string())?)
+    }
+
+    // A ROM image built in memory, e.g. by the assembler
+    pub fn fromBytes(d: Vec<u8>) -> Result<Self, String> {
+        if d.len() < 0x0150 {
+            return Err(format!("{} bytes is too short for a ROM with a header", d.len()));
+        }
         let gbcF = d[0x0143];
         let t = match d[0x0147] {
             0x00 => CartridgeType::Rom,
             0x01 => CartridgeType::Mbc1,
             0x02 => CartridgeType::Mbc1Ram,
             0x03 => CartridgeType::Mbc1RamBattery,
-            _ => panic!("Unsupported cartridge type")
+            t => return Err(format!("unsupported cartridge type {:02X}", t)),
         };
         let (ram, rT) = match d[0x0149] {
             0x00 => (None, RamType::NoRam),
             0x01 => (None, RamType::Unused),
-            0x02 => (Some(Vec::with_capacity(8192)), RamType::Kb8),
-            0x03 => (Some(Vec::with_capacity(8192 * 4)), RamType::Kb32),
-            0x04 => (Some(Vec::with_capacity(8192 * 16)), RamType::Kb128),
-            0x05 => (Some(Vec::with_capacity(8192 * 8)), RamType::Kb64),
-            _ => panic!("Unreachable"),
+            0x02 => (Some(vec![0; 8192]), RamType::Kb8),
+            0x03 => (Some(vec![0; 8192 * 4]), RamType::Kb32),
+            0x04 => (Some(vec![0; 8192 * 16]), RamType::Kb128),
+            0x05 => (Some(vec![0; 8192 * 8]), RamType::Kb64),
+            r => return Err(format!("unknown RAM size {:02X}", r)),
         };
 
         let rSize = d[0x0148];
-        Self {
+        Ok(Self {
             data: d,
             gbcFlag: gbcF,
             cartType: t,
@@ -60,26 +74,133 @@ impl Cartridge {
 
             romSize: rSize,
 
-            ram: ram
+            ram,
+
+            ramEnabled: false,
+            bank1: 1,
+            bank2: 0,
+            bankingMode: 0,
+        })
+    }
+
+    // 0x80 marks CGB enhanced titles, 0xC0 CGB only ones
+    pub fn isCgb(&self) -> bool {
+        self.gbcFlag & 0x80 != 0
+    }
+
+    // The SGB flag only counts together with the new licensee code
+    pub fn supportsSgb(&self) -> bool {
+        self.data[0x0146] == 0x03 && self.data[0x014B] == 0x33
+    }
+
+    pub fn hasMapper(&self) -> bool {
+        !matches!(self.cartType, CartridgeType::Rom)
+    }
+
+    pub fn title(&self) -> &[u8] {
+        &self.data[0x0134..= 0x0143]
+    }
+
+    // Old licensee code 0x01, or 0x33 followed by the new licensee code "01"
+    pub fn isNintendo(&self) -> bool {
+        match self.data[0x014B] {
+            0x01 => true,
+            0x33 => &self.data[0x0144..= 0x0145] == b"01",
+            _ => false,
         }
     }
 
-    pub fn readRom(&self, addr: u16) -> u8 {
+    pub fn romLen(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn ramLen(&self) -> usize {
+        match self.ramType {
+            RamType::Kb8 => {8 * 1024},
+            RamType::Kb32 => {32 * 1024},
+            RamType::Kb128 => {128 * 1024},
+            RamType::Kb64 => {64 * 1024},
+            _ => {0},
+        }
+    }
+
+    // Whole RAM contents, whether the game enabled it or not
+    pub fn ram(&self) -> &[u8] {
+        match &self.ram {
+            Some(r) => r,
+            None => &[],
+        }
+    }
+
+    fn romBanks(&self) -> usize {
+        (self.data.len() / 0x4000).max(2)
+    }
+
+    // Bank mapped at 4000-7FFF
+    pub fn romBank(&self) -> u16 {
         match self.cartType {
-            CartridgeType::Rom => {self.data[addr as usize]},
-            _ => panic!("Mappers not implemented")
+            CartridgeType::Rom => {1},
+            _ => {((((self.bank2 as usize) << 5) | self.bank1 as usize) % self.romBanks()) as u16},
         }
     }
 
-    pub fn readRam(&self, addr: u16) -> u8 {
+    // Bank mapped at 0000-3FFF, only MBC1 in mode 1 switches it
+    pub fn lowRomBank(&self) -> u16 {
         match self.cartType {
             CartridgeType::Rom => {0},
-            _ => panic!("Mappers not implemented")
+            _ => {
+                if self.bankingMode == 1 {(((self.bank2 as usize) << 5) % self.romBanks()) as u16} else {0}
+            },
+        }
+    }
+
+    pub fn readRom(&self, addr: u16) -> u8 {
+        let offset = match addr {
+            0x0000..= 0x3FFF => {self.lowRomBank() as usize * 0x4000 + addr as usize},
+            _ => {self.romBank() as usize * 0x4000 + (addr & 0x3FFF) as usize},
+        };
+        match self.data.get(offset) {
+            Some(d) => *d,
+            None => 0xFF,
+        }
+    }
+
+    // Writes to ROM set the mapper registers
+    pub fn writeRom(&mut self, addr: u16, d: u8) {
+        match self.cartType {
+            CartridgeType::Rom => {/* no mapper, the write goes nowhere */},
+            _ => {
+                match addr {
+                    0x0000..= 0x1FFF => {self.ramEnabled = d & 0x0F == 0x0A},
+                    // bank 0 can't be selected here, it reads as bank 1
+                    0x2000..= 0x3FFF => {self.bank1 = if d & 0x1F == 0 {1} else {d & 0x1F}},
+                    0x4000..= 0x5FFF => {self.bank2 = d & 0x03},
+                    _ => {self.bankingMode = d & 0x01},
+                }
+            },
+        }
+    }
+
+    fn ramOffset(&self, addr: u16) -> Option<usize> {
+        let len = self.ram().len();
+        if !self.ramEnabled || len == 0 {
+            return None;
+        }
+        let bank = if self.bankingMode == 1 {self.bank2 as usize} else {0};
+        Some((bank * 0x2000 + (addr - 0xA000) as usize) % len)
+    }
+
+    pub fn readRam(&self, addr: u16) -> u8 {
+        match (self.ramOffset(addr), &self.ram) {
+            (Some(i), Some(r)) => r[i],
+            _ => 0xFF,
         }
     }
 
     pub fn writeRam(&mut self, addr: u16, d: u8) {
-        todo!("Not implemented")
+        if let (Some(i), Some(r)) = (self.ramOffset(addr), &mut self.ram) {
+            r[i] = d;
+        }
     }
 }
 
@@ -133,4 +254,79 @@ $05 	1 MByte 	64
 $06 	2 MByte 	128
 $07 	4 MByte 	256
 $08 	8 MByte 	512
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+mod tests {
+    use super::Cartridge;
+
+    // MBC1+RAM+BATTERY with 32 KiB of RAM, every bank starts with its own number
+    fn mbc1(banks: usize) -> Cartridge {
+        let mut d = vec![0; banks * 0x4000];
+        for b in 0..banks {
+            d[b * 0x4000] = b as u8;
+        }
+        d[0x0147] = 0x03;
+        d[0x0149] = 0x03;
+        Cartridge::fromBytes(d).unwrap()
+    }
+
+    #[test]
+    fn bankZeroReadsAsOne() {
+        let mut c = mbc1(8);
+        assert_eq!(c.readRom(0x4000), 1);
+        c.writeRom(0x2000, 0x00);
+        assert_eq!(c.romBank(), 1);
+        c.writeRom(0x2000, 0x03);
+        assert_eq!(c.readRom(0x4000), 3);
+        // only five bits are used, so 20 is 0 again
+        c.writeRom(0x2000, 0x20);
+        assert_eq!(c.romBank(), 1);
+        // banks past the end of the ROM wrap around
+        c.writeRom(0x2000, 0x0A);
+        assert_eq!(c.readRom(0x4000), 2);
+    }
+
+    #[test]
+    fn upperBitsSelectHighBanks() {
+        let mut c = mbc1(64);
+        c.writeRom(0x4000, 0x01);
+        c.writeRom(0x2000, 0x05);
+        assert_eq!(c.readRom(0x4000), 37);
+        // mode 0 keeps bank 0 at 0000-3FFF
+        assert_eq!(c.readRom(0x0000), 0);
+        c.writeRom(0x6000, 0x01);
+        assert_eq!(c.lowRomBank(), 32);
+        assert_eq!(c.readRom(0x0000), 32);
+        assert_eq!(c.readRom(0x4000), 37);
+    }
+
+    #[test]
+    fn ramBanksOnlySwitchInModeOne() {
+        let mut c = mbc1(4);
+        c.writeRam(0xA000, 0x11);
+        assert_eq!(c.readRam(0xA000), 0xFF);
+
+        c.writeRom(0x0000, 0x0A);
+        c.writeRam(0xA000, 0x11);
+        c.writeRom(0x4000, 0x02);
+        // bank2 is ignored for RAM in mode 0
+        assert_eq!(c.readRam(0xA000), 0x11);
+        c.writeRom(0x6000, 0x01);
+        assert_eq!(c.readRam(0xA000), 0x00);
+        c.writeRam(0xA000, 0x22);
+        assert_eq!(c.ram()[2 * 0x2000], 0x22);
+        c.writeRom(0x6000, 0x00);
+        assert_eq!(c.readRam(0xA000), 0x11);
+
+        c.writeRom(0x0000, 0x00);
+        assert_eq!(c.readRam(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn unsupportedRoms() {
+        assert!(Cartridge::fromBytes(vec![0; 0x100]).is_err());
+        let mut d = vec![0; 0x8000];
+        d[0x0147] = 0x19;
+        assert_eq!(Cartridge::fromBytes(d).unwrap_err(), "unsupported cartridge type 19");
+    }
+}
@@ -0,0 +1,265 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Code/Data Logger, one flag byte for every byte of ROM, WRAM, HRAM and cartridge RAM.
+// Saved in the BizHawk CDL layout ("BIZHAWK-CDL-2", sub type "GB") which other Game Boy
+// tools read, logs are merged with the file on disk so several sessions add up.
+// BizHawk only knows the ROM, WRAM and CartRAM blocks and the first three flags, the full
+// log with HRAM and the WRITTEN flag goes into game.cdl.extra in the same layout.
+
+pub const OPCODE: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+// not a BizHawk flag, only set in RAM
+pub const WRITTEN: u8 = 0x08;
+
+const BIZHAWK_FLAGS: u8 = OPCODE | OPERAND | DATA;
+
+// BizHawk sizes WRAM for the CGB on every model
+pub const WRAM_SIZE: usize = 0x8000;
+const HRAM_SIZE: usize = 0x7F;
+
+const HEADER: &str = "BIZHAWK-CDL-2";
+const SUB_TYPE: &str = "GB";
+const EXTRA_SUB_TYPE: &str = "GB-EXTRA";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Domain {
+    Rom,
+    Wram,
+    Hram,
+    CartRam,
+}
+
+impl Domain {
+    fn name(&self) -> &'static str {
+        match self {
+            Domain::Rom => {"ROM"},
+            Domain::Wram => {"WRAM"},
+            Domain::Hram => {"HRAM"},
+            Domain::CartRam => {"CartRAM"},
+        }
+    }
+}
+
+const DOMAINS: [Domain; 4] = [Domain::Rom, Domain::Wram, Domain::Hram, Domain::CartRam];
+const BIZHAWK_DOMAINS: [Domain; 3] = [Domain::Rom, Domain::Wram, Domain::CartRam];
+
+fn extraPath(path: &Path) -> PathBuf {
+    path.with_extension("cdl.extra")
+}
+
+pub struct CodeDataLog {
+    // written when the log is dropped, None while logging is off
+    path: Option<PathBuf>,
+    // reads come through &Bus, so the flags need interior mutability like the breakpoint hits
+    blocks: RefCell<[Vec<u8>; 4]>,
+}
+
+impl CodeDataLog {
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            blocks: RefCell::new([Vec::new(), Vec::new(), Vec::new(), Vec::new()]),
+        }
+    }
+
+    // game.gb logs to game.cdl, an existing log with the same sizes is continued
+    pub fn open(romPath: &str, romSize: usize, cartRamSize: usize) -> Self {
+        let path = Path::new(romPath).with_extension("cdl");
+        let mut log = Self {
+            path: None,
+            blocks: RefCell::new([vec![0; romSize], vec![0; WRAM_SIZE], vec![0; HRAM_SIZE], vec![0; cartRamSize]]),
+        };
+        for saved in [Self::load(&path), Self::read(&extraPath(&path), EXTRA_SUB_TYPE)].iter().flatten() {
+            log.merge(saved);
+        }
+        log.path = Some(path);
+        log
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn log(&self, domain: Domain, offset: usize, flag: u8) {
+        if let Some(b) = self.blocks.borrow_mut()[domain as usize].get_mut(offset) {
+            *b |= flag;
+        }
+    }
+
+    pub fn flags(&self, domain: Domain, offset: usize) -> u8 {
+        self.blocks.borrow()[domain as usize].get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn block(&self, domain: Domain) -> Vec<u8> {
+        self.blocks.borrow()[domain as usize].clone()
+    }
+
+    fn merge(&self, other: &CodeDataLog) {
+        let mut blocks = self.blocks.borrow_mut();
+        for (mine, theirs) in blocks.iter_mut().zip(other.blocks.borrow().iter()) {
+            if mine.len() == theirs.len() {
+                for (a, b) in mine.iter_mut().zip(theirs.iter()) {
+                    *a |= *b;
+                }
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(path, SUB_TYPE)
+    }
+
+    fn read(path: &Path, subType: &str) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a Game Boy CDL file");
+        let mut r = Reader {data: &data, pos: 0};
+        if r.string().ok_or_else(invalid)? != HEADER || r.string().ok_or_else(invalid)?.trim_end() != subType {
+            return Err(invalid());
+        }
+        let log = Self::new();
+        for _ in 0..r.int().ok_or_else(invalid)? {
+            let name = r.string().ok_or_else(invalid)?;
+            let len = r.int().ok_or_else(invalid)? as usize;
+            let bytes = r.bytes(len).ok_or_else(invalid)?;
+            // blocks other tools add, like VRAM, are skipped
+            if let Some(d) = DOMAINS.iter().find(|d| d.name() == name) {
+                log.blocks.borrow_mut()[*d as usize] = bytes.to_vec();
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.encode(SUB_TYPE, &BIZHAWK_DOMAINS, BIZHAWK_FLAGS))?;
+        fs::write(extraPath(path), self.encode(EXTRA_SUB_TYPE, &DOMAINS, 0xFF))
+    }
+
+    fn encode(&self, subType: &str, domains: &[Domain], mask: u8) -> Vec<u8> {
+        let blocks = self.blocks.borrow();
+        let present: Vec<Domain> = domains.iter().copied().filter(|d| !blocks[*d as usize].is_empty()).collect();
+        let mut out = Vec::new();
+        writeString(&mut out, HEADER);
+        writeString(&mut out, &format!("{:<15}", subType));
+        out.extend_from_slice(&(present.len() as i32).to_le_bytes());
+        for d in present {
+            writeString(&mut out, d.name());
+            out.extend_from_slice(&(blocks[d as usize].len() as i32).to_le_bytes());
+            out.extend(blocks[d as usize].iter().map(|f| f & mask));
+        }
+        out
+    }
+}
+
+impl Drop for CodeDataLog {
+    // every way out of the emulator drops the CPU, so the log is saved wherever it stopped
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.save(path) {
+                eprintln!("Failed to save {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// .NET BinaryWriter strings, a 7 bit encoded length followed by the UTF-8 bytes
+fn writeString(out: &mut Vec<u8>, s: &str) {
+    let mut len = s.len();
+    while len >= 0x80 {
+        out.push((len as u8) | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn int(&mut self) -> Option<i32> {
+        let b = self.bytes(4)?;
+        Some(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let mut len = 0;
+        let mut shift = 0;
+        loop {
+            let b = *self.bytes(1)?.first()?;
+            len |= ((b & 0x7F) as usize) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodeDataLog, Domain, Reader, extraPath, OPCODE, DATA, WRITTEN, WRAM_SIZE};
+    use std::fs;
+
+    fn romPath(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("cdl-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("game.gb").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn bizhawkBlocksOnly() {
+        let rom = romPath("blocks");
+        let log = CodeDataLog::open(&rom, 0x8000, 0);
+        log.log(Domain::Rom, 0x150, OPCODE);
+        log.log(Domain::Wram, 0x10, DATA | WRITTEN);
+        log.log(Domain::Hram, 0x00, OPCODE);
+        drop(log);
+
+        let data = fs::read(std::path::Path::new(&rom).with_extension("cdl")).unwrap();
+        let mut r = Reader {data: &data, pos: 0};
+        assert_eq!(r.string().unwrap(), "BIZHAWK-CDL-2");
+        assert_eq!(r.string().unwrap(), "GB             ");
+        // no cartridge RAM and no HRAM block
+        assert_eq!(r.int(), Some(2));
+        assert_eq!(r.string().unwrap(), "ROM");
+        assert_eq!(r.int(), Some(0x8000));
+        assert_eq!(r.bytes(0x8000).unwrap()[0x150], OPCODE);
+        assert_eq!(r.string().unwrap(), "WRAM");
+        assert_eq!(r.int(), Some(WRAM_SIZE as i32));
+        assert_eq!(r.bytes(WRAM_SIZE).unwrap()[0x10], DATA);
+        assert_eq!(r.pos, data.len());
+    }
+
+    #[test]
+    fn extraFlagsSurviveReopening() {
+        let rom = romPath("extra");
+        let log = CodeDataLog::open(&rom, 0x8000, 0x2000);
+        log.log(Domain::Wram, 0x7FFF, WRITTEN);
+        log.log(Domain::Hram, 0x7E, OPCODE);
+        log.log(Domain::CartRam, 0x1FFF, DATA | WRITTEN);
+        drop(log);
+        assert!(extraPath(&std::path::Path::new(&rom).with_extension("cdl")).exists());
+
+        let log = CodeDataLog::open(&rom, 0x8000, 0x2000);
+        assert_eq!(log.flags(Domain::Wram, 0x7FFF), WRITTEN);
+        assert_eq!(log.flags(Domain::Hram, 0x7E), OPCODE);
+        assert_eq!(log.flags(Domain::CartRam, 0x1FFF), DATA | WRITTEN);
+        // a block with a different size is a different game, it starts over
+        let other = CodeDataLog::open(&rom, 0x10000, 0x2000);
+        assert_eq!(other.flags(Domain::Rom, 0), 0);
+        assert_eq!(other.flags(Domain::CartRam, 0x1FFF), DATA | WRITTEN);
+    }
+}
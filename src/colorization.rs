@@ -0,0 +1,195 @@
+use super::joypad::Button;
+
+// Palette selection done by the CGB boot ROM for DMG only cartridges.
+// Games are identified by the sum of their 16 title bytes, checksums shared by
+// several titles are told apart by the fourth letter of the title.
+
+const UNIQUE_CHECKSUMS: usize = 65;
+
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    // checksums with more than one title, disambiguated by DUPLICATE_LETTERS
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+// Rows of 14 letters, one column per duplicated checksum
+const DUPLICATE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination for every identified title, unique checksums first
+const COMBINATION_PER_TITLE: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18,
+    29,
+];
+
+// Offset of a palette in PALETTE_COLORS
+const fn pal(palette: usize) -> usize {
+    palette * 4
+}
+
+// OBJ0, OBJ1 and BG palettes as offsets into PALETTE_COLORS. A few combinations
+// start in the middle of a palette, which the boot ROM relies on for some games.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (pal(4), pal(4), pal(29)), (pal(18), pal(18), pal(18)), (pal(20), pal(20), pal(20)), (pal(24), pal(24), pal(24)),
+    (pal(9), pal(9), pal(9)), (pal(0), pal(0), pal(0)), (pal(27), pal(27), pal(27)), (pal(5), pal(5), pal(5)),
+    (pal(12), pal(12), pal(12)), (pal(26), pal(26), pal(26)), (pal(16), pal(8), pal(8)), (pal(4), pal(28), pal(28)),
+    (pal(4), pal(2), pal(2)), (pal(3), pal(4), pal(4)), (pal(4), pal(29), pal(29)), (pal(28), pal(4), pal(28)),
+    (pal(2), pal(17), pal(2)), (pal(16), pal(16), pal(8)), (pal(4), pal(4), pal(7)), (pal(4), pal(4), pal(18)),
+    (pal(4), pal(4), pal(20)), (pal(19), pal(19), pal(9)), (pal(4) - 1, pal(4) - 1, pal(11)), (pal(17), pal(17), pal(2)),
+    (pal(4), pal(4), pal(2)), (pal(4), pal(4), pal(3)), (pal(28), pal(28), pal(0)), (pal(3), pal(3), pal(0)),
+    (pal(0), pal(0), pal(1)), (pal(18), pal(22), pal(18)), (pal(20), pal(22), pal(20)), (pal(24), pal(22), pal(24)),
+    (pal(16), pal(22), pal(8)), (pal(17), pal(4), pal(13)), (pal(28) - 1, pal(0), pal(14)), (pal(28) - 1, pal(4), pal(15)),
+    (pal(19), pal(22), pal(9)), (pal(16), pal(28), pal(10)), (pal(4), pal(23), pal(28)), (pal(17), pal(22), pal(2)),
+    (pal(4), pal(0), pal(2)), (pal(4), pal(28), pal(3)), (pal(28), pal(3), pal(0)), (pal(3), pal(28), pal(4)),
+    (pal(21), pal(28), pal(4)), (pal(3), pal(28), pal(0)), (pal(25), pal(3), pal(28)), (pal(0), pal(28), pal(8)),
+    (pal(4), pal(3), pal(28)), (pal(28), pal(3), pal(6)), (pal(4), pal(28), pal(29)),
+];
+
+const PALETTE_COLORS: [u16; 30 * 4] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Combinations picked by holding a direction, optionally with A or B, on the boot logo
+const MANUAL_COMBINATIONS: [(Button, Option<Button>, usize); 12] = [
+    (Button::Up, None, 5),
+    (Button::Up, Some(Button::A), 43),
+    (Button::Up, Some(Button::B), 28),
+    (Button::Left, None, 48),
+    (Button::Left, Some(Button::A), 40),
+    (Button::Left, Some(Button::B), 7),
+    (Button::Down, None, 8),
+    (Button::Down, Some(Button::A), 3),
+    (Button::Down, Some(Button::B), 49),
+    (Button::Right, None, 1),
+    (Button::Right, Some(Button::A), 0),
+    (Button::Right, Some(Button::B), 6),
+];
+
+pub struct Colorization {
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+    pub bg: [u16; 4],
+}
+
+// Only titles licensed by Nintendo are looked up, everything else gets combination 0
+pub fn combinationForTitle(title: &[u8], nintendo: bool) -> usize {
+    if !nintendo {
+        return 0;
+    }
+    let checksum = title.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    let index = match TITLE_CHECKSUMS.iter().position(|c| *c == checksum) {
+        Some(i) => i,
+        None => return 0,
+    };
+    if index < UNIQUE_CHECKSUMS {
+        return COMBINATION_PER_TITLE[index] as usize;
+    }
+
+    let column = index - UNIQUE_CHECKSUMS;
+    let duplicates = TITLE_CHECKSUMS.len() - UNIQUE_CHECKSUMS;
+    let mut letter = column;
+    while letter < DUPLICATE_LETTERS.len() {
+        if DUPLICATE_LETTERS[letter] == title[3] {
+            return COMBINATION_PER_TITLE[UNIQUE_CHECKSUMS + letter] as usize;
+        }
+        letter += duplicates;
+    }
+    0
+}
+
+// Held buttons as a Joypad::pressed mask, None when no manual combination is held
+pub fn combinationForButtons(pressed: u8) -> Option<usize> {
+    let held = |b: Button| pressed & (1 << b as u8) != 0;
+    let modifier = if held(Button::A) {
+        Some(Button::A)
+    } else if held(Button::B) {
+        Some(Button::B)
+    } else {
+        None
+    };
+    MANUAL_COMBINATIONS.iter()
+        .find(|(direction, m, _)| held(*direction) && *m == modifier)
+        .map(|(_, _, combination)| *combination)
+}
+
+pub fn palettes(combination: usize) -> Colorization {
+    let (obj0, obj1, bg) = COMBINATIONS[combination];
+    let palette = |offset: usize| {
+        let mut p = [0u16; 4];
+        p.copy_from_slice(&PALETTE_COLORS[offset..offset + 4]);
+        p
+    };
+    Colorization {
+        obj0: palette(obj0),
+        obj1: palette(obj1),
+        bg: palette(bg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combinationForTitle, palettes};
+
+    fn title(name: &[u8]) -> [u8; 16] {
+        let mut t = [0; 16];
+        t[..name.len()].copy_from_slice(name);
+        t
+    }
+
+    #[test]
+    fn uniqueChecksum() {
+        assert_eq!(combinationForTitle(&title(b"TETRIS"), true), 3);
+        assert_eq!(palettes(3).bg, palettes(3).obj0);
+    }
+
+    #[test]
+    fn sharedChecksumUsesFourthLetter() {
+        // both sum to 46
+        assert_eq!(combinationForTitle(&title(b"SUPER MARIOLAND"), true), 22);
+        assert_eq!(combinationForTitle(&title(b"METROID2"), true), 46);
+    }
+
+    #[test]
+    fn otherPublishersGetTheDefault() {
+        assert_eq!(combinationForTitle(&title(b"TETRIS"), false), 0);
+        assert_eq!(combinationForTitle(&title(b"HELLO"), true), 0);
+    }
+}
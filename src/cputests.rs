@@ -0,0 +1,274 @@
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use super::cpu::Z80;
+use super::flatram::{AccessKind, BusAccess, FlatRam};
+
+// Runs SingleStepTests sm83 vectors, one JSON file per opcode ("00.json" .. "cb ff.json").
+// Each vector starts with the opcode already fetched, PC one past it, and its last cycle
+// fetches the next opcode, the same fetch/execute overlap this CPU has.
+
+const REGISTERS: [&str; 10] = ["a", "f", "b", "c", "d", "e", "h", "l", "sp", "pc"];
+
+// failures printed per file, the rest are only counted
+const SHOWN_FAILURES: usize = 3;
+
+pub struct Failure {
+    pub name: String,
+    pub differences: Vec<String>,
+}
+
+pub struct FileResult {
+    pub file: String,
+    pub passed: usize,
+    pub failures: Vec<Failure>,
+}
+
+fn number(state: &Value, key: &str) -> Result<u16, String> {
+    state[key].as_u64().map(|v| v as u16).ok_or(format!("missing {}", key))
+}
+
+fn register(c: &Z80<FlatRam>, name: &str) -> u16 {
+    match name {
+        "a" => {c.a as u16}, "f" => {c.f as u16},
+        "b" => {c.b as u16}, "c" => {c.c as u16},
+        "d" => {c.d as u16}, "e" => {c.e as u16},
+        "h" => {c.h as u16}, "l" => {c.l as u16},
+        "sp" => {c.sp},
+        // our PC stops on the next opcode, the vectors count its fetch as done
+        _ => {c.pc.wrapping_add(1)},
+    }
+}
+
+fn setup(state: &Value) -> Result<Z80<FlatRam>, String> {
+    let mut ram = FlatRam::new();
+    for entry in state["ram"].as_array().ok_or("missing ram")? {
+        let addr = entry[0].as_u64().ok_or("bad ram entry")?;
+        let value = entry[1].as_u64().ok_or("bad ram entry")?;
+        ram.ram[addr as usize] = value as u8;
+    }
+    let mut c = Z80::withMemory(ram);
+    c.a = number(state, "a")? as u8;
+    c.f = number(state, "f")? as u8;
+    c.b = number(state, "b")? as u8;
+    c.c = number(state, "c")? as u8;
+    c.d = number(state, "d")? as u8;
+    c.e = number(state, "e")? as u8;
+    c.h = number(state, "h")? as u8;
+    c.l = number(state, "l")? as u8;
+    c.sp = number(state, "sp")?;
+    c.setIme(state["ime"].as_u64().unwrap_or(0) != 0);
+    c.bus.ram[0xFFFF] = state["ie"].as_u64().unwrap_or(0) as u8;
+    c.jump(number(state, "pc")?.wrapping_sub(1));
+    Ok(c)
+}
+
+fn describe(accesses: &[BusAccess]) -> String {
+    if accesses.is_empty() {
+        return String::from("nothing");
+    }
+    let texts: Vec<String> = accesses.iter().map(|a| match a.kind {
+        AccessKind::Read => format!("read {:04X}={:02X}", a.addr, a.value),
+        AccessKind::Write => format!("write {:04X}={:02X}", a.addr, a.value),
+    }).collect();
+    texts.join(", ")
+}
+
+// "r-m" reads, "-wm" writes, anything else is a cycle without memory access
+fn expectedCycle(entry: &Value) -> Vec<BusAccess> {
+    let (addr, value, pins) = match (entry[0].as_u64(), entry[1].as_u64(), entry[2].as_str()) {
+        (Some(a), Some(v), Some(p)) => (a as u16, v as u8, p),
+        _ => return Vec::new(),
+    };
+    let kind = if pins.starts_with('r') {
+        AccessKind::Read
+    } else if pins.contains('w') {
+        AccessKind::Write
+    } else {
+        return Vec::new();
+    };
+    vec![BusAccess {cycle: 0, addr, value, kind}]
+}
+
+fn compareCycles(test: &Value, recorded: &[BusAccess], cycles: u32) -> Vec<String> {
+    let expected = match test["cycles"].as_array() {
+        Some(e) => e,
+        None => return Vec::new(),
+    };
+    let mut differences = Vec::new();
+    if expected.len() as u32 != cycles {
+        differences.push(format!("took {} M-cycles, expected {}", cycles, expected.len()));
+    }
+    for (i, entry) in expected.iter().enumerate() {
+        let want = expectedCycle(entry);
+        let mut got: Vec<BusAccess> = Vec::new();
+        // the next opcode is fetched after the last cycle's ticks but belongs to it,
+        // and the same access repeated within one cycle looks like a single one on the bus
+        for a in recorded.iter().filter(|a| a.cycle.min(cycles.max(1) - 1) == i as u32) {
+            if !got.iter().any(|g| g.addr == a.addr && g.value == a.value && g.kind == a.kind) {
+                got.push(BusAccess {cycle: 0, ..*a});
+            }
+        }
+        if got != want {
+            differences.push(format!("cycle {}: {}, expected {}", i, describe(&got), describe(&want)));
+            break;
+        }
+    }
+    differences
+}
+
+fn runVector(test: &Value, checkCycles: bool) -> Result<Vec<String>, String> {
+    let mut c = setup(&test["initial"])?;
+    let result = panic::catch_unwind(AssertUnwindSafe(move || {
+        c.step();
+        c
+    }));
+    let c = match result {
+        Ok(c) => c,
+        Err(e) => {
+            let message = e.downcast_ref::<String>().cloned()
+                .or(e.downcast_ref::<&str>().map(|s| String::from(*s)))
+                .unwrap_or_default();
+            return Ok(vec![format!("panicked: {}", message)]);
+        },
+    };
+
+    let expected = &test["final"];
+    let mut differences = Vec::new();
+    for name in REGISTERS.iter() {
+        let want = number(expected, name)?;
+        let got = register(&c, name);
+        if got != want {
+            differences.push(format!("{}: {:02X}, expected {:02X}", name.to_uppercase(), got, want));
+        }
+    }
+    if let Some(ime) = expected["ime"].as_u64() {
+        if c.ime() != (ime != 0) {
+            differences.push(format!("IME: {}, expected {}", c.ime() as u8, ime));
+        }
+    }
+    let flat = &c.bus;
+    for entry in expected["ram"].as_array().ok_or("missing ram")? {
+        let addr = entry[0].as_u64().ok_or("bad ram entry")? as usize;
+        let want = entry[1].as_u64().ok_or("bad ram entry")? as u8;
+        if flat.ram[addr] != want {
+            differences.push(format!("[{:04X}]: {:02X}, expected {:02X}", addr, flat.ram[addr], want));
+        }
+    }
+    if checkCycles {
+        differences.extend(compareCycles(test, &flat.accesses(), flat.ticks / 4));
+    }
+    Ok(differences)
+}
+
+pub fn runFile(path: &Path, checkCycles: bool) -> Result<FileResult, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let tests: Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut result = FileResult {
+        file: path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default(),
+        passed: 0,
+        failures: Vec::new(),
+    };
+    for test in tests.as_array().ok_or("expected a list of tests")? {
+        let differences = runVector(test, checkCycles)?;
+        if differences.is_empty() {
+            result.passed += 1;
+        } else {
+            let name = String::from(test["name"].as_str().unwrap_or("?"));
+            result.failures.push(Failure {name, differences});
+        }
+    }
+    Ok(result)
+}
+
+// A single file or a directory of them, prints a report and returns true when everything passed
+pub fn run(path: &str, checkCycles: bool) -> bool {
+    let path = Path::new(path);
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        match fs::read_dir(path) {
+            Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|e| e == "json"))
+                .collect(),
+            Err(e) => {
+                println!("{}: {}", path.display(), e);
+                return false;
+            },
+        }
+    } else {
+        vec![path.to_path_buf()]
+    };
+    files.sort();
+
+    // panics are reported as failures, the default hook would print every one of them
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let (mut passed, mut failed) = (0, 0);
+    for file in files.iter() {
+        match runFile(file, checkCycles) {
+            Ok(r) => {
+                passed += r.passed;
+                failed += r.failures.len();
+                let status = if r.failures.is_empty() {"ok"} else {"FAILED"};
+                println!("{:<14} {:>5}/{:<5} {}", r.file, r.passed, r.passed + r.failures.len(), status);
+                for f in r.failures.iter().take(SHOWN_FAILURES) {
+                    println!("    {}: {}", f.name, f.differences.join("; "));
+                }
+            },
+            Err(e) => {
+                failed += 1;
+                println!("{}", e);
+            },
+        }
+    }
+    panic::set_hook(hook);
+    println!("{} passed, {} failed", passed, failed);
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use serde_json::json;
+    use super::{runFile, runVector};
+
+    // Ten vectors each of nop, rlc b and jp nz in the SingleStepTests sm83 format
+    fn vendored(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/sm83").join(name)
+    }
+
+    #[test]
+    fn vendoredVectors() {
+        for name in ["00.json", "cb 00.json", "c2.json"] {
+            let r = runFile(&vendored(name), true).unwrap();
+            assert_eq!(r.passed, 10, "{}", name);
+            assert!(r.failures.is_empty(), "{}: {:?}", name, r.failures.iter().map(|f| &f.name).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn wrongExpectationsAreReported() {
+        // inc a, the opcode has already been fetched and the cycle fetches the next one
+        let mut test = json!({
+            "name": "3c",
+            "initial": {"a": 0x0F, "f": 0x00, "b": 0, "c": 0, "d": 0, "e": 0, "h": 0, "l": 0, "sp": 0, "pc": 0x0101, "ime": 0, "ie": 0,
+                "ram": [[0x0100, 0x3C], [0x0101, 0x00]]},
+            "final": {"a": 0x10, "f": 0x20, "b": 0, "c": 0, "d": 0, "e": 0, "h": 0, "l": 0, "sp": 0, "pc": 0x0102, "ime": 0, "ie": 0,
+                "ram": [[0x0100, 0x3C], [0x0101, 0x00]]},
+            "cycles": [[0x0101, 0x00, "r-m"]],
+        });
+        assert!(runVector(&test, true).unwrap().is_empty());
+        test["final"]["f"] = json!(0x00);
+        test["cycles"][0][0] = json!(0x0102);
+        assert_eq!(runVector(&test, true).unwrap().len(), 2);
+    }
+
+    // cargo test -- --ignored, with the full sm83 suite in SM83_TESTS or sm83/v1
+    #[test]
+    #[ignore]
+    fn singleStepTests() {
+        let dir = std::env::var("SM83_TESTS").unwrap_or(String::from("sm83/v1"));
+        assert!(Path::new(&dir).is_dir(), "{} not found, set SM83_TESTS to the sm83/v1 directory", dir);
+        assert!(super::run(&dir, true));
+    }
+}
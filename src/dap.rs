@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use serde_json::{json, Value};
+use super::cpu::{Z80, Flags};
+use super::breakpoints::{StopReason, Trigger};
+use super::debugger::{self, Goal};
+use super::expression::{self, Expression};
+use super::visualizer::showCode;
+
+// Debug Adapter Protocol server so editors like VS Code can drive the emulator.
+// Started with --dap on stdio or --dap-port=N on a local TCP socket.
+
+// instructions run between looking for new requests while the program is running
+const SLICE: usize = 10000;
+
+const REGISTERS_REF: i64 = 1;
+const FLAGS_REF: i64 = 2;
+const IO_REF: i64 = 3;
+
+const IO_REGISTERS: [(&str, u16); 25] = [
+    ("P1", 0xFF00), ("DIV", 0xFF04), ("TIMA", 0xFF05), ("TMA", 0xFF06), ("TAC", 0xFF07),
+    ("IF", 0xFF0F), ("LCDC", 0xFF40), ("STAT", 0xFF41), ("SCY", 0xFF42), ("SCX", 0xFF43),
+    ("LY", 0xFF44), ("LYC", 0xFF45), ("DMA", 0xFF46), ("BGP", 0xFF47), ("OBP0", 0xFF48),
+    ("OBP1", 0xFF49), ("WY", 0xFF4A), ("WX", 0xFF4B), ("KEY1", 0xFF4D), ("VBK", 0xFF4F),
+    ("HDMA5", 0xFF55), ("BCPS", 0xFF68), ("OCPS", 0xFF6A), ("SVBK", 0xFF70), ("IE", 0xFFFF),
+];
+
+const FLAGS: [(&str, Flags); 4] = [
+    ("Z", Flags::Zero), ("N", Flags::Sub), ("H", Flags::HCarry), ("C", Flags::Carry),
+];
+
+pub fn serveStdio(c: Z80, boot: &dyn Fn(&str) -> Result<Z80, String>) -> io::Result<()> {
+    serve(io::stdin(), Box::new(io::stdout()), c, boot)
+}
+
+pub fn serveTcp(port: u16, c: Z80, boot: &dyn Fn(&str) -> Result<Z80, String>) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    let input = stream.try_clone()?;
+    serve(input, Box::new(stream), c, boot)
+}
+
+// Returns once the client disconnects, an error means the connection broke
+fn serve<R: Read + Send + 'static>(input: R, output: Box<dyn Write>, c: Z80, boot: &dyn Fn(&str) -> Result<Z80, String>) -> io::Result<()> {
+    // requests are read on their own thread so a running program can still be paused
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        while let Some(m) = readMessage(&mut reader) {
+            if tx.send(m).is_err() {
+                break;
+            }
+        }
+    });
+    let mut s = Session {
+        c,
+        boot,
+        output,
+        seq: 1,
+        goal: None,
+        stopOnEntry: false,
+        events: Vec::new(),
+        sourceBreakpoints: HashMap::new(),
+        functionBreakpoints: Vec::new(),
+        instructionBreakpoints: Vec::new(),
+    };
+    match s.run(rx) {
+        // a client that goes away without a disconnect request just ends the session
+        Err(e) if matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted) => Ok(()),
+        result => result,
+    }
+}
+
+fn readMessage<R: BufRead>(r: &mut R) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length:") {
+            length = v.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    r.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+struct Session<'a> {
+    c: Z80,
+    boot: &'a dyn Fn(&str) -> Result<Z80, String>,
+    output: Box<dyn Write>,
+    seq: i64,
+    // Some while the program runs
+    goal: Option<Goal>,
+    stopOnEntry: bool,
+    // sent after the response of the request that caused them
+    events: Vec<(&'static str, Value)>,
+    // DAP replaces every breakpoint of a kind at once, so the ids are kept per kind,
+    // source breakpoints per file
+    sourceBreakpoints: HashMap<String, Vec<usize>>,
+    functionBreakpoints: Vec<usize>,
+    instructionBreakpoints: Vec<usize>,
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self, rx: Receiver<Value>) -> io::Result<()> {
+        loop {
+            let message = if self.goal.is_some() {
+                match rx.try_recv() {
+                    Ok(m) => Some(m),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match rx.recv() {
+                    Ok(m) => Some(m),
+                    Err(_) => return Ok(()),
+                }
+            };
+            match message {
+                Some(m) => {
+                    if !self.handle(&m)? {
+                        return Ok(());
+                    }
+                },
+                None => {self.runSlice()?},
+            }
+        }
+    }
+
+    fn runSlice(&mut self) -> io::Result<()> {
+        let mut goal = match self.goal.take() {
+            Some(g) => g,
+            None => return Ok(()),
+        };
+        for _i in 0..SLICE {
+            let cycles = self.c.step();
+            if let Some(r) = self.c.stopReason.take() {
+                return self.stopped(r);
+            }
+            if goal.reached(&self.c, cycles) {
+                return self.event("stopped", json!({"reason": "step", "threadId": 1}));
+            }
+        }
+        self.goal = Some(goal);
+        Ok(())
+    }
+
+    fn stopped(&mut self, r: StopReason) -> io::Result<()> {
+        let reason = match r {
+            StopReason::Breakpoint {..} => "breakpoint",
+            StopReason::Lockup {..} => "exception",
+            _ => "data breakpoint",
+        };
+        self.event("stopped", json!({
+            "reason": reason,
+            "description": r.to_string(),
+            "threadId": 1,
+            "hitBreakpointIds": r.id().into_iter().collect::<Vec<usize>>(),
+        }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+
+    // Returns false once the client disconnected
+    fn handle(&mut self, m: &Value) -> io::Result<bool> {
+        let command = m["command"].as_str().unwrap_or("");
+        let result = self.request(command, &m["arguments"]);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": m["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => {response["body"] = body},
+            Err(e) => {response["message"] = json!(e)},
+        }
+        self.send(response)?;
+        for (event, body) in std::mem::take(&mut self.events) {
+            self.event(event, body)?;
+        }
+        Ok(command != "disconnect")
+    }
+
+    fn request(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                self.events.push(("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsWriteMemoryRequest": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsTerminateRequest": true,
+                }))
+            },
+            "launch" | "attach" => {
+                if let Some(program) = args["program"].as_str() {
+                    self.c = (self.boot)(program)?;
+                }
+                self.stopOnEntry = command == "attach" || args["stopOnEntry"].as_bool().unwrap_or(false);
+                Ok(Value::Null)
+            },
+            "configurationDone" => {
+                if self.stopOnEntry {
+                    self.events.push(("stopped", json!({"reason": "entry", "threadId": 1})));
+                } else {
+                    self.goal = Some(Goal::Continue);
+                }
+                Ok(Value::Null)
+            },
+            "disconnect" => {Ok(Value::Null)},
+            "terminate" => {
+                self.goal = None;
+                self.events.push(("terminated", json!({})));
+                Ok(Value::Null)
+            },
+            "threads" => {Ok(json!({"threads": [{"id": 1, "name": "SM83"}]}))},
+            "pause" => {
+                self.goal = None;
+                self.events.push(("stopped", json!({"reason": "pause", "threadId": 1})));
+                Ok(Value::Null)
+            },
+            "continue" => {
+                self.goal = Some(Goal::Continue);
+                Ok(json!({"allThreadsContinued": true}))
+            },
+            "next" => {self.goal = Some(Goal::over(&mut self.c)); Ok(Value::Null)},
+            "stepIn" => {self.goal = Some(Goal::Step); Ok(Value::Null)},
+            "stepOut" => {
+                if self.c.callStack.depth() == 0 {
+                    return Err(String::from("not inside a call, nothing to step out of"));
+                }
+                self.goal = Some(Goal::out(&mut self.c));
+                Ok(Value::Null)
+            },
+            "setBreakpoints" => {
+                // a line resolves through the label it defines, so the source has to be the one the .sym came from
+                let path = String::from(args["source"]["path"].as_str().unwrap_or(""));
+                let lines: Vec<String> = fs::read_to_string(&path).map(|t| t.lines().map(String::from).collect()).unwrap_or_default();
+                let ids = self.sourceBreakpoints.remove(&path).unwrap_or_default();
+                let (ids, breakpoints) = self.replaceBreakpoints(ids, args, |s, b| {
+                    let line = b["line"].as_u64().unwrap_or(0) as usize;
+                    let label = sourceLabel(&lines, line)
+                        .ok_or("no label on this line, use a function or instruction breakpoint")?;
+                    debugger::parseLocation(&s.c, &label)
+                });
+                self.sourceBreakpoints.insert(path, ids);
+                Ok(json!({"breakpoints": breakpoints}))
+            },
+            "setFunctionBreakpoints" => {
+                let ids = std::mem::take(&mut self.functionBreakpoints);
+                let (ids, breakpoints) = self.replaceBreakpoints(ids, args, |s, b| {
+                    debugger::parseLocation(&s.c, b["name"].as_str().unwrap_or(""))
+                });
+                self.functionBreakpoints = ids;
+                Ok(json!({"breakpoints": breakpoints}))
+            },
+            "setInstructionBreakpoints" => {
+                let ids = std::mem::take(&mut self.instructionBreakpoints);
+                let (ids, breakpoints) = self.replaceBreakpoints(ids, args, |_, b| {
+                    let addr = parseReference(&b["instructionReference"])? + b["offset"].as_i64().unwrap_or(0);
+                    Ok((None, addr as u16))
+                });
+                self.instructionBreakpoints = ids;
+                Ok(json!({"breakpoints": breakpoints}))
+            },
+            "stackTrace" => {
+                // frame 0 is the current instruction, the others sit on the call that entered the frame above
+                let frames = self.c.callStack.frames();
+                let mut locations = vec![(self.c.pc, frames.last().map_or(self.c.pc, |f| f.target))];
+                for (i, f) in frames.iter().enumerate().rev() {
+                    let function = if i > 0 {frames[i - 1].target} else {0x0100};
+                    locations.push((f.caller, function));
+                }
+                let stackFrames: Vec<Value> = locations.iter().enumerate().map(|(id, (pc, function))| json!({
+                    "id": id,
+                    "name": self.c.symbolAt(*function).unwrap_or(format!("{:04X}", function)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04X}", pc),
+                })).collect();
+                Ok(json!({"stackFrames": stackFrames, "totalFrames": locations.len()}))
+            },
+            "scopes" => {
+                Ok(json!({"scopes": [
+                    {"name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false},
+                    {"name": "Flags", "variablesReference": FLAGS_REF, "expensive": false},
+                    {"name": "I/O", "variablesReference": IO_REF, "expensive": false},
+                ]}))
+            },
+            "variables" => {Ok(json!({"variables": self.variables(args["variablesReference"].as_i64().unwrap_or(0))}))},
+            "setVariable" => {
+                let name = args["name"].as_str().unwrap_or("");
+                let value = debugger::evaluate(&self.c, args["value"].as_str().unwrap_or(""))?;
+                match args["variablesReference"].as_i64().unwrap_or(0) {
+                    REGISTERS_REF => {debugger::setRegister(&mut self.c, name, value)?},
+                    FLAGS_REF => {
+                        let (_, f) = FLAGS.iter().find(|(n, _)| *n == name).ok_or("unknown flag")?;
+                        let b = flagBit(f);
+                        self.c.f = if value != 0 {self.c.f | (1 << b)} else {self.c.f & !(1 << b)};
+                    },
+                    IO_REF => {
+                        let (_, addr) = IO_REGISTERS.iter().find(|(n, _)| *n == name).ok_or("unknown register")?;
+                        self.c.writeByte(*addr, value as u8);
+                        self.c.bus.breakpoints.takeHits();
+                    },
+                    _ => {return Err(String::from("unknown scope"))},
+                }
+                let v = self.variables(args["variablesReference"].as_i64().unwrap_or(0));
+                Ok(v.into_iter().find(|v| v["name"] == name).map_or(Value::Null, |v| json!({"value": v["value"]})))
+            },
+            "evaluate" => {
+                let v = Expression::parse(args["expression"].as_str().unwrap_or(""), &self.c.symbols)?.evaluate(&self.c);
+                Ok(json!({"result": format!("{:#X} ({})", v, v), "variablesReference": 0}))
+            },
+            "readMemory" => {
+                let addr = parseReference(&args["memoryReference"])? + args["offset"].as_i64().unwrap_or(0);
+                if !(0..0x10000).contains(&addr) {
+                    return Err(format!("address {} is outside the address space", addr));
+                }
+                let count = args["count"].as_i64().unwrap_or(0).min(0x10000 - addr).max(0);
+                let data: Vec<u8> = (0..count).map(|i| self.c.peekByte((addr + i) as u16)).collect();
+                Ok(json!({"address": format!("0x{:04X}", addr), "data": base64Encode(&data)}))
+            },
+            "writeMemory" => {
+                let addr = parseReference(&args["memoryReference"])? + args["offset"].as_i64().unwrap_or(0);
+                let data = base64Decode(args["data"].as_str().unwrap_or(""))?;
+                if addr < 0 || addr + data.len() as i64 > 0x10000 {
+                    return Err(format!("{} bytes at {:#X} don't fit in the address space", data.len(), addr));
+                }
+                for (i, d) in data.iter().enumerate() {
+                    let a = (addr + i as i64) as u16;
+                    if a < 0x8000 {
+                        return Err(format!("{:04X} is ROM", a));
+                    }
+                    self.c.writeByte(a, *d);
+                }
+                self.c.bus.breakpoints.takeHits();
+                Ok(json!({"bytesWritten": data.len()}))
+            },
+            "disassemble" => {
+                let addr = parseReference(&args["memoryReference"])? + args["offset"].as_i64().unwrap_or(0);
+                // without symbols going backwards is a guess, so negative offsets count one byte per instruction
+                let start = (addr + args["instructionOffset"].as_i64().unwrap_or(0)).max(0) as u16;
+                let count = args["instructionCount"].as_i64().unwrap_or(0).max(0) as u16;
+                // label lines have no tab, the label goes into the symbol field instead
+                let instructions: Vec<Value> = showCode(&self.c, start, count).lines().filter_map(|line| {
+                    let (address, text) = line.split_at(line.find('\t')?);
+                    let mut instruction = json!({"address": address, "instruction": text.trim()});
+                    if let Some(label) = expression::parseNumber(address).and_then(|a| self.c.labelAt(a as u16)) {
+                        instruction["symbol"] = json!(label);
+                    }
+                    Some(instruction)
+                }).collect();
+                Ok(json!({"instructions": instructions}))
+            },
+            _ => {Err(format!("{} is not supported", command))},
+        }
+    }
+
+    fn replaceBreakpoints<F>(&mut self, old: Vec<usize>, args: &Value, location: F) -> (Vec<usize>, Vec<Value>)
+        where F: Fn(&Self, &Value) -> Result<(Option<u16>, u16), String> {
+        for id in old {
+            self.c.bus.breakpoints.remove(id);
+        }
+        let mut ids = Vec::new();
+        let mut results = Vec::new();
+        for b in args["breakpoints"].as_array().unwrap_or(&Vec::new()) {
+            let condition = match b["condition"].as_str() {
+                Some(text) => Expression::parse(text, &self.c.symbols).map(Some),
+                None => Ok(None),
+            };
+            match (location(self, b), condition) {
+                (Ok((bank, addr)), Ok(condition)) => {
+                    let id = self.c.bus.breakpoints.add(Trigger::Pc {addr, bank}, condition);
+                    ids.push(id);
+                    results.push(json!({"id": id, "verified": true, "instructionReference": format!("0x{:04X}", addr)}));
+                },
+                (Err(e), _) | (_, Err(e)) => {results.push(json!({"verified": false, "message": e}))},
+            }
+        }
+        (ids, results)
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Value> {
+        let c = &self.c;
+        match reference {
+            REGISTERS_REF => {
+                let pair = |hi: u8, lo: u8| ((hi as u16) << 8) | lo as u16;
+                let mut v: Vec<Value> = [("A", c.a), ("F", c.f), ("B", c.b), ("C", c.c), ("D", c.d), ("E", c.e), ("H", c.h), ("L", c.l)]
+                    .iter()
+                    .map(|(n, r)| json!({"name": n, "value": format!("0x{:02X}", r), "variablesReference": 0}))
+                    .collect();
+                for (n, r) in [("BC", pair(c.b, c.c)), ("DE", pair(c.d, c.e)), ("HL", pair(c.h, c.l)), ("SP", c.sp), ("PC", c.pc)].iter() {
+                    let r = format!("0x{:04X}", r);
+                    v.push(json!({"name": n, "value": r, "variablesReference": 0, "memoryReference": r}));
+                }
+                v
+            },
+            FLAGS_REF => {
+                FLAGS.iter()
+                    .map(|(n, f)| json!({"name": n, "value": (c.f >> flagBit(f) & 1).to_string(), "variablesReference": 0}))
+                    .collect()
+            },
+            IO_REF => {
+                IO_REGISTERS.iter()
+                    .map(|(n, addr)| json!({"name": n, "value": format!("0x{:02X}", c.peekByte(*addr)), "variablesReference": 0}))
+                    .collect()
+            },
+            _ => {Vec::new()},
+        }
+    }
+}
+
+// Label defined on a 1-based source line, locals like .loop get the global label above as scope
+fn sourceLabel(lines: &[String], line: usize) -> Option<String> {
+    let label = |text: &str| -> Option<String> {
+        if text.starts_with(char::is_whitespace) || text.starts_with(';') {
+            return None;
+        }
+        let name = text.split(|ch: char| ch == ':' || ch.is_whitespace()).next()?;
+        // directives start in the first column too, only labels end with a colon
+        let colon = text[name.len()..].starts_with(':');
+        if name.is_empty() || !(colon || name.starts_with('.')) {None} else {Some(String::from(name))}
+    };
+    let name = label(lines.get(line.checked_sub(1)?)?)?;
+    if !name.starts_with('.') {
+        return Some(name);
+    }
+    let scope = lines[..line - 1].iter().rev().filter_map(|l| label(l)).find(|l| !l.starts_with('.'))?;
+    Some(scope + &name)
+}
+
+fn flagBit(f: &Flags) -> u8 {
+    match f {
+        Flags::Zero => 7,
+        Flags::Sub => 6,
+        Flags::HCarry => 5,
+        Flags::Carry => 4,
+    }
+}
+
+fn parseReference(v: &Value) -> Result<i64, String> {
+    v.as_str().and_then(expression::parseNumber).ok_or(format!("bad memory reference {}", v))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64Encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64Decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let (mut n, mut bits) = (0u32, 0);
+    for ch in text.bytes().filter(|ch| *ch != b'=') {
+        let v = BASE64.iter().position(|b| *b == ch).ok_or("bad base64 data")? as u32;
+        n = (n << 6) | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,357 @@
+use std::io::{self, BufRead, Write};
+use super::cpu::{Z80, Flags};
+use super::breakpoints::{Access, StopReason, Trigger};
+use super::expression::Expression;
+use super::trace::{Filter, Tracer};
+use super::visualizer::{showRam, showRegisters, showCode, showCallStack};
+use super::golden::FRAME_CYCLES;
+
+// Terminal debugger, started with --debug. Needs no window so it also works on headless machines.
+
+// M-cycles a run command may take before giving the prompt back, about ten emulated seconds
+const RUN_LIMIT: u64 = 10 * 1024 * 1024;
+
+const HELP: &str = "\
+step|s [n]                      run n instructions
+next|n                          step over calls and interrupts
+finish                          run until the current function returns
+backtrace|bt                    show the shadow call stack
+continue|c                      run until a breakpoint or watchpoint, at most about 10 s
+frame [n]                       run until n more frames are finished
+break|b [[bank:]addr|label [if cond]]
+                                add a PC breakpoint, lists them without arguments
+watch [r|w|rw] start[-end] [if cond]
+                                stop on memory accesses, rw by default
+watch io addr[=value] [if cond] stop on I/O register writes
+watch suspicious [if cond]      stop on accesses that do nothing on hardware, e.g.
+                                unusable memory, unmapped I/O or writes to ROM without a mapper
+delete|d [id]                   delete a breakpoint, all of them without an id
+registers|r                     show the registers
+set reg value                   set a register, e.g. set HL 0xC000
+x addr [rows]                   dump memory, 16 bytes per row
+write addr byte...              write bytes to memory
+disassemble|dis [addr] [n]      disassemble n instructions
+print|p expr                    evaluate an expression, e.g. p [HL]+1
+trace file [[bank:]start-end]   log instructions in the gameboy-doctor format
+trace off                       stop logging
+quit|q";
+
+pub fn run(c: &mut Z80) {
+    println!("{}", showCode(c, c.pc, 1).trim_end());
+    let stdin = io::stdin();
+    loop {
+        print!("(gb) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            return;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (command, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+        if command == "quit" || command == "q" {
+            return;
+        }
+        if let Err(e) = execute(c, command, rest) {
+            println!("error: {}", e);
+        }
+    }
+}
+
+fn execute(c: &mut Z80, command: &str, rest: &str) -> Result<(), String> {
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    match command {
+        "help" | "h" => {println!("{}", HELP)},
+        "step" | "s" => {
+            let n = optionalArg(c, args.first(), 1)?;
+            repeat(c, n, |_| Goal::Step);
+        },
+        "next" | "n" => {repeat(c, 1, Goal::over)},
+        "finish" => {
+            if c.callStack.depth() == 0 {
+                return Err(String::from("not inside a call, nothing to finish"));
+            }
+            repeat(c, 1, Goal::out);
+        },
+        "continue" | "c" => {repeat(c, 1, |_| Goal::Continue)},
+        "frame" => {
+            let n = optionalArg(c, args.first(), 1)?;
+            repeat(c, n, Goal::frame);
+        },
+        "break" | "b" => {
+            if args.is_empty() {
+                listBreakpoints(c);
+                return Ok(());
+            }
+            let (target, condition) = splitCondition(c, rest)?;
+            let (bank, addr) = parseLocation(c, target)?;
+            let trigger = Trigger::Pc {addr, bank};
+            let id = c.bus.breakpoints.add(trigger, condition);
+            println!("breakpoint {} set", id);
+        },
+        "watch" => {
+            let (target, condition) = splitCondition(c, rest)?;
+            let mut words: Vec<&str> = target.split_whitespace().collect();
+            let trigger = if words.first() == Some(&"suspicious") {
+                Trigger::Suspicious
+            } else if words.first() == Some(&"io") {
+                let spec = words.get(1).ok_or("missing register")?;
+                let (addr, value) = match spec.find('=') {
+                    Some(i) => (&spec[..i], Some(evaluate(c, &spec[i + 1..])? as u8)),
+                    None => (&spec[..], None),
+                };
+                Trigger::IoWrite {addr: evaluate(c, addr)? as u16, value}
+            } else {
+                let access = match words.first() {
+                    Some(&"r") => {words.remove(0); Access::Read},
+                    Some(&"w") => {words.remove(0); Access::Write},
+                    Some(&"rw") => {words.remove(0); Access::ReadWrite},
+                    _ => {Access::ReadWrite},
+                };
+                let range = words.join("");
+                let (start, end) = match range.find('-') {
+                    Some(i) if i > 0 => (evaluate(c, &range[..i])?, evaluate(c, &range[i + 1..])?),
+                    _ => {let a = evaluate(c, &range)?; (a, a)},
+                };
+                Trigger::Memory {start: start as u16, end: end as u16, access}
+            };
+            let id = c.bus.breakpoints.add(trigger, condition);
+            println!("watchpoint {} set", id);
+        },
+        "delete" | "d" => {
+            match args.first() {
+                Some(id) => {
+                    let id = evaluate(c, id)? as usize;
+                    if !c.bus.breakpoints.remove(id) {
+                        return Err(format!("no breakpoint {}", id));
+                    }
+                },
+                None => {
+                    let ids: Vec<usize> = c.bus.breakpoints.list().iter().map(|b| b.id).collect();
+                    for id in ids {
+                        c.bus.breakpoints.remove(id);
+                    }
+                },
+            }
+        },
+        "backtrace" | "bt" => {print!("{}", showCallStack(c))},
+        "registers" | "r" => {showState(c)},
+        "set" => {
+            if args.len() != 2 {
+                return Err(String::from("usage: set reg value"));
+            }
+            let value = evaluate(c, args[1])?;
+            setRegister(c, args[0], value)?;
+            showState(c);
+        },
+        "x" => {
+            let addr = evaluate(c, args.first().ok_or("missing address")?)? as u16;
+            let rows = optionalArg(c, args.get(1), 4)? as u16;
+            print!("{}", showRam(c, addr, rows, 16));
+        },
+        "write" => {
+            let addr = evaluate(c, args.first().ok_or("missing address")?)? as u16;
+            for (i, b) in args[1..].iter().enumerate() {
+                let a = addr.wrapping_add(i as u16);
+                if a < 0x8000 {
+                    return Err(format!("{:04X} is ROM", a));
+                }
+                let value = evaluate(c, b)? as u8;
+                c.writeByte(a, value);
+            }
+            // writes from the debugger should not fire watchpoints
+            c.bus.breakpoints.takeHits();
+        },
+        "disassemble" | "dis" => {
+            let addr = optionalArg(c, args.first(), c.pc as i64)? as u16;
+            let n = optionalArg(c, args.get(1), 10)? as u16;
+            print!("{}", showCode(c, addr, n));
+        },
+        "print" | "p" => {
+            let v = evaluate(c, rest)?;
+            println!("{:#X} ({})", v, v);
+        },
+        "trace" => {
+            match args.first() {
+                Some(&"off") => {c.trace = None},
+                Some(path) => {
+                    let filter = match args.get(1) {
+                        Some(f) => Some(Filter::parse(f)?),
+                        None => None,
+                    };
+                    c.trace = Some(Tracer::toFile(path, filter).map_err(|e| e.to_string())?);
+                },
+                None => {return Err(String::from("missing file"))},
+            }
+        },
+        _ => {return Err(format!("unknown command {}, try help", command))},
+    }
+    Ok(())
+}
+
+// What a run command waits for, checked after every instruction
+pub enum Goal {
+    Step,
+    // call stack depths, anything called or interrupting in between runs to completion
+    Over {depth: usize},
+    Out {depth: usize},
+    // also ends after a frame's worth of M-cycles, no frame is finished while the LCD is off
+    Frame {cycles: u32},
+    Continue,
+}
+
+impl Goal {
+    pub fn over(c: &mut Z80) -> Self {
+        Goal::Over {depth: c.callStack.depth()}
+    }
+
+    pub fn out(c: &mut Z80) -> Self {
+        Goal::Out {depth: c.callStack.depth()}
+    }
+
+    pub fn frame(c: &mut Z80) -> Self {
+        c.bus.gpu.frameReady = false;
+        Goal::Frame {cycles: 0}
+    }
+
+    // cycles is what the instruction that just ran took
+    pub fn reached(&mut self, c: &Z80, cycles: u32) -> bool {
+        match self {
+            Goal::Step => {true},
+            Goal::Over {depth} => {c.callStack.depth() <= *depth},
+            Goal::Out {depth} => {c.callStack.depth() < *depth},
+            Goal::Frame {cycles: total} => {
+                *total += cycles;
+                c.bus.gpu.frameReady || *total >= FRAME_CYCLES
+            },
+            Goal::Continue => {false},
+        }
+    }
+}
+
+// Runs whole instructions until the goal is reached, a breakpoint fires or RUN_LIMIT is hit
+pub fn runUntil(c: &mut Z80, goal: &mut Goal) -> Option<StopReason> {
+    let mut total = 0;
+    loop {
+        let cycles = c.step();
+        if let Some(r) = c.stopReason.take() {
+            return Some(r);
+        }
+        if goal.reached(c, cycles) {
+            return None;
+        }
+        total += cycles as u64;
+        if total >= RUN_LIMIT {
+            println!("still running after {} M-cycles, stopped", RUN_LIMIT);
+            return None;
+        }
+    }
+}
+
+fn repeat<F: Fn(&mut Z80) -> Goal>(c: &mut Z80, n: i64, goal: F) {
+    let mut stop = None;
+    for _i in 0..n {
+        let mut g = goal(c);
+        stop = runUntil(c, &mut g);
+        if stop.is_some() {
+            break;
+        }
+    }
+    if let Some(r) = stop {
+        println!("stopped: {}", r);
+    }
+    print!("{}", showCode(c, c.pc, 1));
+}
+
+fn showState(c: &Z80) {
+    println!("{}", showRegisters(c));
+    let flag = |f: Flags, name: char| if c.getFlag(f) {name} else {'-'};
+    println!("Flags: {}{}{}{}", flag(Flags::Zero, 'Z'), flag(Flags::Sub, 'N'), flag(Flags::HCarry, 'H'), flag(Flags::Carry, 'C'));
+}
+
+fn listBreakpoints(c: &Z80) {
+    for b in c.bus.breakpoints.list() {
+        let trigger = match b.trigger {
+            Trigger::Pc {addr, bank: Some(bank)} => format!("break {:02X}:{:04X}", bank, addr),
+            Trigger::Pc {addr, bank: None} => format!("break {:04X}", addr),
+            Trigger::Memory {start, end, access} => format!("watch {:?} {:04X}-{:04X}", access, start, end),
+            Trigger::IoWrite {addr, value: Some(v)} => format!("watch io {:04X}={:02X}", addr, v),
+            Trigger::IoWrite {addr, value: None} => format!("watch io {:04X}", addr),
+            Trigger::Suspicious => {String::from("watch suspicious")},
+        };
+        let condition = b.condition.as_ref().map(|e| format!(" if {}", e.source)).unwrap_or_default();
+        println!("{}: {}{}", b.id, trigger, condition);
+    }
+}
+
+fn splitCondition<'a>(c: &Z80, text: &'a str) -> Result<(&'a str, Option<Expression>), String> {
+    match text.find(" if ") {
+        Some(i) => Ok((text[..i].trim(), Some(Expression::parse(&text[i + 4..], &c.symbols)?))),
+        None => Ok((text.trim(), None)),
+    }
+}
+
+// addr, bank:addr or a label, labels in ROM only match in their own bank
+pub fn parseLocation(c: &Z80, text: &str) -> Result<(Option<u16>, u16), String> {
+    if let Some((bank, addr)) = c.symbols.lookup(text) {
+        return Ok((if addr < 0x8000 {Some(bank)} else {None}, addr));
+    }
+    match text.find(':') {
+        Some(i) => Ok((Some(evaluate(c, &text[..i])? as u16), evaluate(c, &text[i + 1..])? as u16)),
+        None => Ok((None, evaluate(c, text)? as u16)),
+    }
+}
+
+pub fn evaluate(c: &Z80, text: &str) -> Result<i64, String> {
+    Ok(Expression::parse(text, &c.symbols)?.evaluate(c))
+}
+
+fn optionalArg(c: &Z80, arg: Option<&&str>, default: i64) -> Result<i64, String> {
+    match arg {
+        Some(a) => evaluate(c, a),
+        None => Ok(default),
+    }
+}
+
+pub fn setRegister(c: &mut Z80, name: &str, value: i64) -> Result<(), String> {
+    let (hi, lo) = ((value >> 8) as u8, value as u8);
+    match name.to_ascii_uppercase().as_str() {
+        "A" => {c.a = lo}, "F" => {c.f = lo & 0xF0},
+        "B" => {c.b = lo}, "C" => {c.c = lo},
+        "D" => {c.d = lo}, "E" => {c.e = lo},
+        "H" => {c.h = lo}, "L" => {c.l = lo},
+        "AF" => {c.a = hi; c.f = lo & 0xF0},
+        "BC" => {c.b = hi; c.c = lo},
+        "DE" => {c.d = hi; c.e = lo},
+        "HL" => {c.h = hi; c.l = lo},
+        "SP" => {c.sp = value as u16},
+        "PC" => {c.jump(value as u16)},
+        _ => {return Err(format!("unknown register {}", name))},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parseLocation;
+    use crate::cpu::Z80;
+
+    #[test]
+    fn locations() {
+        let mut c = Z80::new();
+        c.symbols.add(3, 0x4123, "LoadLevel");
+        c.symbols.add(0, 0xC000, "wLevel");
+        assert_eq!(parseLocation(&c, "$0150"), Ok((None, 0x0150)));
+        assert_eq!(parseLocation(&c, "2:$4000"), Ok((Some(2), 0x4000)));
+        assert_eq!(parseLocation(&c, "$1F:$4000 + 8"), Ok((Some(0x1F), 0x4008)));
+        // ROM labels only match in their bank, RAM labels anywhere
+        assert_eq!(parseLocation(&c, "LoadLevel"), Ok((Some(3), 0x4123)));
+        assert_eq!(parseLocation(&c, "wLevel"), Ok((None, 0xC000)));
+        assert!(parseLocation(&c, "2:").is_err());
+    }
+}
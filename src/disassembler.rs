@@ -0,0 +1,560 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use super::symbols::Symbols;
+use super::cdl::{self, CodeDataLog, Domain};
+use super::opcodes::{self, OperandKind};
+
+// Recursive traversal disassembler for whole ROMs. Code is found by following every
+// jump, call and vector from the entry points, the rest is kept as data, and the
+// result is written as RGBDS source that assembles back to the same bytes.
+
+pub const BANK_SIZE: usize = 0x4000;
+
+// A isn't written out for these, "sub b" rather than "sub a, b"
+const IMPLIED_A: [&str; 5] = ["SUB", "AND", "XOR", "OR", "CP"];
+
+const INTERRUPT_VECTORS: [(u16, &str); 5] = [
+    (0x40, "VBlankInterrupt"),
+    (0x48, "LCDInterrupt"),
+    (0x50, "TimerInterrupt"),
+    (0x58, "SerialInterrupt"),
+    (0x60, "JoypadInterrupt"),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Flow {
+    Next,
+    Jump(u16),
+    // conditional jumps also fall through
+    Branch(u16),
+    Call(u16),
+    Rst(u16),
+    Return,
+    ConditionalReturn,
+    // jp hl, the target isn't known
+    Indirect,
+}
+
+pub struct Instruction {
+    pub len: usize,
+    // the target operand, when there is one, is appended when the source is written
+    pub text: String,
+    pub flow: Flow,
+    // the operand is a label candidate, false for rst which always names its vector
+    pub targetOperand: bool,
+}
+
+fn inst(len: usize, text: String, flow: Flow) -> Option<Instruction> {
+    Some(Instruction {len, text, flow, targetOperand: false})
+}
+
+fn branch(len: usize, text: String, flow: Flow) -> Option<Instruction> {
+    Some(Instruction {len, text, flow, targetOperand: true})
+}
+
+// RGBDS text of an operand, the branch targets are left out
+fn operandText(kind: OperandKind, n8: u8, n16: u16) -> String {
+    let signed = |v: i8| if v < 0 {format!("- {}", -(v as i16))} else {format!("+ {}", v)};
+    match kind {
+        OperandKind::AtBC => {String::from("[bc]")},
+        OperandKind::AtDE => {String::from("[de]")},
+        OperandKind::AtHL => {String::from("[hl]")},
+        OperandKind::AtHLI => {String::from("[hli]")},
+        OperandKind::AtHLD => {String::from("[hld]")},
+        OperandKind::AtC => {String::from("[c]")},
+        OperandKind::U8 => {format!("${:02X}", n8)},
+        OperandKind::U16 => {format!("${:04X}", n16)},
+        OperandKind::I8 => {(n8 as i8).to_string()},
+        OperandKind::Rel8 | OperandKind::Addr16 => {String::new()},
+        OperandKind::AtU16 => {format!("[${:04X}]", n16)},
+        OperandKind::AtHighU8 => {format!("[$FF{:02X}]", n8)},
+        OperandKind::SpPlusI8 => {format!("sp {}", signed(n8 as i8))},
+        OperandKind::Bit(n) => {n.to_string()},
+        OperandKind::Vector(v) => {format!("${:02X}", v)},
+        _ => {kind.name().to_ascii_lowercase()},
+    }
+}
+
+// None for the opcodes that lock up the CPU
+pub fn decode(b: [u8; 3], addr: u16) -> Option<Instruction> {
+    let prefixed = b[0] == 0xCB;
+    let o = if prefixed {opcodes::get(true, b[1])} else {opcodes::get(false, b[0])};
+    if o.isIllegal() {
+        return None;
+    }
+    let len = o.length as usize;
+    let n8 = b[1];
+    let n16 = u16::from_le_bytes([b[1], b[2]]);
+    let relative = addr.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+    match b[0] {
+        // rgbasm always emits stop followed by $00
+        0x10 if n8 != 0x00 => return inst(2, format!("db $10, ${:02X} ; stop", n8), Flow::Next),
+        // older rgbasm versions put a nop after halt, a db keeps the bytes exact everywhere
+        0x76 => return inst(1, String::from("db $76 ; halt"), Flow::Next),
+        // some rgbasm versions turn these into ldh when the address is in high RAM
+        0xEA if n16 >= 0xFF00 => return inst(3, format!("db $EA, ${:02X}, $FF ; ld [${:04X}], a", b[1], n16), Flow::Next),
+        0xFA if n16 >= 0xFF00 => return inst(3, format!("db $FA, ${:02X}, $FF ; ld a, [${:04X}]", b[1], n16), Flow::Next),
+        _ => {},
+    }
+
+    let mut operands = o.operands;
+    if IMPLIED_A.contains(&o.mnemonic) && operands.len() == 2 {
+        operands = &operands[1..];
+    }
+    let texts: Vec<String> = operands.iter().map(|k| operandText(*k, n8, n16)).collect();
+    let mnemonic = o.mnemonic.to_ascii_lowercase();
+    let text = if texts.is_empty() {mnemonic} else {format!("{} {}", mnemonic, texts.join(", "))};
+
+    let conditional = o.isConditional();
+    match (o.mnemonic, operands.last()) {
+        ("JR", _) if conditional => branch(len, text, Flow::Branch(relative)),
+        ("JR", _) => branch(len, text, Flow::Jump(relative)),
+        ("JP", Some(OperandKind::HL)) => inst(len, text, Flow::Indirect),
+        ("JP", _) if conditional => branch(len, text, Flow::Branch(n16)),
+        ("JP", _) => branch(len, text, Flow::Jump(n16)),
+        ("CALL", _) => branch(len, text, Flow::Call(n16)),
+        ("RET", _) if conditional => inst(len, text, Flow::ConditionalReturn),
+        ("RET", _) | ("RETI", _) => inst(len, text, Flow::Return),
+        ("RST", Some(OperandKind::Vector(v))) => inst(len, text, Flow::Rst(*v as u16)),
+        _ => inst(len, text, Flow::Next),
+    }
+}
+
+// Whether the instruction leaves an unknown value in A, used to follow bank switches
+fn writesA(b: [u8; 3]) -> bool {
+    match b[0] {
+        0x0A | 0x1A | 0x2A | 0x3A | 0x3C | 0x3D | 0x07 | 0x0F | 0x17 | 0x1F | 0x27 | 0x2F => true,
+        // LD A,r and the ALU ops but CP
+        0x78..= 0xB7 => true,
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xF0 | 0xF1 | 0xF2 | 0xFA => true,
+        0xCB => b[1] & 0x07 == 0x07 && b[1] & 0xC0 != 0x40,
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mark {
+    Unknown,
+    Opcode,
+    Operand,
+    // first byte of a jump table entry, the second is an Operand
+    Pointer,
+    Data,
+}
+
+pub struct Disassembly<'a> {
+    rom: &'a [u8],
+    pub marks: Vec<Mark>,
+    labels: BTreeMap<usize, String>,
+    // ROM offset each traced branch or table entry resolved to
+    targets: HashMap<usize, usize>,
+    queue: VecDeque<(usize, u16)>,
+    // opcodes a code/data log saw executed, traced after everything reachable statically
+    executed: Vec<usize>,
+}
+
+pub fn romOffset(bank: usize, addr: u16) -> usize {
+    if addr < 0x4000 {addr as usize} else {bank * BANK_SIZE + (addr as usize - 0x4000)}
+}
+
+fn location(offset: usize) -> (usize, u16) {
+    let bank = offset / BANK_SIZE;
+    let addr = if bank == 0 {offset} else {0x4000 + offset % BANK_SIZE};
+    (bank, addr as u16)
+}
+
+impl<'a> Disassembly<'a> {
+    pub fn new(rom: &'a [u8]) -> Self {
+        Self {
+            rom,
+            marks: vec![Mark::Unknown; rom.len()],
+            labels: BTreeMap::new(),
+            targets: HashMap::new(),
+            queue: VecDeque::new(),
+            executed: Vec::new(),
+        }
+    }
+
+    // Bytes the log only saw read as data are never traced into
+    pub fn applyLog(&mut self, log: &[u8]) {
+        if log.len() != self.rom.len() {
+            return;
+        }
+        for (offset, flags) in log.iter().enumerate() {
+            if flags & cdl::OPCODE != 0 {
+                self.executed.push(offset);
+            } else if flags & cdl::OPERAND == 0 && flags & cdl::DATA != 0 {
+                self.marks[offset] = Mark::Data;
+            }
+        }
+    }
+
+    fn addEntry(&mut self, bank: usize, addr: u16, name: String) {
+        let offset = romOffset(bank, addr);
+        if offset >= self.rom.len() {
+            return;
+        }
+        self.labels.entry(offset).or_insert(name);
+        self.queue.push_back((bank, addr));
+    }
+
+    // Bank of a target in ROMX, None when it depends on a bank switch that couldn't be followed
+    fn targetBank(&self, bank: usize, switched: Option<usize>, target: u16) -> Option<usize> {
+        match target {
+            0x0000..= 0x3FFF => Some(0),
+            0x4000..= 0x7FFF if bank != 0 => Some(bank),
+            0x4000..= 0x7FFF => match switched {
+                Some(b) => Some(b),
+                None if self.rom.len() <= 2 * BANK_SIZE => Some(1),
+                None => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        self.addEntry(0, 0x0100, String::from("Entry"));
+        self.drain();
+        // unused vectors often hold the tail of the previous handler or padding, so they come last
+        for (addr, name) in INTERRUPT_VECTORS.iter() {
+            let first = self.rom.get(*addr as usize).copied().unwrap_or(0);
+            if self.marks.get(*addr as usize) == Some(&Mark::Unknown) && first != 0x00 && first != 0xFF {
+                self.addEntry(0, *addr, String::from(*name));
+                self.drain();
+            }
+        }
+        // code only reached through jp hl or a bank switch that couldn't be followed
+        for offset in self.executed.clone() {
+            if self.marks[offset] == Mark::Unknown {
+                let (bank, addr) = location(offset);
+                self.queue.push_back((bank, addr));
+                self.drain();
+            }
+        }
+    }
+
+    fn drain(&mut self) {
+        while let Some((bank, addr)) = self.queue.pop_front() {
+            self.trace(bank, addr);
+        }
+    }
+
+    fn fetch(&self, bank: usize, addr: u16) -> Option<([u8; 3], Instruction)> {
+        let offset = romOffset(bank, addr);
+        if offset >= self.rom.len() {
+            return None;
+        }
+        let regionEnd = if addr < 0x4000 {0x4000} else {0x8000};
+        let available = (regionEnd - addr as usize).min(self.rom.len() - offset).min(3);
+        let mut b = [0u8; 3];
+        b[..available].copy_from_slice(&self.rom[offset..offset + available]);
+        match decode(b, addr) {
+            Some(i) if i.len <= available => Some((b, i)),
+            _ => None,
+        }
+    }
+
+    // A routine that pops its own return address and ends in jp hl reads a jump table
+    // placed right after the call, like rst $28 in many Nintendo games
+    fn dispatchesTable(&self, bank: usize, start: u16) -> bool {
+        let mut addr = start;
+        let mut depth = 0;
+        let mut popped = false;
+        for _ in 0..32 {
+            let (b, i) = match self.fetch(bank, addr) {
+                Some(f) => f,
+                None => return false,
+            };
+            match b[0] {
+                0xC5 | 0xD5 | 0xE5 | 0xF5 => {depth += 1},
+                0xC1 | 0xD1 | 0xE1 | 0xF1 if depth == 0 => {popped = true},
+                0xC1 | 0xD1 | 0xE1 | 0xF1 => {depth -= 1},
+                _ => {},
+            }
+            match i.flow {
+                Flow::Indirect => return popped,
+                Flow::Next | Flow::Branch(_) | Flow::ConditionalReturn => {},
+                _ => return false,
+            }
+            addr = addr.wrapping_add(i.len as u16);
+        }
+        false
+    }
+
+    // Reads pointers until one leaves ROM or the table runs into known code
+    fn readTable(&mut self, bank: usize, start: u16) {
+        let regionEnd = if start < 0x4000 {0x4000} else {0x8000};
+        let mut addr = start;
+        let mut entries = Vec::new();
+        while (addr as usize) + 2 <= regionEnd && entries.len() < 256 {
+            let offset = romOffset(bank, addr);
+            // a logged table was read as data, which is what it is
+            if offset + 2 > self.rom.len() || self.marks[offset..offset + 2].iter().any(|m| *m != Mark::Unknown && *m != Mark::Data) {
+                break;
+            }
+            if !entries.is_empty() && self.labels.contains_key(&offset) {
+                break;
+            }
+            let target = u16::from_le_bytes([self.rom[offset], self.rom[offset + 1]]);
+            if target >= 0x8000 || (target >= start && target < addr + 2) {
+                break;
+            }
+            self.marks[offset] = Mark::Pointer;
+            self.marks[offset + 1] = Mark::Operand;
+            entries.push((offset, target));
+            addr += 2;
+        }
+        for (offset, t) in entries {
+            self.follow(offset, bank, None, t, "Jump");
+        }
+    }
+
+    fn trace(&mut self, bank: usize, start: u16) {
+        let mut addr = start;
+        // last constant loaded into A and the bank it selected when written to the MBC
+        let mut lastA: Option<u8> = None;
+        let mut switched: Option<usize> = None;
+        loop {
+            let offset = romOffset(bank, addr);
+            if offset >= self.rom.len() || self.marks[offset] != Mark::Unknown {
+                return;
+            }
+            let regionEnd = if addr < 0x4000 {0x4000} else {0x8000};
+            let (b, i) = match self.fetch(bank, addr) {
+                Some(f) => f,
+                None => return,
+            };
+            if self.marks[offset..offset + i.len].iter().any(|m| *m != Mark::Unknown) {
+                return;
+            }
+            self.marks[offset] = Mark::Opcode;
+            for m in self.marks[offset + 1..offset + i.len].iter_mut() {
+                *m = Mark::Operand;
+            }
+
+            let n16 = u16::from_le_bytes([b[1], b[2]]);
+            if b[0] == 0x3E {
+                lastA = Some(b[1]);
+            } else if b[0] == 0xEA && (0x2000..0x4000).contains(&n16) {
+                // MBC1 and most others select ROM banks here, bank 0 maps bank 1
+                switched = lastA.map(|v| (v as usize).max(1));
+            } else if writesA(b) {
+                lastA = None;
+            }
+
+            match i.flow {
+                Flow::Next => {},
+                Flow::Jump(t) => {
+                    self.follow(offset, bank, switched, t, "Jump");
+                    return;
+                },
+                Flow::Branch(t) => {self.follow(offset, bank, switched, t, "Jump")},
+                Flow::Call(t) | Flow::Rst(t) => {
+                    let conditional = b[0] & 0xE7 == 0xC4;
+                    self.follow(offset, bank, switched, t, if b[0] & 0xC7 == 0xC7 {"Rst"} else {"Call"});
+                    lastA = None;
+                    // a conditional call falls through when not taken, so it can't be followed by a table
+                    if !conditional && self.targetBank(bank, switched, t).is_some_and(|tb| self.dispatchesTable(tb, t)) {
+                        self.readTable(bank, addr.wrapping_add(i.len as u16));
+                        return;
+                    }
+                },
+                Flow::Return | Flow::Indirect => {return},
+                Flow::ConditionalReturn => {},
+            }
+            addr = addr.wrapping_add(i.len as u16);
+            if addr as usize >= regionEnd {
+                return;
+            }
+        }
+    }
+
+    fn follow(&mut self, from: usize, bank: usize, switched: Option<usize>, target: u16, kind: &str) {
+        if let Some(b) = self.targetBank(bank, switched, target) {
+            self.targets.insert(from, romOffset(b, target));
+            self.addEntry(b, target, format!("{}_{:03X}_{:04X}", kind, b, target));
+        }
+    }
+
+    // Symbols replace the generated names and add labels the traversal didn't find
+    pub fn applySymbols(&mut self, symbols: &Symbols) {
+        let offsets: Vec<usize> = (0..self.rom.len()).collect();
+        for offset in offsets {
+            let (bank, addr) = location(offset);
+            if let Some(name) = symbols.label(bank as u16, addr) {
+                if self.marks[offset] != Mark::Operand {
+                    self.labels.insert(offset, String::from(name));
+                }
+            }
+        }
+    }
+
+    // Label of the branch or pointer at offset, when its target was traced to an instruction
+    fn labelFor(&self, offset: usize) -> Option<&str> {
+        let target = *self.targets.get(&offset)?;
+        match self.marks.get(target) {
+            Some(Mark::Opcode) => self.labels.get(&target).map(|s| s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn codeBytes(&self) -> usize {
+        self.marks.iter().filter(|m| **m == Mark::Opcode || **m == Mark::Operand).count()
+    }
+
+    pub fn source(&self) -> String {
+        let mut out = String::new();
+        for bank in 0..self.rom.len().div_ceil(BANK_SIZE) {
+            if bank == 0 {
+                out.push_str("SECTION \"ROM Bank $000\", ROM0[$0000]\n\n");
+            } else {
+                out.push_str(&format!("\nSECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]\n\n", bank, bank));
+            }
+            let end = ((bank + 1) * BANK_SIZE).min(self.rom.len());
+            let mut offset = bank * BANK_SIZE;
+            while offset < end {
+                if let Some(label) = self.labels.get(&offset) {
+                    out.push_str(&format!("{}:\n", label));
+                }
+                if self.marks[offset] == Mark::Opcode {
+                    offset = self.writeInstruction(&mut out, offset);
+                } else if self.marks[offset] == Mark::Pointer {
+                    offset = self.writePointer(&mut out, offset);
+                } else {
+                    offset = self.writeData(&mut out, offset, end);
+                }
+            }
+        }
+        out
+    }
+
+    fn writeInstruction(&self, out: &mut String, offset: usize) -> usize {
+        let (_, addr) = location(offset);
+        let mut b = [0u8; 3];
+        let available = (self.rom.len() - offset).min(3);
+        b[..available].copy_from_slice(&self.rom[offset..offset + available]);
+        // the marks and the decoder should agree, a byte they don't is kept as data
+        let i = match decode(b, addr) {
+            Some(i) => i,
+            None => {
+                out.push_str(&format!("    db ${:02X}\n", b[0]));
+                return offset + 1;
+            },
+        };
+        out.push_str("    ");
+        out.push_str(&i.text);
+        if i.targetOperand {
+            let target = match i.flow {
+                Flow::Jump(t) | Flow::Branch(t) | Flow::Call(t) => t,
+                _ => 0,
+            };
+            match self.labelFor(offset) {
+                Some(label) => out.push_str(label),
+                None => out.push_str(&format!("${:04X}", target)),
+            }
+        }
+        out.push('\n');
+        offset + i.len
+    }
+
+    fn writePointer(&self, out: &mut String, offset: usize) -> usize {
+        let target = u16::from_le_bytes([self.rom[offset], self.rom[offset + 1]]);
+        match self.labelFor(offset) {
+            Some(label) => out.push_str(&format!("    dw {}\n", label)),
+            None => out.push_str(&format!("    dw ${:04X}\n", target)),
+        }
+        offset + 2
+    }
+
+    // Data up to the next instruction or label, long runs of one value become ds
+    fn writeData(&self, out: &mut String, start: usize, end: usize) -> usize {
+        let mut stop = start + 1;
+        while stop < end && self.marks[stop] != Mark::Opcode && self.marks[stop] != Mark::Pointer && !self.labels.contains_key(&stop) {
+            stop += 1;
+        }
+        let data = &self.rom[start..stop];
+        let mut i = 0;
+        while i < data.len() {
+            let run = data[i..].iter().take_while(|b| **b == data[i]).count();
+            if run >= 8 {
+                out.push_str(&format!("    ds {}, ${:02X}\n", run, data[i]));
+                i += run;
+                continue;
+            }
+            let mut line = Vec::new();
+            while i < data.len() && line.len() < 16 && data[i..].iter().take_while(|b| **b == data[i]).count() < 8 {
+                line.push(format!("${:02X}", data[i]));
+                i += 1;
+            }
+            out.push_str(&format!("    db {}\n", line.join(", ")));
+        }
+        stop
+    }
+}
+
+// Writes name.asm and a Makefile into dir, name.cdl next to the ROM is used when there is one.
+// Returns the number of code bytes found and the ROM size
+pub fn exportProject(romPath: &str, dir: &str) -> std::io::Result<(usize, usize)> {
+    let rom = fs::read(romPath)?;
+    let mut d = Disassembly::new(&rom);
+    if let Ok(log) = CodeDataLog::load(&Path::new(romPath).with_extension("cdl")) {
+        d.applyLog(&log.block(Domain::Rom));
+    }
+    d.run();
+    d.applySymbols(&Symbols::loadNextTo(romPath));
+
+    let name = Path::new(romPath).file_stem().and_then(|s| s.to_str()).unwrap_or("game");
+    fs::create_dir_all(dir)?;
+    fs::write(Path::new(dir).join(format!("{}.asm", name)), d.source())?;
+    // the dumped ROM relative to the project, make compare ORIGINAL=... points it elsewhere
+    let original = relativePath(&fs::canonicalize(dir)?, &fs::canonicalize(romPath)?);
+    let makefile = format!(
+        "ORIGINAL ?= {1}\n\n{0}.gb: {0}.o\n\trgblink -o $@ $<\n\n{0}.o: {0}.asm\n\trgbasm -o $@ $<\n\ncompare: {0}.gb\n\tcmp $< $(ORIGINAL)\n",
+        name, original.display());
+    fs::write(Path::new(dir).join("Makefile"), makefile)?;
+    Ok((d.codeBytes(), rom.len()))
+}
+
+// Path of to as seen from the directory from, both absolute
+fn relativePath(from: &Path, to: &Path) -> PathBuf {
+    let (from, to): (Vec<Component>, Vec<Component>) = (from.components().collect(), to.components().collect());
+    let common = from.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _c in common..from.len() {
+        path.push("..");
+    }
+    for c in &to[common..] {
+        path.push(c);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Disassembly};
+    use crate::assembler::assemble;
+
+    #[test]
+    fn highRamThroughC() {
+        assert_eq!(decode([0xE2, 0x00, 0x00], 0).unwrap().text, "ldh [c], a");
+        assert_eq!(decode([0xF2, 0x00, 0x00], 0).unwrap().text, "ldh a, [c]");
+    }
+
+    // The in-tree assembler has no sections, so they are dropped and the banks assembled
+    // back to back, which only works for 32 KiB ROMs
+    #[test]
+    fn tetrisReassembles() {
+        let rom = include_bytes!("../roms/tetris.gb");
+        let mut d = Disassembly::new(rom);
+        d.run();
+        let source: String = d.source().lines()
+            .filter(|l| !l.starts_with("SECTION"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let program = assemble(&source, 0x0000).unwrap();
+        assert_eq!(program.bytes.len(), rom.len());
+        assert!(program.bytes == rom[..], "reassembled bytes differ");
+        assert!(d.codeBytes() > 0x1000);
+    }
+}
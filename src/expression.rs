@@ -0,0 +1,276 @@
+use super::cpu::Z80;
+use super::memory::Memory;
+use super::symbols::Symbols;
+
+// Small expressions over the CPU state, used for breakpoint conditions and the debugger.
+// Registers (A, BC, SP, PC, ...), numbers (0x3C, $3C, %1010, 60), labels, [addr] reads a byte,
+// and the usual C operators: ! ~ - + & | ^ == != < > <= >= && ||
+
+#[derive(Clone, Debug)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Node>),
+    Unary(char, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+#[derive(Clone, Debug)]
+pub struct Expression {
+    pub source: String,
+    root: Node,
+}
+
+const BINARY_LEVELS: [&[&str]; 6] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<=", ">=", "<", ">"],
+    &["|", "^"],
+    &["&"],
+    &["+", "-"],
+];
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn skipSpaces(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skipSpaces();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == BINARY_LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'outer: loop {
+            for op in BINARY_LEVELS[level].iter() {
+                // keep && and || from being read as & and |
+                let doubled = op.len() == 1 && self.text[self.pos..].trim_start().starts_with(&op.repeat(2));
+                if !doubled && self.eat(op) {
+                    let right = self.binary(level + 1)?;
+                    left = Node::Binary(op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        for op in ['!', '~', '-'].iter() {
+            // != is a comparison, not a negation
+            if !self.text[self.pos..].trim_start().starts_with("!=") && self.eat(&op.to_string()) {
+                return Ok(Node::Unary(*op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        if self.eat("(") {
+            let n = self.binary(0)?;
+            return if self.eat(")") {Ok(n)} else {Err(format!("expected ) at {}", self.pos))};
+        }
+        if self.eat("[") {
+            let n = self.binary(0)?;
+            return if self.eat("]") {Ok(Node::Memory(Box::new(n)))} else {Err(format!("expected ] at {}", self.pos))};
+        }
+        self.skipSpaces();
+        let start = self.pos;
+        let rest = &self.text[self.pos..];
+        let len = rest.find(|ch: char| !(ch.is_ascii_alphanumeric() || "$%_.".contains(ch))).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(format!("unexpected input at {}", start));
+        }
+        self.pos += len;
+        let word = &rest[..len];
+        if let Some(r) = parseRegister(word) {
+            return Ok(Node::Register(r));
+        }
+        if let Some((_, addr)) = self.symbols.lookup(word) {
+            return Ok(Node::Number(addr as i64));
+        }
+        parseNumber(word).map(Node::Number).ok_or(format!("unknown value {}", word))
+    }
+}
+
+fn parseRegister(word: &str) -> Option<Register> {
+    Some(match word.to_ascii_uppercase().as_str() {
+        "A" => Register::A, "F" => Register::F,
+        "B" => Register::B, "C" => Register::C,
+        "D" => Register::D, "E" => Register::E,
+        "H" => Register::H, "L" => Register::L,
+        "AF" => Register::AF, "BC" => Register::BC,
+        "DE" => Register::DE, "HL" => Register::HL,
+        "SP" => Register::SP, "PC" => Register::PC,
+        _ => return None,
+    })
+}
+
+pub fn parseNumber(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or(lower.strip_prefix("$")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b").or(lower.strip_prefix("%")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+impl Expression {
+    // Labels are resolved here, so a condition keeps its address if symbols are reloaded
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Self, String> {
+        let mut p = Parser {text, pos: 0, symbols};
+        let root = p.binary(0)?;
+        p.skipSpaces();
+        if p.pos != text.len() {
+            return Err(format!("unexpected input at {}", p.pos));
+        }
+        Ok(Self {
+            source: String::from(text.trim()),
+            root,
+        })
+    }
+
+    pub fn evaluate<M: Memory>(&self, c: &Z80<M>) -> i64 {
+        evaluate(&self.root, c)
+    }
+}
+
+fn register<M: Memory>(r: Register, c: &Z80<M>) -> i64 {
+    let pair = |hi: u8, lo: u8| ((hi as i64) << 8) | lo as i64;
+    match r {
+        Register::A => {c.a as i64}, Register::F => {c.f as i64},
+        Register::B => {c.b as i64}, Register::C => {c.c as i64},
+        Register::D => {c.d as i64}, Register::E => {c.e as i64},
+        Register::H => {c.h as i64}, Register::L => {c.l as i64},
+        Register::AF => {pair(c.a, c.f)}, Register::BC => {pair(c.b, c.c)},
+        Register::DE => {pair(c.d, c.e)}, Register::HL => {pair(c.h, c.l)},
+        Register::SP => {c.sp as i64}, Register::PC => {c.pc as i64},
+    }
+}
+
+fn evaluate<M: Memory>(n: &Node, c: &Z80<M>) -> i64 {
+    match n {
+        Node::Number(v) => {*v},
+        Node::Register(r) => {register(*r, c)},
+        Node::Memory(addr) => {c.peekByte(evaluate(addr, c) as u16) as i64},
+        Node::Unary(op, a) => {
+            let v = evaluate(a, c);
+            match op {
+                '!' => {(v == 0) as i64},
+                '~' => {!v},
+                _ => {-v},
+            }
+        },
+        Node::Binary(op, a, b) => {
+            let (x, y) = (evaluate(a, c), evaluate(b, c));
+            match *op {
+                "||" => {(x != 0 || y != 0) as i64},
+                "&&" => {(x != 0 && y != 0) as i64},
+                "==" => {(x == y) as i64},
+                "!=" => {(x != y) as i64},
+                "<=" => {(x <= y) as i64},
+                ">=" => {(x >= y) as i64},
+                "<" => {(x < y) as i64},
+                ">" => {(x > y) as i64},
+                "|" => {x | y},
+                "^" => {x ^ y},
+                "&" => {x & y},
+                "+" => {x.wrapping_add(y)},
+                _ => {x.wrapping_sub(y)},
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expression;
+    use crate::cpu::Z80;
+    use crate::symbols::Symbols;
+
+    fn eval(text: &str, c: &Z80) -> i64 {
+        Expression::parse(text, &Symbols::new()).unwrap().evaluate(c)
+    }
+
+    #[test]
+    fn precedence() {
+        let c = Z80::new();
+        assert_eq!(eval("2 + 3 & 6", &c), 4);
+        assert_eq!(eval("1 | 2 == 3", &c), 1);
+        assert_eq!(eval("0 && 1 || 1", &c), 1);
+        assert_eq!(eval("-1 + 3", &c), 2);
+        assert_eq!(eval("~0 & $FF", &c), 0xFF);
+        assert_eq!(eval("!(1 != 1)", &c), 1);
+        assert_eq!(eval("%1010 ^ 0x0F", &c), 5);
+    }
+
+    #[test]
+    fn anyWhitespaceSeparatesTokens() {
+        let c = Z80::new();
+        assert_eq!(eval("\t1\t+\n2 ", &c), 3);
+        assert!(Expression::parse("1 2", &Symbols::new()).is_err());
+    }
+
+    #[test]
+    fn registerNames() {
+        let mut c = Z80::new();
+        c.a = 0x3C;
+        c.b = 0x12;
+        c.c = 0x34;
+        c.sp = 0xCFFE;
+        c.pc = 0x0150;
+        assert_eq!(eval("a == 0x3C && A == 60", &c), 1);
+        assert_eq!(eval("bc", &c), 0x1234);
+        assert_eq!(eval("sp + 2", &c), 0xD000);
+        assert_eq!(eval("PC", &c), 0x0150);
+    }
+
+    #[test]
+    fn dereference() {
+        let mut c = Z80::new();
+        c.bus.cpuWrite(0xC000, 0x42);
+        c.bus.cpuWrite(0xC001, 0x99);
+        c.h = 0xC0;
+        c.l = 0x01;
+        assert_eq!(eval("[hl]", &c), 0x99);
+        assert_eq!(eval("[hl - 1] == $42", &c), 1);
+        assert_eq!(eval("[$C000 + 1] - [$C000]", &c), 0x57);
+
+        let mut symbols = Symbols::new();
+        symbols.add(0, 0xC000, "wCounter");
+        assert_eq!(Expression::parse("[wCounter] + 1", &symbols).unwrap().evaluate(&c), 0x43);
+    }
+
+    #[test]
+    fn errors() {
+        let symbols = Symbols::new();
+        assert!(Expression::parse("(1 + 2", &symbols).is_err());
+        assert!(Expression::parse("[hl", &symbols).is_err());
+        assert!(Expression::parse("1 +", &symbols).is_err());
+        assert!(Expression::parse("wMissing", &symbols).is_err());
+    }
+}
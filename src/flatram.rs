@@ -0,0 +1,135 @@
+use std::cell::RefCell;
+use super::memory::Memory;
+
+// 64 KiB of plain RAM the CPU can run on instead of the bus, for conformance tests and fuzzing.
+// Every access is recorded with the M-cycle it happened in. IF and IE live at FF0F and FFFF
+// like on the real memory map, nothing else is special.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BusAccess {
+    pub cycle: u32,
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+pub struct FlatRam {
+    pub ram: Vec<u8>,
+    // T-cycles since the recording was cleared
+    pub ticks: u32,
+    accesses: RefCell<Vec<BusAccess>>,
+}
+
+impl FlatRam {
+    pub fn new() -> Self {
+        Self {
+            ram: vec![0; 0x10000],
+            ticks: 0,
+            accesses: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        let value = self.ram[addr as usize];
+        self.record(addr, value, AccessKind::Read);
+        value
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.ram[addr as usize] = value;
+        self.record(addr, value, AccessKind::Write);
+    }
+
+    fn record(&self, addr: u16, value: u8, kind: AccessKind) {
+        self.accesses.borrow_mut().push(BusAccess {cycle: self.ticks / 4, addr, value, kind});
+    }
+
+    pub fn accesses(&self) -> Vec<BusAccess> {
+        self.accesses.borrow().clone()
+    }
+
+    pub fn clearAccesses(&mut self) {
+        self.ticks = 0;
+        self.accesses.borrow_mut().clear();
+    }
+}
+
+impl Memory for FlatRam {
+    fn read(&self, addr: u16, _flag: u8) -> u8 {
+        FlatRam::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        FlatRam::write(self, addr, value);
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    fn interruptEnable(&self) -> u8 {
+        self.ram[0xFFFF]
+    }
+
+    fn interruptRequest(&self) -> u8 {
+        self.ram[0xFF0F]
+    }
+
+    fn setInterruptRequest(&mut self, value: u8) {
+        self.ram[0xFF0F] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlatRam, BusAccess, AccessKind};
+    use crate::cpu::Z80;
+
+    fn access(cycle: u32, addr: u16, value: u8, kind: AccessKind) -> BusAccess {
+        BusAccess {cycle, addr, value, kind}
+    }
+
+    #[test]
+    fn accessesPerCycle() {
+        let mut ram = FlatRam::new();
+        // ld a, [hl] / push bc / nop
+        ram.ram[0x0100..0x0103].copy_from_slice(&[0x7E, 0xC5, 0x00]);
+        ram.ram[0xC000] = 0x5A;
+        let mut c = Z80::withMemory(ram);
+        c.h = 0xC0;
+        c.l = 0x00;
+        c.b = 0x12;
+        c.c = 0x34;
+        c.sp = 0xD000;
+        c.jump(0x0100);
+
+        // the read, then the fetch of the next opcode
+        c.bus.clearAccesses();
+        assert_eq!(c.step(), 2);
+        assert_eq!(c.bus.accesses(), [
+            access(0, 0xC000, 0x5A, AccessKind::Read),
+            access(1, 0x0101, 0xC5, AccessKind::Read),
+        ]);
+        assert_eq!(c.a, 0x5A);
+
+        // an internal cycle, the high byte first, then the fetch
+        c.bus.clearAccesses();
+        assert_eq!(c.step(), 4);
+        assert_eq!(c.bus.accesses(), [
+            access(1, 0xCFFF, 0x12, AccessKind::Write),
+            access(2, 0xCFFE, 0x34, AccessKind::Write),
+            access(3, 0x0102, 0x00, AccessKind::Read),
+        ]);
+        assert_eq!(c.bus.ticks, 16);
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use super::cpu::Z80;
+use super::bus::Model;
+use super::gpu::{colorToRgba, SCREEN_WIDTH, SCREEN_HEIGHT};
+use super::sgb::{SGB_WIDTH, SGB_HEIGHT};
+use super::joypad::Button;
+
+// Golden-frame tests: run a ROM for a number of frames, optionally pressing buttons from a
+// script, and compare the screen with a reference PNG, e.g. the dmg-acid2 and cgb-acid2 ones.
+// On a mismatch the frame we got and a diff image are written next to the reference.
+
+// M-cycles of one frame, frames keep being counted this way while the LCD is off
+pub const FRAME_CYCLES: u32 = 70224 / 4;
+
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // RGBA, 4 bytes per pixel
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    // What's on screen, with the SGB border when running as SGB
+    pub fn screen(c: &Z80) -> Self {
+        let (frame, width, height): (&[u16], usize, usize) = if c.bus.model == Model::Sgb {
+            (&c.bus.sgb.frame, SGB_WIDTH, SGB_HEIGHT)
+        } else {
+            (&c.bus.gpu.frameBuffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+        };
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for color in frame.iter() {
+            pixels.extend_from_slice(&colorToRgba(*color));
+        }
+        Self {width, height, pixels}
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut decoder = png::Decoder::new(file);
+        // palette and 16 bit images come out as 8 bit gray or color
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let data = &buffer[..info.buffer_size()];
+        let pixels: Vec<u8> = match info.color_type {
+            png::ColorType::Rgba => {data.to_vec()},
+            png::ColorType::Rgb => {data.chunks(3).flat_map(|p| [p[0], p[1], p[2], 0xFF]).collect()},
+            png::ColorType::GrayscaleAlpha => {data.chunks(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect()},
+            _ => {data.iter().flat_map(|g| [*g, *g, *g, 0xFF]).collect()},
+        };
+        Ok(Self {
+            width: info.width as usize,
+            height: info.height as usize,
+            pixels,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| format!("{}: {}", path.display(), e))?;
+        writer.write_image_data(&self.pixels).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // FNV-1a over the pixels, short enough to log and compare by eye
+    pub fn hash(&self) -> u64 {
+        self.pixels.iter().fold(0xCBF29CE484222325, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001B3))
+    }
+
+    // Pixels that differ by more than tolerance in any channel, alpha is ignored
+    pub fn differences(&self, other: &Image, tolerance: u8) -> usize {
+        self.pixels.chunks(4).zip(other.pixels.chunks(4))
+            .filter(|(a, b)| (0..3).any(|i| a[i].abs_diff(b[i]) > tolerance))
+            .count()
+    }
+
+    // Differing pixels in red over a faded copy of this image
+    pub fn diff(&self, other: &Image, tolerance: u8) -> Self {
+        let pixels = self.pixels.chunks(4).zip(other.pixels.chunks(4)).flat_map(|(a, b)| {
+            if (0..3).any(|i| a[i].abs_diff(b[i]) > tolerance) {
+                [0xFF, 0x00, 0x00, 0xFF]
+            } else {
+                let gray = ((a[0] as u16 + a[1] as u16 + a[2] as u16) / 3 / 4 + 0xC0) as u8;
+                [gray, gray, gray, 0xFF]
+            }
+        }).collect();
+        Self {width: self.width, height: self.height, pixels}
+    }
+}
+
+pub struct InputEvent {
+    pub frame: u64,
+    pub button: Button,
+    pub down: bool,
+}
+
+// One event per line, "FRAME press|release BUTTON", # starts a comment:
+//   120 press start
+//   122 release start
+pub fn parseInputScript(text: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let bad = || format!("line {}: expected FRAME press|release BUTTON", i + 1);
+        if words.len() != 3 {
+            return Err(bad());
+        }
+        let frame = words[0].parse().map_err(|_| bad())?;
+        let down = match words[1] {
+            "press" => true,
+            "release" => false,
+            _ => return Err(bad()),
+        };
+        let button = Button::parse(words[2]).ok_or(format!("line {}: unknown button {}", i + 1, words[2]))?;
+        events.push(InputEvent {frame, button, down});
+    }
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+fn runFrame(c: &mut Z80) {
+    c.bus.gpu.frameReady = false;
+    let mut cycles = 0;
+    while !c.bus.gpu.frameReady && cycles < FRAME_CYCLES {
+        cycles += c.step();
+    }
+}
+
+// Runs frames frames, events are applied before the frame they name
+pub fn run(c: &mut Z80, frames: u64, events: &[InputEvent]) {
+    let mut next = events.iter().peekable();
+    for frame in 0..frames {
+        while let Some(e) = next.next_if(|e| e.frame <= frame) {
+            c.bus.setButton(0, e.button, e.down);
+        }
+        runFrame(c);
+    }
+}
+
+fn sibling(reference: &Path, suffix: &str) -> PathBuf {
+    let stem = reference.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    reference.with_file_name(format!("{}.{}.png", stem, suffix))
+}
+
+// Compares the screen with the reference, or replaces the reference when blessing.
+// Prints what happened and returns true when the test passed.
+pub fn check(c: &Z80, reference: &Path, tolerance: u8, bless: bool) -> Result<bool, String> {
+    let actual = Image::screen(c);
+    println!("frame hash {:016x}", actual.hash());
+    if bless {
+        if let Some(dir) = reference.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        actual.save(reference)?;
+        println!("blessed {}", reference.display());
+        return Ok(true);
+    }
+
+    let expected = Image::load(reference)?;
+    let (actualPath, diffPath) = (sibling(reference, "actual"), sibling(reference, "diff"));
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        actual.save(&actualPath)?;
+        println!("size differs: {}x{}, reference is {}x{}, frame written to {}",
+            actual.width, actual.height, expected.width, expected.height, actualPath.display());
+        return Ok(false);
+    }
+    let differences = actual.differences(&expected, tolerance);
+    if differences == 0 {
+        println!("matches {}", reference.display());
+        return Ok(true);
+    }
+    actual.save(&actualPath)?;
+    actual.diff(&expected, tolerance).save(&diffPath)?;
+    println!("{} pixels differ from {}, frame written to {} and diff to {}",
+        differences, reference.display(), actualPath.display(), diffPath.display());
+    Ok(false)
+}
@@ -0,0 +1,437 @@
+use super::bit;
+use super::bus::IntrFlags;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+
+// DMG shades expressed as 15 bit colors, lightest first
+const DMG_SHADES: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+pub struct Gpu {
+    vram: [[u8; 8 * 1024]; 2],
+    oam: [u8; 160],
+
+    pub cgbMode: bool,
+    // DMG game on CGB hardware, BGP/OBP0/OBP1 index into palettes set up by the boot ROM
+    compatibility: bool,
+    pub vramBank: u8,
+
+    pub lcdc: u8,
+    stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+
+    bgPaletteRam: [u8; 64],
+    objPaletteRam: [u8; 64],
+    bgPaletteIndex: u8,
+    objPaletteIndex: u8,
+
+    dot: u16,
+    windowLine: u8,
+    statLine: bool,
+
+    // 15 bit colors, red in the low bits, same layout as CGB palette RAM
+    pub frameBuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // DMG shade (0-3) of every pixel after BGP/OBP, the SGB colors the screen from it
+    pub shadeBuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    pub frameReady: bool,
+    // set on the dot a visible line enters HBlank, HBlank DMA is driven by it
+    pub hblankStarted: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+impl Gpu {
+    pub fn new() -> Self {
+        Self {
+            vram: [[0; 8 * 1024]; 2],
+            oam: [0; 160],
+
+            cgbMode: false,
+            compatibility: false,
+            vramBank: 0,
+
+            lcdc: 0x91,
+            stat: 0x80,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+
+            bgPaletteRam: [0xFF; 64],
+            objPaletteRam: [0; 64],
+            bgPaletteIndex: 0,
+            objPaletteIndex: 0,
+
+            dot: 0,
+            windowLine: 0,
+            statLine: false,
+
+            frameBuffer: [DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shadeBuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frameReady: false,
+            hblankStarted: false,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        match self.stat & 0b11 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            _ => Mode::Drawing,
+        }
+    }
+
+    fn setMode(&mut self, m: Mode) {
+        self.stat = (self.stat & !0b11) | m as u8;
+    }
+
+    pub fn lcdEnabled(&self) -> bool {
+        bit::get(self.lcdc, 7)
+    }
+
+    pub fn readVram(&self, addr: u16) -> u8 {
+        self.vram[self.vramBank as usize][(addr & 0x1FFF) as usize]
+    }
+
+    pub fn writeVram(&mut self, addr: u16, data: u8) {
+        self.vram[self.vramBank as usize][(addr & 0x1FFF) as usize] = data;
+    }
+
+    pub fn readOam(&self, addr: u16) -> u8 {
+        self.oam[(addr & 0x00FF) as usize]
+    }
+
+    pub fn writeOam(&mut self, addr: u16, data: u8) {
+        self.oam[(addr & 0x00FF) as usize] = data;
+    }
+
+    pub fn readRegister(&self, addr: u16) -> u8 {
+        match addr & 0x00FF {
+            0x40 => {self.lcdc},
+            0x41 => {self.stat | 0x80},
+            0x42 => {self.scy},
+            0x43 => {self.scx},
+            0x44 => {self.ly},
+            0x45 => {self.lyc},
+            0x47 => {self.bgp},
+            0x48 => {self.obp0},
+            0x49 => {self.obp1},
+            0x4A => {self.wy},
+            0x4B => {self.wx},
+            // the CGB registers read as FF on DMG
+            0x4F if self.cgbMode => {0xFE | self.vramBank},
+            0x68 if self.cgbMode => {self.bgPaletteIndex | 0x40},
+            0x69 if self.cgbMode => {self.bgPaletteRam[(self.bgPaletteIndex & 0x3F) as usize]},
+            0x6A if self.cgbMode => {self.objPaletteIndex | 0x40},
+            0x6B if self.cgbMode => {self.objPaletteRam[(self.objPaletteIndex & 0x3F) as usize]},
+            _ => {0xFF}
+        }
+    }
+
+    pub fn writeRegister(&mut self, addr: u16, data: u8) {
+        match addr & 0x00FF {
+            0x40 => {
+                let wasEnabled = self.lcdEnabled();
+                self.lcdc = data;
+                if wasEnabled && !self.lcdEnabled() {
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.windowLine = 0;
+                    self.setMode(Mode::HBlank);
+                } else if !wasEnabled && self.lcdEnabled() {
+                    self.setMode(Mode::OamScan);
+                }
+            },
+            0x41 => {self.stat = (self.stat & 0x07) | (data & 0x78)},
+            0x42 => {self.scy = data},
+            0x43 => {self.scx = data},
+            0x44 => {/* LY is read only */},
+            0x45 => {self.lyc = data},
+            0x47 => {self.bgp = data},
+            0x48 => {self.obp0 = data},
+            0x49 => {self.obp1 = data},
+            0x4A => {self.wy = data},
+            0x4B => {self.wx = data},
+            0x4F if self.cgbMode => {self.vramBank = data & 0x01},
+            0x68 if self.cgbMode => {self.bgPaletteIndex = data & 0xBF},
+            0x69 if self.cgbMode => {
+                self.bgPaletteRam[(self.bgPaletteIndex & 0x3F) as usize] = data;
+                self.bgPaletteIndex = Self::autoIncrement(self.bgPaletteIndex);
+            },
+            0x6A if self.cgbMode => {self.objPaletteIndex = data & 0xBF},
+            0x6B if self.cgbMode => {
+                self.objPaletteRam[(self.objPaletteIndex & 0x3F) as usize] = data;
+                self.objPaletteIndex = Self::autoIncrement(self.objPaletteIndex);
+            },
+            _ => {}
+        }
+    }
+
+    // BCPS/OCPS only advance when bit 7 is set, wrapping inside the 64 byte RAM
+    fn autoIncrement(index: u8) -> u8 {
+        if bit::get(index, 7) {
+            0x80 | ((index + 1) & 0x3F)
+        } else {
+            index
+        }
+    }
+
+    // Advances the PPU by one dot, returns the interrupt request bits to raise
+    pub fn clock(&mut self) -> u8 {
+        let mut interrupts = 0;
+        if !self.lcdEnabled() {
+            return interrupts;
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly = (self.ly + 1) % LINES_PER_FRAME;
+            if self.ly == 0 {
+                self.windowLine = 0;
+            }
+        }
+
+        if self.ly >= SCREEN_HEIGHT as u8 {
+            if self.ly == SCREEN_HEIGHT as u8 && self.dot == 0 {
+                self.setMode(Mode::VBlank);
+                self.frameReady = true;
+                interrupts = bit::set(interrupts, IntrFlags::VBlank as usize);
+            }
+        } else if self.dot == 0 {
+            self.setMode(Mode::OamScan);
+        } else if self.dot == OAM_SCAN_DOTS {
+            self.setMode(Mode::Drawing);
+        } else if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+            self.renderScanline();
+            self.setMode(Mode::HBlank);
+            self.hblankStarted = true;
+        }
+
+        self.stat = if self.ly == self.lyc {bit::set(self.stat, 2)} else {bit::clr(self.stat, 2)};
+
+        // STAT interrupt fires on the rising edge of the ORed sources
+        let line = (bit::get(self.stat, 6) && bit::get(self.stat, 2))
+            || (bit::get(self.stat, 3) && self.mode() == Mode::HBlank)
+            || (bit::get(self.stat, 4) && self.mode() == Mode::VBlank)
+            || (bit::get(self.stat, 5) && self.mode() == Mode::OamScan);
+        if line && !self.statLine {
+            interrupts = bit::set(interrupts, IntrFlags::LCD as usize);
+        }
+        self.statLine = line;
+
+        interrupts
+    }
+
+    fn bgColor(&self, palette: u8, index: u8) -> u16 {
+        let i = (palette as usize) * 8 + (index as usize) * 2;
+        u16::from_le_bytes([self.bgPaletteRam[i], self.bgPaletteRam[i + 1]]) & 0x7FFF
+    }
+
+    fn objColor(&self, palette: u8, index: u8) -> u16 {
+        let i = (palette as usize) * 8 + (index as usize) * 2;
+        u16::from_le_bytes([self.objPaletteRam[i], self.objPaletteRam[i + 1]]) & 0x7FFF
+    }
+
+    pub fn loadCompatibilityPalettes(&mut self, bg: &[u16; 4], obj0: &[u16; 4], obj1: &[u16; 4]) {
+        for i in 0..4 {
+            self.bgPaletteRam[i * 2..i * 2 + 2].copy_from_slice(&bg[i].to_le_bytes());
+            self.objPaletteRam[i * 2..i * 2 + 2].copy_from_slice(&obj0[i].to_le_bytes());
+            self.objPaletteRam[8 + i * 2..8 + i * 2 + 2].copy_from_slice(&obj1[i].to_le_bytes());
+        }
+        self.compatibility = true;
+    }
+
+    // obj selects OBP0/OBP1 so compatibility mode can pick the matching CGB palette
+    fn putDmgPixel(&mut self, pos: usize, palette: u8, index: u8, obj: Option<u8>) {
+        let shade = (palette >> (index * 2)) & 0b11;
+        self.shadeBuffer[pos] = shade;
+        self.frameBuffer[pos] = if !self.compatibility {
+            DMG_SHADES[shade as usize]
+        } else {
+            match obj {
+                Some(p) => self.objColor(p, shade),
+                None => self.bgColor(0, shade),
+            }
+        };
+    }
+
+    fn tilePixel(&self, bank: usize, tileAddr: u16, x: u8, y: u8) -> u8 {
+        let rowAddr = ((tileAddr + (y as u16) * 2) & 0x1FFF) as usize;
+        let lo = self.vram[bank][rowAddr];
+        let hi = self.vram[bank][rowAddr + 1];
+        let b = 7 - x as usize;
+        ((bit::get(hi, b) as u8) << 1) | (bit::get(lo, b) as u8)
+    }
+
+    fn bgTileAddr(&self, tile: u8) -> u16 {
+        if bit::get(self.lcdc, 4) {
+            0x8000 + (tile as u16) * 16
+        } else {
+            (0x9000 + (tile as i8 as i32) * 16) as u16
+        }
+    }
+
+    // The first 256 BG tiles on screen in map order, which is how SGB VRAM transfers send data
+    pub fn screenTileData(&self) -> Vec<u8> {
+        let mapBase: u16 = if bit::get(self.lcdc, 3) {0x9C00} else {0x9800};
+        let mut data = Vec::with_capacity(4096);
+        for i in 0..256 {
+            let mapAddr = (mapBase + (i / 20) * 32 + i % 20) & 0x1FFF;
+            let tileAddr = (self.bgTileAddr(self.vram[0][mapAddr as usize]) & 0x1FFF) as usize;
+            data.extend_from_slice(&self.vram[0][tileAddr..tileAddr + 16]);
+        }
+        data
+    }
+
+    fn renderScanline(&mut self) {
+        let line = self.ly as usize;
+        // colour index and "BG has priority" flag for every pixel, needed for sprite mixing
+        let mut bgIndex = [0u8; SCREEN_WIDTH];
+        let mut bgPriority = [false; SCREEN_WIDTH];
+
+        let bgEnabled = self.cgbMode || bit::get(self.lcdc, 0);
+        let windowVisible = bit::get(self.lcdc, 5) && bgEnabled && self.ly >= self.wy && self.wx <= 166;
+        let mut windowUsed = false;
+
+        for x in 0..SCREEN_WIDTH {
+            if !bgEnabled {
+                self.putDmgPixel(line * SCREEN_WIDTH + x, self.bgp, 0, None);
+                continue;
+            }
+            let inWindow = windowVisible && (x as i16) >= (self.wx as i16) - 7;
+            let (mapBase, px, py) = if inWindow {
+                windowUsed = true;
+                let base: u16 = if bit::get(self.lcdc, 6) {0x9C00} else {0x9800};
+                (base, ((x as i16) - (self.wx as i16 - 7)) as u8, self.windowLine)
+            } else {
+                let base: u16 = if bit::get(self.lcdc, 3) {0x9C00} else {0x9800};
+                (base, (x as u8).wrapping_add(self.scx), self.ly.wrapping_add(self.scy))
+            };
+
+            let mapAddr = (mapBase + ((py as u16) / 8) * 32 + (px as u16) / 8) & 0x1FFF;
+            let tile = self.vram[0][mapAddr as usize];
+            // BG map attributes live at the same address in bank 1
+            let attr = if self.cgbMode {self.vram[1][mapAddr as usize]} else {0};
+            let bank = if bit::get(attr, 3) {1} else {0};
+            let mut tx = px % 8;
+            let mut ty = py % 8;
+            if bit::get(attr, 5) {tx = 7 - tx}
+            if bit::get(attr, 6) {ty = 7 - ty}
+
+            let index = self.tilePixel(bank, self.bgTileAddr(tile), tx, ty);
+            bgIndex[x] = index;
+            bgPriority[x] = bit::get(attr, 7);
+            if self.cgbMode {
+                self.frameBuffer[line * SCREEN_WIDTH + x] = self.bgColor(attr & 0x07, index);
+            } else {
+                self.putDmgPixel(line * SCREEN_WIDTH + x, self.bgp, index, None);
+            }
+        }
+        if windowUsed {
+            self.windowLine += 1;
+        }
+
+        if bit::get(self.lcdc, 1) {
+            self.renderSprites(line, &bgIndex, &bgPriority);
+        }
+    }
+
+    fn renderSprites(&mut self, line: usize, bgIndex: &[u8; SCREEN_WIDTH], bgPriority: &[bool; SCREEN_WIDTH]) {
+        let height: i16 = if bit::get(self.lcdc, 2) {16} else {8};
+
+        // hardware picks the first 10 sprites in OAM order that overlap the line
+        let mut sprites: Vec<usize> = Vec::with_capacity(10);
+        for i in 0..40 {
+            let y = self.oam[i * 4] as i16 - 16;
+            if (line as i16) >= y && (line as i16) < y + height {
+                sprites.push(i);
+                if sprites.len() == 10 {
+                    break;
+                }
+            }
+        }
+        // on DMG a lower X wins, ties go to the lower OAM index; on CGB only OAM index counts
+        if !self.cgbMode {
+            sprites.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+        }
+
+        let mut drawn = [false; SCREEN_WIDTH];
+        for &i in sprites.iter() {
+            let y = self.oam[i * 4] as i16 - 16;
+            let x = self.oam[i * 4 + 1] as i16 - 8;
+            let mut tile = self.oam[i * 4 + 2];
+            let attr = self.oam[i * 4 + 3];
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let mut row = (line as i16 - y) as u8;
+            if bit::get(attr, 6) {
+                row = (height as u8 - 1) - row;
+            }
+            let bank = if self.cgbMode && bit::get(attr, 3) {1} else {0};
+            let tileAddr = 0x8000 + (tile as u16) * 16;
+
+            for px in 0..8i16 {
+                let sx = x + px;
+                if sx < 0 || sx >= SCREEN_WIDTH as i16 || drawn[sx as usize] {
+                    continue;
+                }
+                let sx = sx as usize;
+                let col = if bit::get(attr, 5) {7 - px as u8} else {px as u8};
+                let index = self.tilePixel(bank, tileAddr, col, row);
+                if index == 0 {
+                    continue;
+                }
+                drawn[sx] = true;
+
+                // in CGB mode LCDC bit 0 clear means sprites are always on top
+                let masterPriority = !self.cgbMode || bit::get(self.lcdc, 0);
+                let bgWins = masterPriority && bgIndex[sx] != 0 && (bit::get(attr, 7) || bgPriority[sx]);
+                if bgWins {
+                    continue;
+                }
+                if self.cgbMode {
+                    self.frameBuffer[line * SCREEN_WIDTH + sx] = self.objColor(attr & 0x07, index);
+                } else {
+                    let obj = if bit::get(attr, 4) {1} else {0};
+                    let palette = if obj == 1 {self.obp1} else {self.obp0};
+                    self.putDmgPixel(line * SCREEN_WIDTH + sx, palette, index, Some(obj));
+                }
+            }
+        }
+    }
+}
+
+// Expands a 15 bit color to 8 bit per channel RGBA
+pub fn colorToRgba(color: u16) -> [u8; 4] {
+    let r = (color & 0x1F) as u8;
+    let g = ((color >> 5) & 0x1F) as u8;
+    let b = ((color >> 10) & 0x1F) as u8;
+    [(r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2), 0xFF]
+}
@@ -0,0 +1,126 @@
+use super::bit;
+
+// M-cycles the CPU is paused for every 16 byte block in single speed
+pub const BLOCK_STALL_CYCLES: u16 = 8;
+
+pub struct Hdma {
+    pub source: u16,
+    pub destination: u16,
+    // blocks of 16 bytes still to copy, minus one, as read back from FF55
+    pub remaining: u8,
+    pub hblankActive: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            remaining: 0x7F,
+            hblankActive: false,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr & 0x00FF {
+            0x55 => {
+                // bit 7 reads 0 while an HBlank transfer is running
+                if self.hblankActive {self.remaining} else {0x80 | self.remaining}
+            },
+            _ => {0xFF}
+        }
+    }
+
+    // Handles FF51-FF54, FF55 is started by the bus since it moves memory
+    pub fn writeAddress(&mut self, addr: u16, data: u8) {
+        match addr & 0x00FF {
+            0x51 => {self.source = (self.source & 0x00FF) | ((data as u16) << 8)},
+            0x52 => {self.source = (self.source & 0xFF00) | ((data & 0xF0) as u16)},
+            0x53 => {self.destination = (self.destination & 0x00FF) | (((data & 0x1F) as u16) << 8)},
+            0x54 => {self.destination = (self.destination & 0xFF00) | ((data & 0xF0) as u16)},
+            _ => {}
+        }
+    }
+
+    // Returns true when the write starts a general purpose transfer
+    pub fn writeControl(&mut self, data: u8) -> bool {
+        if self.hblankActive && !bit::get(data, 7) {
+            // cancelling keeps the remaining length readable with bit 7 set
+            self.hblankActive = false;
+            return false;
+        }
+        self.remaining = data & 0x7F;
+        if bit::get(data, 7) {
+            self.hblankActive = true;
+            false
+        } else {
+            true
+        }
+    }
+
+    // Addresses for the next block, advancing the internal counters
+    pub fn nextBlock(&mut self) -> (u16, u16) {
+        let src = self.source;
+        let dst = 0x8000 | (self.destination & 0x1FF0);
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = self.destination.wrapping_add(0x10);
+        let finished = self.remaining == 0 || self.destination & 0x1FFF == 0;
+        self.remaining = self.remaining.wrapping_sub(1) & 0x7F;
+        if finished {
+            self.hblankActive = false;
+            self.remaining = 0x7F;
+        }
+        (src, dst)
+    }
+
+    pub fn finished(&self) -> bool {
+        !self.hblankActive && self.remaining == 0x7F
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hdma;
+
+    #[test]
+    fn idleReadsFF() {
+        assert_eq!(Hdma::new().read(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn addressesDropTheLowNibble() {
+        let mut h = Hdma::new();
+        for (addr, data) in [(0xFF51, 0xC1), (0xFF52, 0x2F), (0xFF53, 0xE3), (0xFF54, 0x4F)] {
+            h.writeAddress(addr, data);
+        }
+        assert!(h.writeControl(0x00));
+        // the destination is always in VRAM, only 13 bits of it count
+        assert_eq!(h.nextBlock(), (0xC120, 0x8340));
+    }
+
+    #[test]
+    fn hblankTransferCountsDown() {
+        let mut h = Hdma::new();
+        assert!(!h.writeControl(0x83));
+        assert_eq!(h.read(0xFF55), 0x03);
+        h.nextBlock();
+        assert_eq!(h.read(0xFF55), 0x02);
+        h.nextBlock();
+        h.nextBlock();
+        assert_eq!(h.read(0xFF55), 0x00);
+        assert!(!h.finished());
+        h.nextBlock();
+        assert!(h.finished());
+        assert_eq!(h.read(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn cancelKeepsRemainingLength() {
+        let mut h = Hdma::new();
+        h.writeControl(0x85);
+        h.nextBlock();
+        assert!(!h.writeControl(0x00));
+        assert!(!h.hblankActive);
+        assert_eq!(h.read(0xFF55), 0x84);
+    }
+}
@@ -0,0 +1,144 @@
+use super::bit;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Button {
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
+}
+
+impl Button {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "right" => Some(Button::Right),
+            "left" => Some(Button::Left),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "select" => Some(Button::Select),
+            "start" => Some(Button::Start),
+            _ => None,
+        }
+    }
+}
+
+// controllers the SGB can multiplex through MLT_REQ
+pub const MAX_PLAYERS: usize = 4;
+
+pub struct Joypad {
+    // one bit per Button for every player, set while held
+    pub pressed: [u8; MAX_PLAYERS],
+    // P14/P15 as last written, 0 selects the row
+    select: u8,
+    // 1, 2 or 4 with SGB multiplayer and the controller currently read
+    players: u8,
+    current: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            pressed: [0; MAX_PLAYERS],
+            select: 0x30,
+            players: 1,
+            current: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        if self.select == 0x30 {
+            // with nothing selected the SGB returns the controller ID, 0xF for player 1
+            return 0xC0 | self.select | (0x0F - self.current);
+        }
+        let pressed = self.pressed[self.current as usize];
+        let mut lines = 0x0F;
+        if !bit::get(self.select, 4) {
+            lines &= !(pressed & 0x0F);
+        }
+        if !bit::get(self.select, 5) {
+            lines &= !(pressed >> 4);
+        }
+        0xC0 | self.select | lines
+    }
+
+    pub fn write(&mut self, data: u8) {
+        let select = data & 0x30;
+        // releasing both lines after reading a controller moves on to the next one
+        if self.players > 1 && select == 0x30 && self.select != 0x30 {
+            self.current = (self.current + 1) % self.players;
+        }
+        self.select = select;
+    }
+
+    pub fn setPlayers(&mut self, players: u8) {
+        if players != self.players {
+            self.players = players;
+            self.current = 0;
+        }
+    }
+
+    // Returns true when the change should request the joypad interrupt
+    pub fn setButton(&mut self, player: usize, b: Button, down: bool) -> bool {
+        let wasPressed = self.isPressed(player, b);
+        if down {
+            self.pressed[player] = bit::set(self.pressed[player], b as usize);
+        } else {
+            self.pressed[player] = bit::clr(self.pressed[player], b as usize);
+        }
+        down && !wasPressed
+    }
+
+    pub fn isPressed(&self, player: usize, b: Button) -> bool {
+        bit::get(self.pressed[player], b as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Joypad, Button};
+
+    #[test]
+    fn rowsSelectButtons() {
+        let mut j = Joypad::new();
+        assert!(j.setButton(0, Button::Left, true));
+        assert!(!j.setButton(0, Button::Left, true));
+        j.setButton(0, Button::Start, true);
+        j.write(0x20);
+        assert_eq!(j.read(), 0xED);
+        j.write(0x10);
+        assert_eq!(j.read(), 0xD7);
+        j.write(0x00);
+        assert_eq!(j.read(), 0xC5);
+    }
+
+    #[test]
+    fn releasingBothLinesCyclesTheId() {
+        let mut j = Joypad::new();
+        j.write(0x30);
+        assert_eq!(j.read(), 0xFF);
+        j.setPlayers(4);
+        j.setButton(1, Button::A, true);
+
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            ids.push(j.read() & 0x0F);
+            j.write(0x10);
+            let buttons = j.read() & 0x0F;
+            assert_eq!(buttons, if ids.len() == 2 {0x0E} else {0x0F});
+            j.write(0x30);
+        }
+        assert_eq!(ids, [0x0F, 0x0E, 0x0D, 0x0C, 0x0F]);
+
+        // only one controller never cycles
+        j.setPlayers(1);
+        j.write(0x10);
+        j.write(0x30);
+        assert_eq!(j.read(), 0xFF);
+    }
+}
@@ -6,6 +6,28 @@ mod bus;
 mod visualizer;
 mod gpu;
 mod timer;
+mod hdma;
+mod joypad;
+mod serial;
+mod colorization;
+mod sgb;
+mod breakpoints;
+mod callstack;
+mod symbols;
+mod expression;
+mod debugger;
+mod dap;
+mod disassembler;
+mod assembler;
+mod opcodes;
+mod cdl;
+mod trace;
+mod tracecompare;
+mod memory;
+mod flatram;
+mod cputests;
+mod testroms;
+mod golden;
 mod cartridge;
 extern crate sfml;
 use sfml::{
@@ -15,21 +37,219 @@ use sfml::{
     window::{ContextSettings, Event, Key, Style}
 };
 use std::{thread, time};
+use joypad::Button;
+use bus::Model;
 
+// one key set per player, players 2-4 are only read by SGB games that request multiplayer
+const KEY_MAPS: [[(Key, Button); 8]; 4] = [
+    [
+        (Key::D, Button::Right),
+        (Key::A, Button::Left),
+        (Key::W, Button::Up),
+        (Key::S, Button::Down),
+        (Key::K, Button::A),
+        (Key::J, Button::B),
+        (Key::BACKSPACE, Button::Select),
+        (Key::ENTER, Button::Start),
+    ],
+    [
+        (Key::H, Button::Right),
+        (Key::F, Button::Left),
+        (Key::T, Button::Up),
+        (Key::G, Button::Down),
+        (Key::N, Button::A),
+        (Key::B, Button::B),
+        (Key::NUM5, Button::Select),
+        (Key::NUM6, Button::Start),
+    ],
+    [
+        (Key::NUMPAD6, Button::Right),
+        (Key::NUMPAD4, Button::Left),
+        (Key::NUMPAD8, Button::Up),
+        (Key::NUMPAD2, Button::Down),
+        (Key::NUMPAD3, Button::A),
+        (Key::NUMPAD1, Button::B),
+        (Key::DIVIDE, Button::Select),
+        (Key::MULTIPLY, Button::Start),
+    ],
+    [
+        (Key::QUOTE, Button::Right),
+        (Key::L, Button::Left),
+        (Key::P, Button::Up),
+        (Key::SEMICOLON, Button::Down),
+        (Key::PERIOD, Button::A),
+        (Key::COMMA, Button::B),
+        (Key::NUM9, Button::Select),
+        (Key::NUM0, Button::Start),
+    ],
+];
 
-fn main() {
-    let cart = cartridge::Cartridge::new(String::from("roms/tetris.gb"));
+fn keyToButton(k: Key) -> Option<(usize, Button)> {
+    KEY_MAPS.iter().enumerate().find_map(|(player, map)| {
+        map.iter().find(|(key, _)| *key == k).map(|(_, b)| (player, *b))
+    })
+}
 
-    
-    let font = Font::from_file("fonts/RobotoMono-Medium.ttf").unwrap();
-    
+// Powers on a machine with the ROM inserted, in the state the boot ROM leaves behind
+fn boot(romPath: &str, model: Model, readKeys: bool, codeDataLog: bool) -> Result<cpu::Z80, String> {
+    let cart = cartridge::Cartridge::new(String::from(romPath))?;
     let mut c = cpu::Z80::new();
+    c.bus.model = model;
+    // buttons held while starting pick a manual colorization, like on the CGB boot logo
+    for (key, button) in KEY_MAPS[0].iter() {
+        if readKeys && key.is_pressed() {
+            c.bus.setButton(0, *button, true);
+        }
+    }
 
     c.bus.insertCartridge(cart);
     c.reset();
 
     c.pc = 0x0100;
+    c.symbols = symbols::Symbols::loadNextTo(romPath);
+    if codeDataLog {
+        c.bus.startCodeDataLog(romPath);
+    }
+    Ok(c)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let romPath = args.iter().find(|a| !a.starts_with("--")).cloned().unwrap_or(String::from("roms/tetris.gb"));
+    // --trace defaults to the DMG, gameboy-doctor logs start with A:01 and the CGB boots with A:11
+    let tracing = args.iter().any(|a| a.starts_with("--trace="));
+    let model = if args.iter().any(|a| a == "--dmg") {
+        Model::Dmg
+    } else if args.iter().any(|a| a == "--sgb") {
+        Model::Sgb
+    } else if args.iter().any(|a| a == "--cgb") || !tracing {
+        Model::Cgb
+    } else {
+        Model::Dmg
+    };
+    let debug = args.iter().any(|a| a == "--debug");
+    let dapPort = args.iter().find_map(|a| a.strip_prefix("--dap-port=")).map(|p| p.parse::<u16>().expect("Invalid DAP port"));
+    let dapStdio = args.iter().any(|a| a == "--dap");
+    let headless = debug || dapStdio || dapPort.is_some() || args.iter().any(|a| a.starts_with("--golden="));
+    let codeDataLog = args.iter().any(|a| a == "--cdl");
+
+    // --compare=ours.log,reference.log [--context=N] [--sync=0x0100|none] [--ignore=F,PCMEM]
+    if let Some(files) = args.iter().find_map(|a| a.strip_prefix("--compare=")) {
+        let (ours, reference) = files.split_once(',').expect("Expected --compare=ours.log,reference.log");
+        let mut options = tracecompare::Options::new();
+        if let Some(n) = args.iter().find_map(|a| a.strip_prefix("--context=")) {
+            options.context = n.parse().expect("Invalid context");
+        }
+        if let Some(pc) = args.iter().find_map(|a| a.strip_prefix("--sync=")) {
+            options.sync = if pc == "none" {None} else {Some(expression::parseNumber(pc).expect("Invalid sync PC") as u16)};
+        }
+        if let Some(fields) = args.iter().find_map(|a| a.strip_prefix("--ignore=")) {
+            options.ignore = fields.split(',').map(String::from).collect();
+        }
+        let same = tracecompare::compare(ours, reference, &options).expect("Failed to read traces");
+        std::process::exit(if same {0} else {1});
+    }
+
+    // opcode descriptors against the timing rules and a disassemble/assemble round trip
+    if args.iter().any(|a| a == "--check-opcodes") {
+        let problems = opcodes::check();
+        for p in problems.iter() {
+            println!("{}", p);
+        }
+        println!("{} problems in the opcode tables", problems.len());
+        std::process::exit(if problems.is_empty() {0} else {1});
+    }
+
+    // SingleStepTests vectors run on flat RAM, --no-cycles only compares the final state
+    if let Some(path) = args.iter().find_map(|a| a.strip_prefix("--cpu-tests=")) {
+        let passed = cputests::run(path, !args.iter().any(|a| a == "--no-cycles"));
+        std::process::exit(if passed {0} else {1});
+    }
 
+    // --test-roms=dir [--timeout=seconds] [--junit=report.xml] [--markdown=report.md]
+    if let Some(path) = args.iter().find_map(|a| a.strip_prefix("--test-roms=")) {
+        let timeout = args.iter().find_map(|a| a.strip_prefix("--timeout=")).map_or(30, |t| t.parse().expect("Invalid timeout"));
+        let mut reports = Vec::new();
+        if let Some(f) = args.iter().find_map(|a| a.strip_prefix("--junit=")) {
+            reports.push(testroms::Report::JUnit(String::from(f)));
+        }
+        if let Some(f) = args.iter().find_map(|a| a.strip_prefix("--markdown=")) {
+            reports.push(testroms::Report::Markdown(String::from(f)));
+        }
+        let passed = testroms::runAll(path, &|rom: &str| boot(rom, model, false, false), timeout, &reports);
+        std::process::exit(if passed {0} else {1});
+    }
+
+    // --assemble=test.asm writes test.gb, a ROM-only image running the program
+    if let Some(path) = args.iter().find_map(|a| a.strip_prefix("--assemble=")) {
+        let source = std::fs::read_to_string(path).expect("Failed to read source");
+        let rom = assembler::assemble(&source, assembler::ROM_ENTRY).and_then(|p| assembler::romImage(&p));
+        match rom {
+            Ok(rom) => {
+                let out = std::path::Path::new(path).with_extension("gb");
+                std::fs::write(&out, rom).expect("Failed to write ROM");
+                println!("assembled {}", out.display());
+            },
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    // static disassembly only reads the ROM file, nothing is emulated
+    if let Some(dir) = args.iter().find_map(|a| a.strip_prefix("--disassemble=")) {
+        let (code, size) = disassembler::exportProject(&romPath, dir).expect("Failed to write disassembly");
+        println!("{} of {} ROM bytes disassembled as code into {}", code, size, dir);
+        return;
+    }
+
+    let mut c = boot(&romPath, model, !headless, codeDataLog).unwrap_or_else(|e| {
+        println!("Could not load {}: {}", romPath, e);
+        std::process::exit(1);
+    });
+    if let Some(path) = args.iter().find_map(|a| a.strip_prefix("--trace=")) {
+        let filter = args.iter().find_map(|a| a.strip_prefix("--trace-filter=")).map(|f| trace::Filter::parse(f).expect("Invalid trace filter"));
+        c.trace = Some(trace::Tracer::toFile(path, filter).expect("Failed to create trace file"));
+    }
+
+    // --golden=reference.png [--frames=N] [--input=script.txt] [--tolerance=N] [--bless]
+    if let Some(reference) = args.iter().find_map(|a| a.strip_prefix("--golden=")) {
+        let frames = args.iter().find_map(|a| a.strip_prefix("--frames=")).map_or(60, |n| n.parse().expect("Invalid frame count"));
+        let tolerance = args.iter().find_map(|a| a.strip_prefix("--tolerance=")).map_or(0, |n| n.parse().expect("Invalid tolerance"));
+        let events = match args.iter().find_map(|a| a.strip_prefix("--input=")) {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).expect("Failed to read input script");
+                golden::parseInputScript(&text).expect("Invalid input script")
+            },
+            None => Vec::new(),
+        };
+        golden::run(&mut c, frames, &events);
+        let passed = golden::check(&c, std::path::Path::new(reference), tolerance, args.iter().any(|a| a == "--bless"))
+            .unwrap_or_else(|e| {println!("{}", e); false});
+        std::process::exit(if passed {0} else {1});
+    }
+
+    // the debuggers never touch SFML so they run without a display
+    if debug {
+        debugger::run(&mut c);
+        return;
+    }
+    if dapStdio || dapPort.is_some() {
+        let boot = |path: &str| boot(path, model, false, codeDataLog);
+        let result = match dapPort {
+            Some(port) => dap::serveTcp(port, c, &boot),
+            None => dap::serveStdio(c, &boot),
+        };
+        if let Err(e) = result {
+            println!("debug adapter connection failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let font = Font::from_file("fonts/RobotoMono-Medium.ttf").unwrap();
     let mut window = RenderWindow::new((1280, 720),
             "GBA Emulator - Badjaba",
             Style::CLOSE,
@@ -47,9 +267,17 @@ fn main() {
             match event {
                 Event::Closed | Event::KeyPressed {code: Key::ESCAPE, ..} => return,
                 Event::KeyPressed {code: Key::SPACE, ..} => {
-                    c.clock();
+                    c.step();
                 },
                 //Event::KeyPressed {code: Key::R, ..} => {c.executeOpcode(0xc1);},
+                Event::KeyPressed {code, ..} if keyToButton(code).is_some() => {
+                    let (player, button) = keyToButton(code).unwrap();
+                    c.bus.setButton(player, button, true);
+                },
+                Event::KeyReleased {code, ..} if keyToButton(code).is_some() => {
+                    let (player, button) = keyToButton(code).unwrap();
+                    c.bus.setButton(player, button, false);
+                },
                 Event::KeyPressed {code: Key::DOWN, ..} => {
                     if ramPage2 + 18 * 32 < 0xFFFF {ramPage2 += 32} else {}; 
                 },
@@ -69,6 +297,7 @@ fn main() {
         window.clear(Color::BLUE);
         //c.clock();
         visualizer::renderFullDissassembly(&c, ramPage1, ramPage2, &font, &mut window);
+        visualizer::renderScreen(&c, 940.0, 20.0, &mut window);
         window.display();
     }
 }
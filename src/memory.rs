@@ -0,0 +1,64 @@
+use super::bit;
+use super::bus::IntrFlags;
+use super::breakpoints::Breakpoints;
+
+// What the CPU needs from the memory it runs on. Bus is the whole machine, FlatRam is
+// 64 KiB of plain RAM for CPU tests and fuzzing.
+pub trait Memory {
+    // CPU read, flag tells the code/data log whether it's an opcode, an operand or data
+    fn read(&self, addr: u16, flag: u8) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+    // read without side effects, for DMA and the debug views
+    fn peek(&self, addr: u16) -> u8;
+    // one T-cycle passed
+    fn tick(&mut self);
+
+    fn interruptEnable(&self) -> u8;
+    fn interruptRequest(&self) -> u8;
+    fn setInterruptRequest(&mut self, value: u8);
+
+    // true while the CPU has to sit out an M-cycle, e.g. for a CGB DMA transfer
+    fn stall(&mut self) -> bool {
+        false
+    }
+
+    // called by STOP, true when a prepared CGB speed switch happened
+    fn switchSpeed(&mut self) -> bool {
+        false
+    }
+
+    // called by STOP when it doesn't switch speed, the system clock stops
+    fn stop(&mut self) {}
+
+    // true while a button on a selected joypad row is held, this wakes the CPU from STOP
+    fn joypadActive(&self) -> bool {
+        false
+    }
+
+    // ROM bank mapped at addr, None outside of ROM
+    fn romBank(&self, _addr: u16) -> Option<u16> {
+        None
+    }
+
+    // bank mapped at addr the way RGBDS numbers them
+    fn memoryBank(&self, _addr: u16) -> u16 {
+        0
+    }
+
+    fn breakpoints(&self) -> Option<&Breakpoints> {
+        None
+    }
+
+    fn getInterruptRequest(&self, i: IntrFlags) -> bool {
+        bit::get(self.interruptRequest(), i as usize)
+    }
+
+    fn getInterruptEnable(&self, i: IntrFlags) -> bool {
+        bit::get(self.interruptEnable(), i as usize)
+    }
+
+    fn resetInterruptRequest(&mut self, i: IntrFlags) {
+        let value = bit::clr(self.interruptRequest(), i as usize);
+        self.setInterruptRequest(value);
+    }
+}
@@ -0,0 +1,826 @@
+use super::disassembler;
+use super::assembler;
+use super::cpu::Z80;
+use super::flatram::FlatRam;
+
+// Everything the emulator knows about an opcode: the CPU takes its length and timing from
+// here, the disassemblers and the assembler its mnemonic and operands.
+// Cycles are M-cycles of the whole instruction, the CB prefix included, and flags are
+// written Z N H C the way the Pan Docs do: - unchanged, 0 reset, 1 set, the letter when
+// it depends on the result.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OperandKind {
+    A, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP,
+    // conditions, CondC is the carry one
+    NZ, Z, NC, CondC,
+    // (BC) (DE) (HL) (HL+) (HL-) (FF00+C)
+    AtBC, AtDE, AtHL, AtHLI, AtHLD, AtC,
+    U8,
+    U16,
+    // ADD SP,i8
+    I8,
+    // JR target, relative to the next instruction
+    Rel8,
+    // JP and CALL target
+    Addr16,
+    // (u16)
+    AtU16,
+    // (FF00+u8)
+    AtHighU8,
+    // LD HL,SP+i8
+    SpPlusI8,
+    Bit(u8),
+    Vector(u8),
+}
+
+use OperandKind::*;
+
+impl OperandKind {
+    // bytes the operand takes after the opcode
+    pub fn size(&self) -> u8 {
+        match self {
+            U8 | I8 | Rel8 | AtHighU8 | SpPlusI8 => {1},
+            U16 | Addr16 | AtU16 => {2},
+            _ => {0},
+        }
+    }
+
+    pub fn isCondition(&self) -> bool {
+        matches!(self, NZ | Z | NC | CondC)
+    }
+
+    pub fn name(&self) -> String {
+        let text = match self {
+            A => "A", B => "B", C => "C", D => "D", E => "E", H => "H", L => "L",
+            AF => "AF", BC => "BC", DE => "DE", HL => "HL", SP => "SP",
+            NZ => "NZ", Z => "Z", NC => "NC", CondC => "C",
+            AtBC => "(BC)", AtDE => "(DE)", AtHL => "(HL)", AtHLI => "(HL+)", AtHLD => "(HL-)", AtC => "(FF00+C)",
+            U8 => "u8", U16 | Addr16 => "u16", I8 | Rel8 => "i8",
+            AtU16 => "(u16)", AtHighU8 => "(FF00+u8)", SpPlusI8 => "SP+i8",
+            Bit(n) => return n.to_string(),
+            Vector(v) => return format!("{:02X}h", v),
+        };
+        String::from(text)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlagEffect {
+    Unchanged,
+    Reset,
+    Set,
+    Changed,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Opcode {
+    // empty for the opcodes that lock up the CPU
+    pub mnemonic: &'static str,
+    pub operands: &'static [OperandKind],
+    pub length: u8,
+    // cycles when a conditional instruction branches, cyclesNotTaken when it doesn't
+    pub cycles: u8,
+    pub cyclesNotTaken: u8,
+    pub flags: &'static str,
+}
+
+const fn op(mnemonic: &'static str, operands: &'static [OperandKind], length: u8, cycles: u8, cyclesNotTaken: u8, flags: &'static str) -> Opcode {
+    Opcode {mnemonic, operands, length, cycles, cyclesNotTaken, flags}
+}
+
+const ILLEGAL: Opcode = op("", &[], 1, 0, 0, "----");
+
+impl Opcode {
+    pub fn isIllegal(&self) -> bool {
+        self.mnemonic.is_empty()
+    }
+
+    pub fn isConditional(&self) -> bool {
+        self.operands.iter().any(|o| o.isCondition())
+    }
+
+    // "LD A,(HL+)", "BIT 7,H"
+    pub fn name(&self) -> String {
+        if self.isIllegal() {
+            return String::from("ILLEGAL");
+        }
+        let operands: Vec<String> = self.operands.iter().map(|o| o.name()).collect();
+        if operands.is_empty() {
+            String::from(self.mnemonic)
+        } else {
+            format!("{} {}", self.mnemonic, operands.join(","))
+        }
+    }
+
+    // i is 0 for Z, 1 for N, 2 for H and 3 for C
+    pub fn flag(&self, i: usize) -> FlagEffect {
+        match self.flags.as_bytes()[i] {
+            b'-' => {FlagEffect::Unchanged},
+            b'0' => {FlagEffect::Reset},
+            b'1' => {FlagEffect::Set},
+            _ => {FlagEffect::Changed},
+        }
+    }
+}
+
+pub fn get(prefixed: bool, opcode: u8) -> &'static Opcode {
+    if prefixed {&PREFIXED[opcode as usize]} else {&UNPREFIXED[opcode as usize]}
+}
+
+// D3 DB DD E3 E4 EB EC ED F4 FC FD lock up the CPU
+pub const UNPREFIXED: [Opcode; 256] = [
+    op("NOP", &[], 1, 1, 1, "----"), // 00
+    op("LD", &[BC, U16], 3, 3, 3, "----"), // 01
+    op("LD", &[AtBC, A], 1, 2, 2, "----"), // 02
+    op("INC", &[BC], 1, 2, 2, "----"), // 03
+    op("INC", &[B], 1, 1, 1, "Z0H-"), // 04
+    op("DEC", &[B], 1, 1, 1, "Z1H-"), // 05
+    op("LD", &[B, U8], 2, 2, 2, "----"), // 06
+    op("RLCA", &[], 1, 1, 1, "000C"), // 07
+    op("LD", &[AtU16, SP], 3, 5, 5, "----"), // 08
+    op("ADD", &[HL, BC], 1, 2, 2, "-0HC"), // 09
+    op("LD", &[A, AtBC], 1, 2, 2, "----"), // 0A
+    op("DEC", &[BC], 1, 2, 2, "----"), // 0B
+    op("INC", &[C], 1, 1, 1, "Z0H-"), // 0C
+    op("DEC", &[C], 1, 1, 1, "Z1H-"), // 0D
+    op("LD", &[C, U8], 2, 2, 2, "----"), // 0E
+    op("RRCA", &[], 1, 1, 1, "000C"), // 0F
+    op("STOP", &[], 2, 1, 1, "----"), // 10
+    op("LD", &[DE, U16], 3, 3, 3, "----"), // 11
+    op("LD", &[AtDE, A], 1, 2, 2, "----"), // 12
+    op("INC", &[DE], 1, 2, 2, "----"), // 13
+    op("INC", &[D], 1, 1, 1, "Z0H-"), // 14
+    op("DEC", &[D], 1, 1, 1, "Z1H-"), // 15
+    op("LD", &[D, U8], 2, 2, 2, "----"), // 16
+    op("RLA", &[], 1, 1, 1, "000C"), // 17
+    op("JR", &[Rel8], 2, 3, 3, "----"), // 18
+    op("ADD", &[HL, DE], 1, 2, 2, "-0HC"), // 19
+    op("LD", &[A, AtDE], 1, 2, 2, "----"), // 1A
+    op("DEC", &[DE], 1, 2, 2, "----"), // 1B
+    op("INC", &[E], 1, 1, 1, "Z0H-"), // 1C
+    op("DEC", &[E], 1, 1, 1, "Z1H-"), // 1D
+    op("LD", &[E, U8], 2, 2, 2, "----"), // 1E
+    op("RRA", &[], 1, 1, 1, "000C"), // 1F
+    op("JR", &[NZ, Rel8], 2, 3, 2, "----"), // 20
+    op("LD", &[HL, U16], 3, 3, 3, "----"), // 21
+    op("LD", &[AtHLI, A], 1, 2, 2, "----"), // 22
+    op("INC", &[HL], 1, 2, 2, "----"), // 23
+    op("INC", &[H], 1, 1, 1, "Z0H-"), // 24
+    op("DEC", &[H], 1, 1, 1, "Z1H-"), // 25
+    op("LD", &[H, U8], 2, 2, 2, "----"), // 26
+    op("DAA", &[], 1, 1, 1, "Z-0C"), // 27
+    op("JR", &[Z, Rel8], 2, 3, 2, "----"), // 28
+    op("ADD", &[HL, HL], 1, 2, 2, "-0HC"), // 29
+    op("LD", &[A, AtHLI], 1, 2, 2, "----"), // 2A
+    op("DEC", &[HL], 1, 2, 2, "----"), // 2B
+    op("INC", &[L], 1, 1, 1, "Z0H-"), // 2C
+    op("DEC", &[L], 1, 1, 1, "Z1H-"), // 2D
+    op("LD", &[L, U8], 2, 2, 2, "----"), // 2E
+    op("CPL", &[], 1, 1, 1, "-11-"), // 2F
+    op("JR", &[NC, Rel8], 2, 3, 2, "----"), // 30
+    op("LD", &[SP, U16], 3, 3, 3, "----"), // 31
+    op("LD", &[AtHLD, A], 1, 2, 2, "----"), // 32
+    op("INC", &[SP], 1, 2, 2, "----"), // 33
+    op("INC", &[AtHL], 1, 3, 3, "Z0H-"), // 34
+    op("DEC", &[AtHL], 1, 3, 3, "Z1H-"), // 35
+    op("LD", &[AtHL, U8], 2, 3, 3, "----"), // 36
+    op("SCF", &[], 1, 1, 1, "-001"), // 37
+    op("JR", &[CondC, Rel8], 2, 3, 2, "----"), // 38
+    op("ADD", &[HL, SP], 1, 2, 2, "-0HC"), // 39
+    op("LD", &[A, AtHLD], 1, 2, 2, "----"), // 3A
+    op("DEC", &[SP], 1, 2, 2, "----"), // 3B
+    op("INC", &[A], 1, 1, 1, "Z0H-"), // 3C
+    op("DEC", &[A], 1, 1, 1, "Z1H-"), // 3D
+    op("LD", &[A, U8], 2, 2, 2, "----"), // 3E
+    op("CCF", &[], 1, 1, 1, "-00C"), // 3F
+    op("LD", &[B, B], 1, 1, 1, "----"), // 40
+    op("LD", &[B, C], 1, 1, 1, "----"), // 41
+    op("LD", &[B, D], 1, 1, 1, "----"), // 42
+    op("LD", &[B, E], 1, 1, 1, "----"), // 43
+    op("LD", &[B, H], 1, 1, 1, "----"), // 44
+    op("LD", &[B, L], 1, 1, 1, "----"), // 45
+    op("LD", &[B, AtHL], 1, 2, 2, "----"), // 46
+    op("LD", &[B, A], 1, 1, 1, "----"), // 47
+    op("LD", &[C, B], 1, 1, 1, "----"), // 48
+    op("LD", &[C, C], 1, 1, 1, "----"), // 49
+    op("LD", &[C, D], 1, 1, 1, "----"), // 4A
+    op("LD", &[C, E], 1, 1, 1, "----"), // 4B
+    op("LD", &[C, H], 1, 1, 1, "----"), // 4C
+    op("LD", &[C, L], 1, 1, 1, "----"), // 4D
+    op("LD", &[C, AtHL], 1, 2, 2, "----"), // 4E
+    op("LD", &[C, A], 1, 1, 1, "----"), // 4F
+    op("LD", &[D, B], 1, 1, 1, "----"), // 50
+    op("LD", &[D, C], 1, 1, 1, "----"), // 51
+    op("LD", &[D, D], 1, 1, 1, "----"), // 52
+    op("LD", &[D, E], 1, 1, 1, "----"), // 53
+    op("LD", &[D, H], 1, 1, 1, "----"), // 54
+    op("LD", &[D, L], 1, 1, 1, "----"), // 55
+    op("LD", &[D, AtHL], 1, 2, 2, "----"), // 56
+    op("LD", &[D, A], 1, 1, 1, "----"), // 57
+    op("LD", &[E, B], 1, 1, 1, "----"), // 58
+    op("LD", &[E, C], 1, 1, 1, "----"), // 59
+    op("LD", &[E, D], 1, 1, 1, "----"), // 5A
+    op("LD", &[E, E], 1, 1, 1, "----"), // 5B
+    op("LD", &[E, H], 1, 1, 1, "----"), // 5C
+    op("LD", &[E, L], 1, 1, 1, "----"), // 5D
+    op("LD", &[E, AtHL], 1, 2, 2, "----"), // 5E
+    op("LD", &[E, A], 1, 1, 1, "----"), // 5F
+    op("LD", &[H, B], 1, 1, 1, "----"), // 60
+    op("LD", &[H, C], 1, 1, 1, "----"), // 61
+    op("LD", &[H, D], 1, 1, 1, "----"), // 62
+    op("LD", &[H, E], 1, 1, 1, "----"), // 63
+    op("LD", &[H, H], 1, 1, 1, "----"), // 64
+    op("LD", &[H, L], 1, 1, 1, "----"), // 65
+    op("LD", &[H, AtHL], 1, 2, 2, "----"), // 66
+    op("LD", &[H, A], 1, 1, 1, "----"), // 67
+    op("LD", &[L, B], 1, 1, 1, "----"), // 68
+    op("LD", &[L, C], 1, 1, 1, "----"), // 69
+    op("LD", &[L, D], 1, 1, 1, "----"), // 6A
+    op("LD", &[L, E], 1, 1, 1, "----"), // 6B
+    op("LD", &[L, H], 1, 1, 1, "----"), // 6C
+    op("LD", &[L, L], 1, 1, 1, "----"), // 6D
+    op("LD", &[L, AtHL], 1, 2, 2, "----"), // 6E
+    op("LD", &[L, A], 1, 1, 1, "----"), // 6F
+    op("LD", &[AtHL, B], 1, 2, 2, "----"), // 70
+    op("LD", &[AtHL, C], 1, 2, 2, "----"), // 71
+    op("LD", &[AtHL, D], 1, 2, 2, "----"), // 72
+    op("LD", &[AtHL, E], 1, 2, 2, "----"), // 73
+    op("LD", &[AtHL, H], 1, 2, 2, "----"), // 74
+    op("LD", &[AtHL, L], 1, 2, 2, "----"), // 75
+    op("HALT", &[], 1, 1, 1, "----"), // 76
+    op("LD", &[AtHL, A], 1, 2, 2, "----"), // 77
+    op("LD", &[A, B], 1, 1, 1, "----"), // 78
+    op("LD", &[A, C], 1, 1, 1, "----"), // 79
+    op("LD", &[A, D], 1, 1, 1, "----"), // 7A
+    op("LD", &[A, E], 1, 1, 1, "----"), // 7B
+    op("LD", &[A, H], 1, 1, 1, "----"), // 7C
+    op("LD", &[A, L], 1, 1, 1, "----"), // 7D
+    op("LD", &[A, AtHL], 1, 2, 2, "----"), // 7E
+    op("LD", &[A, A], 1, 1, 1, "----"), // 7F
+    op("ADD", &[A, B], 1, 1, 1, "Z0HC"), // 80
+    op("ADD", &[A, C], 1, 1, 1, "Z0HC"), // 81
+    op("ADD", &[A, D], 1, 1, 1, "Z0HC"), // 82
+    op("ADD", &[A, E], 1, 1, 1, "Z0HC"), // 83
+    op("ADD", &[A, H], 1, 1, 1, "Z0HC"), // 84
+    op("ADD", &[A, L], 1, 1, 1, "Z0HC"), // 85
+    op("ADD", &[A, AtHL], 1, 2, 2, "Z0HC"), // 86
+    op("ADD", &[A, A], 1, 1, 1, "Z0HC"), // 87
+    op("ADC", &[A, B], 1, 1, 1, "Z0HC"), // 88
+    op("ADC", &[A, C], 1, 1, 1, "Z0HC"), // 89
+    op("ADC", &[A, D], 1, 1, 1, "Z0HC"), // 8A
+    op("ADC", &[A, E], 1, 1, 1, "Z0HC"), // 8B
+    op("ADC", &[A, H], 1, 1, 1, "Z0HC"), // 8C
+    op("ADC", &[A, L], 1, 1, 1, "Z0HC"), // 8D
+    op("ADC", &[A, AtHL], 1, 2, 2, "Z0HC"), // 8E
+    op("ADC", &[A, A], 1, 1, 1, "Z0HC"), // 8F
+    op("SUB", &[A, B], 1, 1, 1, "Z1HC"), // 90
+    op("SUB", &[A, C], 1, 1, 1, "Z1HC"), // 91
+    op("SUB", &[A, D], 1, 1, 1, "Z1HC"), // 92
+    op("SUB", &[A, E], 1, 1, 1, "Z1HC"), // 93
+    op("SUB", &[A, H], 1, 1, 1, "Z1HC"), // 94
+    op("SUB", &[A, L], 1, 1, 1, "Z1HC"), // 95
+    op("SUB", &[A, AtHL], 1, 2, 2, "Z1HC"), // 96
+    op("SUB", &[A, A], 1, 1, 1, "Z1HC"), // 97
+    op("SBC", &[A, B], 1, 1, 1, "Z1HC"), // 98
+    op("SBC", &[A, C], 1, 1, 1, "Z1HC"), // 99
+    op("SBC", &[A, D], 1, 1, 1, "Z1HC"), // 9A
+    op("SBC", &[A, E], 1, 1, 1, "Z1HC"), // 9B
+    op("SBC", &[A, H], 1, 1, 1, "Z1HC"), // 9C
+    op("SBC", &[A, L], 1, 1, 1, "Z1HC"), // 9D
+    op("SBC", &[A, AtHL], 1, 2, 2, "Z1HC"), // 9E
+    op("SBC", &[A, A], 1, 1, 1, "Z1HC"), // 9F
+    op("AND", &[A, B], 1, 1, 1, "Z010"), // A0
+    op("AND", &[A, C], 1, 1, 1, "Z010"), // A1
+    op("AND", &[A, D], 1, 1, 1, "Z010"), // A2
+    op("AND", &[A, E], 1, 1, 1, "Z010"), // A3
+    op("AND", &[A, H], 1, 1, 1, "Z010"), // A4
+    op("AND", &[A, L], 1, 1, 1, "Z010"), // A5
+    op("AND", &[A, AtHL], 1, 2, 2, "Z010"), // A6
+    op("AND", &[A, A], 1, 1, 1, "Z010"), // A7
+    op("XOR", &[A, B], 1, 1, 1, "Z000"), // A8
+    op("XOR", &[A, C], 1, 1, 1, "Z000"), // A9
+    op("XOR", &[A, D], 1, 1, 1, "Z000"), // AA
+    op("XOR", &[A, E], 1, 1, 1, "Z000"), // AB
+    op("XOR", &[A, H], 1, 1, 1, "Z000"), // AC
+    op("XOR", &[A, L], 1, 1, 1, "Z000"), // AD
+    op("XOR", &[A, AtHL], 1, 2, 2, "Z000"), // AE
+    op("XOR", &[A, A], 1, 1, 1, "Z000"), // AF
+    op("OR", &[A, B], 1, 1, 1, "Z000"), // B0
+    op("OR", &[A, C], 1, 1, 1, "Z000"), // B1
+    op("OR", &[A, D], 1, 1, 1, "Z000"), // B2
+    op("OR", &[A, E], 1, 1, 1, "Z000"), // B3
+    op("OR", &[A, H], 1, 1, 1, "Z000"), // B4
+    op("OR", &[A, L], 1, 1, 1, "Z000"), // B5
+    op("OR", &[A, AtHL], 1, 2, 2, "Z000"), // B6
+    op("OR", &[A, A], 1, 1, 1, "Z000"), // B7
+    op("CP", &[A, B], 1, 1, 1, "Z1HC"), // B8
+    op("CP", &[A, C], 1, 1, 1, "Z1HC"), // B9
+    op("CP", &[A, D], 1, 1, 1, "Z1HC"), // BA
+    op("CP", &[A, E], 1, 1, 1, "Z1HC"), // BB
+    op("CP", &[A, H], 1, 1, 1, "Z1HC"), // BC
+    op("CP", &[A, L], 1, 1, 1, "Z1HC"), // BD
+    op("CP", &[A, AtHL], 1, 2, 2, "Z1HC"), // BE
+    op("CP", &[A, A], 1, 1, 1, "Z1HC"), // BF
+    op("RET", &[NZ], 1, 5, 2, "----"), // C0
+    op("POP", &[BC], 1, 3, 3, "----"), // C1
+    op("JP", &[NZ, Addr16], 3, 4, 3, "----"), // C2
+    op("JP", &[Addr16], 3, 4, 4, "----"), // C3
+    op("CALL", &[NZ, Addr16], 3, 6, 3, "----"), // C4
+    op("PUSH", &[BC], 1, 4, 4, "----"), // C5
+    op("ADD", &[A, U8], 2, 2, 2, "Z0HC"), // C6
+    op("RST", &[Vector(0x00)], 1, 4, 4, "----"), // C7
+    op("RET", &[Z], 1, 5, 2, "----"), // C8
+    op("RET", &[], 1, 4, 4, "----"), // C9
+    op("JP", &[Z, Addr16], 3, 4, 3, "----"), // CA
+    op("PREFIX", &[], 1, 1, 1, "----"), // CB
+    op("CALL", &[Z, Addr16], 3, 6, 3, "----"), // CC
+    op("CALL", &[Addr16], 3, 6, 6, "----"), // CD
+    op("ADC", &[A, U8], 2, 2, 2, "Z0HC"), // CE
+    op("RST", &[Vector(0x08)], 1, 4, 4, "----"), // CF
+    op("RET", &[NC], 1, 5, 2, "----"), // D0
+    op("POP", &[DE], 1, 3, 3, "----"), // D1
+    op("JP", &[NC, Addr16], 3, 4, 3, "----"), // D2
+    ILLEGAL, // D3
+    op("CALL", &[NC, Addr16], 3, 6, 3, "----"), // D4
+    op("PUSH", &[DE], 1, 4, 4, "----"), // D5
+    op("SUB", &[A, U8], 2, 2, 2, "Z1HC"), // D6
+    op("RST", &[Vector(0x10)], 1, 4, 4, "----"), // D7
+    op("RET", &[CondC], 1, 5, 2, "----"), // D8
+    op("RETI", &[], 1, 4, 4, "----"), // D9
+    op("JP", &[CondC, Addr16], 3, 4, 3, "----"), // DA
+    ILLEGAL, // DB
+    op("CALL", &[CondC, Addr16], 3, 6, 3, "----"), // DC
+    ILLEGAL, // DD
+    op("SBC", &[A, U8], 2, 2, 2, "Z1HC"), // DE
+    op("RST", &[Vector(0x18)], 1, 4, 4, "----"), // DF
+    op("LDH", &[AtHighU8, A], 2, 3, 3, "----"), // E0
+    op("POP", &[HL], 1, 3, 3, "----"), // E1
+    op("LDH", &[AtC, A], 1, 2, 2, "----"), // E2
+    ILLEGAL, // E3
+    ILLEGAL, // E4
+    op("PUSH", &[HL], 1, 4, 4, "----"), // E5
+    op("AND", &[A, U8], 2, 2, 2, "Z010"), // E6
+    op("RST", &[Vector(0x20)], 1, 4, 4, "----"), // E7
+    op("ADD", &[SP, I8], 2, 4, 4, "00HC"), // E8
+    op("JP", &[HL], 1, 1, 1, "----"), // E9
+    op("LD", &[AtU16, A], 3, 4, 4, "----"), // EA
+    ILLEGAL, // EB
+    ILLEGAL, // EC
+    ILLEGAL, // ED
+    op("XOR", &[A, U8], 2, 2, 2, "Z000"), // EE
+    op("RST", &[Vector(0x28)], 1, 4, 4, "----"), // EF
+    op("LDH", &[A, AtHighU8], 2, 3, 3, "----"), // F0
+    op("POP", &[AF], 1, 3, 3, "ZNHC"), // F1
+    op("LDH", &[A, AtC], 1, 2, 2, "----"), // F2
+    op("DI", &[], 1, 1, 1, "----"), // F3
+    ILLEGAL, // F4
+    op("PUSH", &[AF], 1, 4, 4, "----"), // F5
+    op("OR", &[A, U8], 2, 2, 2, "Z000"), // F6
+    op("RST", &[Vector(0x30)], 1, 4, 4, "----"), // F7
+    op("LD", &[HL, SpPlusI8], 2, 3, 3, "00HC"), // F8
+    op("LD", &[SP, HL], 1, 2, 2, "----"), // F9
+    op("LD", &[A, AtU16], 3, 4, 4, "----"), // FA
+    op("EI", &[], 1, 1, 1, "----"), // FB
+    ILLEGAL, // FC
+    ILLEGAL, // FD
+    op("CP", &[A, U8], 2, 2, 2, "Z1HC"), // FE
+    op("RST", &[Vector(0x38)], 1, 4, 4, "----"), // FF
+];
+
+// second byte after CB
+pub const PREFIXED: [Opcode; 256] = [
+    op("RLC", &[B], 2, 2, 2, "Z00C"), // 00
+    op("RLC", &[C], 2, 2, 2, "Z00C"), // 01
+    op("RLC", &[D], 2, 2, 2, "Z00C"), // 02
+    op("RLC", &[E], 2, 2, 2, "Z00C"), // 03
+    op("RLC", &[H], 2, 2, 2, "Z00C"), // 04
+    op("RLC", &[L], 2, 2, 2, "Z00C"), // 05
+    op("RLC", &[AtHL], 2, 4, 4, "Z00C"), // 06
+    op("RLC", &[A], 2, 2, 2, "Z00C"), // 07
+    op("RRC", &[B], 2, 2, 2, "Z00C"), // 08
+    op("RRC", &[C], 2, 2, 2, "Z00C"), // 09
+    op("RRC", &[D], 2, 2, 2, "Z00C"), // 0A
+    op("RRC", &[E], 2, 2, 2, "Z00C"), // 0B
+    op("RRC", &[H], 2, 2, 2, "Z00C"), // 0C
+    op("RRC", &[L], 2, 2, 2, "Z00C"), // 0D
+    op("RRC", &[AtHL], 2, 4, 4, "Z00C"), // 0E
+    op("RRC", &[A], 2, 2, 2, "Z00C"), // 0F
+    op("RL", &[B], 2, 2, 2, "Z00C"), // 10
+    op("RL", &[C], 2, 2, 2, "Z00C"), // 11
+    op("RL", &[D], 2, 2, 2, "Z00C"), // 12
+    op("RL", &[E], 2, 2, 2, "Z00C"), // 13
+    op("RL", &[H], 2, 2, 2, "Z00C"), // 14
+    op("RL", &[L], 2, 2, 2, "Z00C"), // 15
+    op("RL", &[AtHL], 2, 4, 4, "Z00C"), // 16
+    op("RL", &[A], 2, 2, 2, "Z00C"), // 17
+    op("RR", &[B], 2, 2, 2, "Z00C"), // 18
+    op("RR", &[C], 2, 2, 2, "Z00C"), // 19
+    op("RR", &[D], 2, 2, 2, "Z00C"), // 1A
+    op("RR", &[E], 2, 2, 2, "Z00C"), // 1B
+    op("RR", &[H], 2, 2, 2, "Z00C"), // 1C
+    op("RR", &[L], 2, 2, 2, "Z00C"), // 1D
+    op("RR", &[AtHL], 2, 4, 4, "Z00C"), // 1E
+    op("RR", &[A], 2, 2, 2, "Z00C"), // 1F
+    op("SLA", &[B], 2, 2, 2, "Z00C"), // 20
+    op("SLA", &[C], 2, 2, 2, "Z00C"), // 21
+    op("SLA", &[D], 2, 2, 2, "Z00C"), // 22
+    op("SLA", &[E], 2, 2, 2, "Z00C"), // 23
+    op("SLA", &[H], 2, 2, 2, "Z00C"), // 24
+    op("SLA", &[L], 2, 2, 2, "Z00C"), // 25
+    op("SLA", &[AtHL], 2, 4, 4, "Z00C"), // 26
+    op("SLA", &[A], 2, 2, 2, "Z00C"), // 27
+    op("SRA", &[B], 2, 2, 2, "Z00C"), // 28
+    op("SRA", &[C], 2, 2, 2, "Z00C"), // 29
+    op("SRA", &[D], 2, 2, 2, "Z00C"), // 2A
+    op("SRA", &[E], 2, 2, 2, "Z00C"), // 2B
+    op("SRA", &[H], 2, 2, 2, "Z00C"), // 2C
+    op("SRA", &[L], 2, 2, 2, "Z00C"), // 2D
+    op("SRA", &[AtHL], 2, 4, 4, "Z00C"), // 2E
+    op("SRA", &[A], 2, 2, 2, "Z00C"), // 2F
+    op("SWAP", &[B], 2, 2, 2, "Z000"), // 30
+    op("SWAP", &[C], 2, 2, 2, "Z000"), // 31
+    op("SWAP", &[D], 2, 2, 2, "Z000"), // 32
+    op("SWAP", &[E], 2, 2, 2, "Z000"), // 33
+    op("SWAP", &[H], 2, 2, 2, "Z000"), // 34
+    op("SWAP", &[L], 2, 2, 2, "Z000"), // 35
+    op("SWAP", &[AtHL], 2, 4, 4, "Z000"), // 36
+    op("SWAP", &[A], 2, 2, 2, "Z000"), // 37
+    op("SRL", &[B], 2, 2, 2, "Z00C"), // 38
+    op("SRL", &[C], 2, 2, 2, "Z00C"), // 39
+    op("SRL", &[D], 2, 2, 2, "Z00C"), // 3A
+    op("SRL", &[E], 2, 2, 2, "Z00C"), // 3B
+    op("SRL", &[H], 2, 2, 2, "Z00C"), // 3C
+    op("SRL", &[L], 2, 2, 2, "Z00C"), // 3D
+    op("SRL", &[AtHL], 2, 4, 4, "Z00C"), // 3E
+    op("SRL", &[A], 2, 2, 2, "Z00C"), // 3F
+    op("BIT", &[Bit(0), B], 2, 2, 2, "Z01-"), // 40
+    op("BIT", &[Bit(0), C], 2, 2, 2, "Z01-"), // 41
+    op("BIT", &[Bit(0), D], 2, 2, 2, "Z01-"), // 42
+    op("BIT", &[Bit(0), E], 2, 2, 2, "Z01-"), // 43
+    op("BIT", &[Bit(0), H], 2, 2, 2, "Z01-"), // 44
+    op("BIT", &[Bit(0), L], 2, 2, 2, "Z01-"), // 45
+    op("BIT", &[Bit(0), AtHL], 2, 3, 3, "Z01-"), // 46
+    op("BIT", &[Bit(0), A], 2, 2, 2, "Z01-"), // 47
+    op("BIT", &[Bit(1), B], 2, 2, 2, "Z01-"), // 48
+    op("BIT", &[Bit(1), C], 2, 2, 2, "Z01-"), // 49
+    op("BIT", &[Bit(1), D], 2, 2, 2, "Z01-"), // 4A
+    op("BIT", &[Bit(1), E], 2, 2, 2, "Z01-"), // 4B
+    op("BIT", &[Bit(1), H], 2, 2, 2, "Z01-"), // 4C
+    op("BIT", &[Bit(1), L], 2, 2, 2, "Z01-"), // 4D
+    op("BIT", &[Bit(1), AtHL], 2, 3, 3, "Z01-"), // 4E
+    op("BIT", &[Bit(1), A], 2, 2, 2, "Z01-"), // 4F
+    op("BIT", &[Bit(2), B], 2, 2, 2, "Z01-"), // 50
+    op("BIT", &[Bit(2), C], 2, 2, 2, "Z01-"), // 51
+    op("BIT", &[Bit(2), D], 2, 2, 2, "Z01-"), // 52
+    op("BIT", &[Bit(2), E], 2, 2, 2, "Z01-"), // 53
+    op("BIT", &[Bit(2), H], 2, 2, 2, "Z01-"), // 54
+    op("BIT", &[Bit(2), L], 2, 2, 2, "Z01-"), // 55
+    op("BIT", &[Bit(2), AtHL], 2, 3, 3, "Z01-"), // 56
+    op("BIT", &[Bit(2), A], 2, 2, 2, "Z01-"), // 57
+    op("BIT", &[Bit(3), B], 2, 2, 2, "Z01-"), // 58
+    op("BIT", &[Bit(3), C], 2, 2, 2, "Z01-"), // 59
+    op("BIT", &[Bit(3), D], 2, 2, 2, "Z01-"), // 5A
+    op("BIT", &[Bit(3), E], 2, 2, 2, "Z01-"), // 5B
+    op("BIT", &[Bit(3), H], 2, 2, 2, "Z01-"), // 5C
+    op("BIT", &[Bit(3), L], 2, 2, 2, "Z01-"), // 5D
+    op("BIT", &[Bit(3), AtHL], 2, 3, 3, "Z01-"), // 5E
+    op("BIT", &[Bit(3), A], 2, 2, 2, "Z01-"), // 5F
+    op("BIT", &[Bit(4), B], 2, 2, 2, "Z01-"), // 60
+    op("BIT", &[Bit(4), C], 2, 2, 2, "Z01-"), // 61
+    op("BIT", &[Bit(4), D], 2, 2, 2, "Z01-"), // 62
+    op("BIT", &[Bit(4), E], 2, 2, 2, "Z01-"), // 63
+    op("BIT", &[Bit(4), H], 2, 2, 2, "Z01-"), // 64
+    op("BIT", &[Bit(4), L], 2, 2, 2, "Z01-"), // 65
+    op("BIT", &[Bit(4), AtHL], 2, 3, 3, "Z01-"), // 66
+    op("BIT", &[Bit(4), A], 2, 2, 2, "Z01-"), // 67
+    op("BIT", &[Bit(5), B], 2, 2, 2, "Z01-"), // 68
+    op("BIT", &[Bit(5), C], 2, 2, 2, "Z01-"), // 69
+    op("BIT", &[Bit(5), D], 2, 2, 2, "Z01-"), // 6A
+    op("BIT", &[Bit(5), E], 2, 2, 2, "Z01-"), // 6B
+    op("BIT", &[Bit(5), H], 2, 2, 2, "Z01-"), // 6C
+    op("BIT", &[Bit(5), L], 2, 2, 2, "Z01-"), // 6D
+    op("BIT", &[Bit(5), AtHL], 2, 3, 3, "Z01-"), // 6E
+    op("BIT", &[Bit(5), A], 2, 2, 2, "Z01-"), // 6F
+    op("BIT", &[Bit(6), B], 2, 2, 2, "Z01-"), // 70
+    op("BIT", &[Bit(6), C], 2, 2, 2, "Z01-"), // 71
+    op("BIT", &[Bit(6), D], 2, 2, 2, "Z01-"), // 72
+    op("BIT", &[Bit(6), E], 2, 2, 2, "Z01-"), // 73
+    op("BIT", &[Bit(6), H], 2, 2, 2, "Z01-"), // 74
+    op("BIT", &[Bit(6), L], 2, 2, 2, "Z01-"), // 75
+    op("BIT", &[Bit(6), AtHL], 2, 3, 3, "Z01-"), // 76
+    op("BIT", &[Bit(6), A], 2, 2, 2, "Z01-"), // 77
+    op("BIT", &[Bit(7), B], 2, 2, 2, "Z01-"), // 78
+    op("BIT", &[Bit(7), C], 2, 2, 2, "Z01-"), // 79
+    op("BIT", &[Bit(7), D], 2, 2, 2, "Z01-"), // 7A
+    op("BIT", &[Bit(7), E], 2, 2, 2, "Z01-"), // 7B
+    op("BIT", &[Bit(7), H], 2, 2, 2, "Z01-"), // 7C
+    op("BIT", &[Bit(7), L], 2, 2, 2, "Z01-"), // 7D
+    op("BIT", &[Bit(7), AtHL], 2, 3, 3, "Z01-"), // 7E
+    op("BIT", &[Bit(7), A], 2, 2, 2, "Z01-"), // 7F
+    op("RES", &[Bit(0), B], 2, 2, 2, "----"), // 80
+    op("RES", &[Bit(0), C], 2, 2, 2, "----"), // 81
+    op("RES", &[Bit(0), D], 2, 2, 2, "----"), // 82
+    op("RES", &[Bit(0), E], 2, 2, 2, "----"), // 83
+    op("RES", &[Bit(0), H], 2, 2, 2, "----"), // 84
+    op("RES", &[Bit(0), L], 2, 2, 2, "----"), // 85
+    op("RES", &[Bit(0), AtHL], 2, 4, 4, "----"), // 86
+    op("RES", &[Bit(0), A], 2, 2, 2, "----"), // 87
+    op("RES", &[Bit(1), B], 2, 2, 2, "----"), // 88
+    op("RES", &[Bit(1), C], 2, 2, 2, "----"), // 89
+    op("RES", &[Bit(1), D], 2, 2, 2, "----"), // 8A
+    op("RES", &[Bit(1), E], 2, 2, 2, "----"), // 8B
+    op("RES", &[Bit(1), H], 2, 2, 2, "----"), // 8C
+    op("RES", &[Bit(1), L], 2, 2, 2, "----"), // 8D
+    op("RES", &[Bit(1), AtHL], 2, 4, 4, "----"), // 8E
+    op("RES", &[Bit(1), A], 2, 2, 2, "----"), // 8F
+    op("RES", &[Bit(2), B], 2, 2, 2, "----"), // 90
+    op("RES", &[Bit(2), C], 2, 2, 2, "----"), // 91
+    op("RES", &[Bit(2), D], 2, 2, 2, "----"), // 92
+    op("RES", &[Bit(2), E], 2, 2, 2, "----"), // 93
+    op("RES", &[Bit(2), H], 2, 2, 2, "----"), // 94
+    op("RES", &[Bit(2), L], 2, 2, 2, "----"), // 95
+    op("RES", &[Bit(2), AtHL], 2, 4, 4, "----"), // 96
+    op("RES", &[Bit(2), A], 2, 2, 2, "----"), // 97
+    op("RES", &[Bit(3), B], 2, 2, 2, "----"), // 98
+    op("RES", &[Bit(3), C], 2, 2, 2, "----"), // 99
+    op("RES", &[Bit(3), D], 2, 2, 2, "----"), // 9A
+    op("RES", &[Bit(3), E], 2, 2, 2, "----"), // 9B
+    op("RES", &[Bit(3), H], 2, 2, 2, "----"), // 9C
+    op("RES", &[Bit(3), L], 2, 2, 2, "----"), // 9D
+    op("RES", &[Bit(3), AtHL], 2, 4, 4, "----"), // 9E
+    op("RES", &[Bit(3), A], 2, 2, 2, "----"), // 9F
+    op("RES", &[Bit(4), B], 2, 2, 2, "----"), // A0
+    op("RES", &[Bit(4), C], 2, 2, 2, "----"), // A1
+    op("RES", &[Bit(4), D], 2, 2, 2, "----"), // A2
+    op("RES", &[Bit(4), E], 2, 2, 2, "----"), // A3
+    op("RES", &[Bit(4), H], 2, 2, 2, "----"), // A4
+    op("RES", &[Bit(4), L], 2, 2, 2, "----"), // A5
+    op("RES", &[Bit(4), AtHL], 2, 4, 4, "----"), // A6
+    op("RES", &[Bit(4), A], 2, 2, 2, "----"), // A7
+    op("RES", &[Bit(5), B], 2, 2, 2, "----"), // A8
+    op("RES", &[Bit(5), C], 2, 2, 2, "----"), // A9
+    op("RES", &[Bit(5), D], 2, 2, 2, "----"), // AA
+    op("RES", &[Bit(5), E], 2, 2, 2, "----"), // AB
+    op("RES", &[Bit(5), H], 2, 2, 2, "----"), // AC
+    op("RES", &[Bit(5), L], 2, 2, 2, "----"), // AD
+    op("RES", &[Bit(5), AtHL], 2, 4, 4, "----"), // AE
+    op("RES", &[Bit(5), A], 2, 2, 2, "----"), // AF
+    op("RES", &[Bit(6), B], 2, 2, 2, "----"), // B0
+    op("RES", &[Bit(6), C], 2, 2, 2, "----"), // B1
+    op("RES", &[Bit(6), D], 2, 2, 2, "----"), // B2
+    op("RES", &[Bit(6), E], 2, 2, 2, "----"), // B3
+    op("RES", &[Bit(6), H], 2, 2, 2, "----"), // B4
+    op("RES", &[Bit(6), L], 2, 2, 2, "----"), // B5
+    op("RES", &[Bit(6), AtHL], 2, 4, 4, "----"), // B6
+    op("RES", &[Bit(6), A], 2, 2, 2, "----"), // B7
+    op("RES", &[Bit(7), B], 2, 2, 2, "----"), // B8
+    op("RES", &[Bit(7), C], 2, 2, 2, "----"), // B9
+    op("RES", &[Bit(7), D], 2, 2, 2, "----"), // BA
+    op("RES", &[Bit(7), E], 2, 2, 2, "----"), // BB
+    op("RES", &[Bit(7), H], 2, 2, 2, "----"), // BC
+    op("RES", &[Bit(7), L], 2, 2, 2, "----"), // BD
+    op("RES", &[Bit(7), AtHL], 2, 4, 4, "----"), // BE
+    op("RES", &[Bit(7), A], 2, 2, 2, "----"), // BF
+    op("SET", &[Bit(0), B], 2, 2, 2, "----"), // C0
+    op("SET", &[Bit(0), C], 2, 2, 2, "----"), // C1
+    op("SET", &[Bit(0), D], 2, 2, 2, "----"), // C2
+    op("SET", &[Bit(0), E], 2, 2, 2, "----"), // C3
+    op("SET", &[Bit(0), H], 2, 2, 2, "----"), // C4
+    op("SET", &[Bit(0), L], 2, 2, 2, "----"), // C5
+    op("SET", &[Bit(0), AtHL], 2, 4, 4, "----"), // C6
+    op("SET", &[Bit(0), A], 2, 2, 2, "----"), // C7
+    op("SET", &[Bit(1), B], 2, 2, 2, "----"), // C8
+    op("SET", &[Bit(1), C], 2, 2, 2, "----"), // C9
+    op("SET", &[Bit(1), D], 2, 2, 2, "----"), // CA
+    op("SET", &[Bit(1), E], 2, 2, 2, "----"), // CB
+    op("SET", &[Bit(1), H], 2, 2, 2, "----"), // CC
+    op("SET", &[Bit(1), L], 2, 2, 2, "----"), // CD
+    op("SET", &[Bit(1), AtHL], 2, 4, 4, "----"), // CE
+    op("SET", &[Bit(1), A], 2, 2, 2, "----"), // CF
+    op("SET", &[Bit(2), B], 2, 2, 2, "----"), // D0
+    op("SET", &[Bit(2), C], 2, 2, 2, "----"), // D1
+    op("SET", &[Bit(2), D], 2, 2, 2, "----"), // D2
+    op("SET", &[Bit(2), E], 2, 2, 2, "----"), // D3
+    op("SET", &[Bit(2), H], 2, 2, 2, "----"), // D4
+    op("SET", &[Bit(2), L], 2, 2, 2, "----"), // D5
+    op("SET", &[Bit(2), AtHL], 2, 4, 4, "----"), // D6
+    op("SET", &[Bit(2), A], 2, 2, 2, "----"), // D7
+    op("SET", &[Bit(3), B], 2, 2, 2, "----"), // D8
+    op("SET", &[Bit(3), C], 2, 2, 2, "----"), // D9
+    op("SET", &[Bit(3), D], 2, 2, 2, "----"), // DA
+    op("SET", &[Bit(3), E], 2, 2, 2, "----"), // DB
+    op("SET", &[Bit(3), H], 2, 2, 2, "----"), // DC
+    op("SET", &[Bit(3), L], 2, 2, 2, "----"), // DD
+    op("SET", &[Bit(3), AtHL], 2, 4, 4, "----"), // DE
+    op("SET", &[Bit(3), A], 2, 2, 2, "----"), // DF
+    op("SET", &[Bit(4), B], 2, 2, 2, "----"), // E0
+    op("SET", &[Bit(4), C], 2, 2, 2, "----"), // E1
+    op("SET", &[Bit(4), D], 2, 2, 2, "----"), // E2
+    op("SET", &[Bit(4), E], 2, 2, 2, "----"), // E3
+    op("SET", &[Bit(4), H], 2, 2, 2, "----"), // E4
+    op("SET", &[Bit(4), L], 2, 2, 2, "----"), // E5
+    op("SET", &[Bit(4), AtHL], 2, 4, 4, "----"), // E6
+    op("SET", &[Bit(4), A], 2, 2, 2, "----"), // E7
+    op("SET", &[Bit(5), B], 2, 2, 2, "----"), // E8
+    op("SET", &[Bit(5), C], 2, 2, 2, "----"), // E9
+    op("SET", &[Bit(5), D], 2, 2, 2, "----"), // EA
+    op("SET", &[Bit(5), E], 2, 2, 2, "----"), // EB
+    op("SET", &[Bit(5), H], 2, 2, 2, "----"), // EC
+    op("SET", &[Bit(5), L], 2, 2, 2, "----"), // ED
+    op("SET", &[Bit(5), AtHL], 2, 4, 4, "----"), // EE
+    op("SET", &[Bit(5), A], 2, 2, 2, "----"), // EF
+    op("SET", &[Bit(6), B], 2, 2, 2, "----"), // F0
+    op("SET", &[Bit(6), C], 2, 2, 2, "----"), // F1
+    op("SET", &[Bit(6), D], 2, 2, 2, "----"), // F2
+    op("SET", &[Bit(6), E], 2, 2, 2, "----"), // F3
+    op("SET", &[Bit(6), H], 2, 2, 2, "----"), // F4
+    op("SET", &[Bit(6), L], 2, 2, 2, "----"), // F5
+    op("SET", &[Bit(6), AtHL], 2, 4, 4, "----"), // F6
+    op("SET", &[Bit(6), A], 2, 2, 2, "----"), // F7
+    op("SET", &[Bit(7), B], 2, 2, 2, "----"), // F8
+    op("SET", &[Bit(7), C], 2, 2, 2, "----"), // F9
+    op("SET", &[Bit(7), D], 2, 2, 2, "----"), // FA
+    op("SET", &[Bit(7), E], 2, 2, 2, "----"), // FB
+    op("SET", &[Bit(7), H], 2, 2, 2, "----"), // FC
+    op("SET", &[Bit(7), L], 2, 2, 2, "----"), // FD
+    op("SET", &[Bit(7), AtHL], 2, 4, 4, "----"), // FE
+    op("SET", &[Bit(7), A], 2, 2, 2, "----"), // FF
+];
+const ALU_FLAGS: [&str; 8] = ["Z0HC", "Z0HC", "Z1HC", "Z1HC", "Z010", "Z000", "Z000", "Z1HC"];
+
+// Length, cycles, cycles not taken and flags as the opcode's bit fields give them, written
+// independently of the tables from the Pan Docs timing rules. None for the illegal opcodes.
+fn fromSpec(prefixed: bool, opcode: u8) -> Option<(u8, u8, u8, &'static str)> {
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+    let (p, q) = (y >> 1, y & 1);
+    if prefixed {
+        let cycles = if z != 6 {2} else if x == 1 {3} else {4};
+        let flags = match x {
+            0 if y == 6 => "Z000",
+            0 => "Z00C",
+            1 => "Z01-",
+            _ => "----",
+        };
+        return Some((2, cycles, cycles, flags));
+    }
+    let (length, cycles, notTaken, flags) = match (x, z) {
+        (0, 0) => match y {
+            0 => (1, 1, 1, "----"),
+            1 => (3, 5, 5, "----"),
+            2 => (2, 1, 1, "----"),
+            3 => (2, 3, 3, "----"),
+            _ => (2, 3, 2, "----"),
+        },
+        (0, 1) if q == 0 => (3, 3, 3, "----"),
+        (0, 1) => (1, 2, 2, "-0HC"),
+        (0, 2) | (0, 3) => (1, 2, 2, "----"),
+        (0, 4) | (0, 5) => {
+            let cycles = if y == 6 {3} else {1};
+            (1, cycles, cycles, if z == 4 {"Z0H-"} else {"Z1H-"})
+        },
+        (0, 6) => if y == 6 {(2, 3, 3, "----")} else {(2, 2, 2, "----")},
+        (0, _) => (1, 1, 1, ["000C", "000C", "000C", "000C", "Z-0C", "-11-", "-001", "-00C"][y as usize]),
+        (1, _) if y == 6 && z == 6 => (1, 1, 1, "----"),
+        (1, _) if y == 6 || z == 6 => (1, 2, 2, "----"),
+        (1, _) => (1, 1, 1, "----"),
+        (2, _) => (1, if z == 6 {2} else {1}, if z == 6 {2} else {1}, ALU_FLAGS[y as usize]),
+        (_, 0) => match y {
+            0..= 3 => (1, 5, 2, "----"),
+            5 => (2, 4, 4, "00HC"),
+            7 => (2, 3, 3, "00HC"),
+            _ => (2, 3, 3, "----"),
+        },
+        (_, 1) if q == 0 => (1, 3, 3, if p == 3 {"ZNHC"} else {"----"}),
+        (_, 1) => match p {
+            0 | 1 => (1, 4, 4, "----"),
+            2 => (1, 1, 1, "----"),
+            _ => (1, 2, 2, "----"),
+        },
+        (_, 2) => match y {
+            0..= 3 => (3, 4, 3, "----"),
+            4 | 6 => (1, 2, 2, "----"),
+            _ => (3, 4, 4, "----"),
+        },
+        (_, 3) => match y {
+            0 => (3, 4, 4, "----"),
+            1 | 6 | 7 => (1, 1, 1, "----"),
+            _ => return None,
+        },
+        (_, 4) if y < 4 => (3, 6, 3, "----"),
+        (_, 4) => return None,
+        (_, 5) if q == 0 => (1, 4, 4, "----"),
+        (_, 5) if p == 0 => (3, 6, 6, "----"),
+        (_, 5) => return None,
+        (_, 6) => (2, 2, 2, ALU_FLAGS[y as usize]),
+        _ => (1, 4, 4, "----"),
+    };
+    Some((length, cycles, notTaken, flags))
+}
+
+// Checks the tables against the timing rules, against themselves, against the M-cycles the
+// CPU takes and against a round trip through the disassembler and the assembler.
+// Returns one line per problem.
+pub fn check() -> Vec<String> {
+    let mut problems = Vec::new();
+    for prefixed in [false, true] {
+        for opcode in 0..=255u8 {
+            let o = get(prefixed, opcode);
+            let at = if prefixed {format!("CB {:02X}", opcode)} else {format!("{:02X}", opcode)};
+            let expected = fromSpec(prefixed, opcode);
+            let actual = (o.length, o.cycles, o.cyclesNotTaken, o.flags);
+            match expected {
+                None if o.isIllegal() => {continue},
+                None => {problems.push(format!("{} {}: should be illegal", at, o.name())); continue},
+                Some(_) if o.isIllegal() => {problems.push(format!("{}: missing", at)); continue},
+                Some(e) if e != actual => {
+                    problems.push(format!("{} {}: length {} cycles {}/{} flags {}, expected length {} cycles {}/{} flags {}",
+                        at, o.name(), actual.0, actual.1, actual.2, actual.3, e.0, e.1, e.2, e.3));
+                },
+                _ => {},
+            }
+
+            // STOP is followed by a byte that isn't an operand
+            let padding = if !prefixed && opcode == 0x10 {1} else {0};
+            let size = prefixed as u8 + 1 + padding + o.operands.iter().map(|k| k.size()).sum::<u8>();
+            if size != o.length {
+                problems.push(format!("{} {}: operands take {} bytes, length is {}", at, o.name(), size, o.length));
+            }
+            if o.isConditional() != (o.cycles != o.cyclesNotTaken) || o.cyclesNotTaken > o.cycles {
+                problems.push(format!("{} {}: cycles {}/{} don't fit the condition", at, o.name(), o.cycles, o.cyclesNotTaken));
+            }
+            if o.flags.len() != 4 || o.flags.chars().zip("ZNHC".chars()).any(|(f, n)| f != n && !"-01".contains(f)) {
+                problems.push(format!("{} {}: bad flags {}", at, o.name(), o.flags));
+            }
+
+            if !prefixed && opcode == 0xCB {
+                continue;
+            }
+            problems.extend(cpuTiming(prefixed, opcode));
+            problems.extend(roundTrip(prefixed, opcode));
+        }
+    }
+    problems
+}
+
+// Runs the opcode on flat RAM with all flags clear and then all set, so conditional
+// instructions are both taken and not taken
+fn cpuTiming(prefixed: bool, opcode: u8) -> Option<String> {
+    let o = get(prefixed, opcode);
+    for flags in [0x00, 0xF0] {
+        let mut ram = FlatRam::new();
+        let bytes = if prefixed {[0xCB, opcode, 0x00]} else {[opcode, 0x00, 0xC0]};
+        ram.ram[0x0100..0x0103].copy_from_slice(&bytes);
+        let mut c = Z80::withMemory(ram);
+        c.f = flags;
+        c.sp = 0xD000;
+        c.h = 0xC0;
+        c.jump(0x0100);
+        let cycles = c.step();
+        let taken = match o.operands.first() {
+            Some(NZ) => {flags & 0x80 == 0},
+            Some(Z) => {flags & 0x80 != 0},
+            Some(NC) => {flags & 0x10 == 0},
+            Some(CondC) => {flags & 0x10 != 0},
+            _ => {true},
+        };
+        let expected = if taken {o.cycles} else {o.cyclesNotTaken};
+        if cycles != expected as u32 {
+            return Some(format!("{:02X?} {}: the CPU took {} M-cycles, expected {}", &bytes[..o.length as usize], o.name(), cycles, expected));
+        }
+    }
+    None
+}
+
+// Disassembles the opcode with some operand bytes and assembles the text again
+fn roundTrip(prefixed: bool, opcode: u8) -> Option<String> {
+    let bytes = if prefixed {[0xCB, opcode, 0x00]} else {[opcode, 0x34, 0x12]};
+    let i = match disassembler::decode(bytes, assembler::ROM_ENTRY) {
+        Some(i) => i,
+        None => return Some(format!("{:02X?}: not disassembled", &bytes[..2])),
+    };
+    let mut text = i.text.clone();
+    if i.targetOperand {
+        if let disassembler::Flow::Jump(t) | disassembler::Flow::Branch(t) | disassembler::Flow::Call(t) = i.flow {
+            text += &format!("${:04X}", t);
+        }
+    }
+    let length = get(prefixed, opcode).length as usize;
+    match assembler::assemble(&text, assembler::ROM_ENTRY) {
+        Ok(p) if p.bytes == bytes[..length] && i.len == length => None,
+        Ok(p) => Some(format!("{:02X?}: \"{}\" assembles to {:02X?}", &bytes[..length], text, p.bytes)),
+        Err(e) => Some(format!("{:02X?}: \"{}\" {}", &bytes[..length], text, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    #[test]
+    fn tablesMatchCpuAndTools() {
+        let problems = check();
+        assert!(problems.is_empty(), "{}", problems.join("\n"));
+    }
+}
@@ -0,0 +1,60 @@
+use super::bit;
+
+// SB and SC. Nothing is ever plugged into the link port, so 0xFF is shifted in and the
+// bytes shifted out are kept in output, test ROMs print their results this way.
+pub struct Serial {
+    pub data: u8,
+    control: u8,
+    // T-cycles until the transfer in progress is done
+    cyclesLeft: u16,
+    pub output: Vec<u8>,
+}
+
+// 8 bits at 8192 Hz on the internal clock
+const TRANSFER_CYCLES: u16 = 8 * 512;
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            data: 0,
+            control: 0,
+            cyclesLeft: 0,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr & 0x00FF {
+            0x01 => {self.data},
+            _ => {self.control | 0x7E},
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0x00FF {
+            0x01 => {self.data = data},
+            _ => {
+                self.control = data;
+                // on the external clock the transfer waits for a partner that never comes
+                if bit::get(data, 7) && bit::get(data, 0) {
+                    self.cyclesLeft = TRANSFER_CYCLES;
+                    self.output.push(self.data);
+                }
+            },
+        }
+    }
+
+    // Returns true when a transfer finished, which requests the serial interrupt
+    pub fn clock(&mut self) -> bool {
+        if self.cyclesLeft == 0 {
+            return false;
+        }
+        self.cyclesLeft -= 1;
+        if self.cyclesLeft > 0 {
+            return false;
+        }
+        self.data = 0xFF;
+        self.control = bit::clr(self.control, 7);
+        true
+    }
+}
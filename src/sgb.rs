@@ -0,0 +1,578 @@
+use super::bit;
+use super::gpu::{Gpu, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+// top left corner of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+
+// what the SGB BIOS loads before the game sends its own palettes
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mask {
+    Cancel = 0,
+    Freeze = 1,
+    Black = 2,
+    Color0 = 3,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Transfer {
+    Palettes,
+    BorderTiles(usize),
+    BorderMap,
+    AttributeFiles,
+}
+
+pub struct Sgb {
+    // P14/P15 as last written to FF00, bits are sampled on the edge from both high
+    lastSelect: u8,
+    receiving: bool,
+    bitCount: usize,
+    packet: [u8; 16],
+    packets: Vec<u8>,
+
+    palettes: [[u16; 4]; 4],
+    systemPalettes: [[u16; 4]; 512],
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attributeFiles: [u8; 45 * 90],
+    pub mask: Mask,
+    // joypads enabled by MLT_REQ
+    pub players: u8,
+    pendingTransfer: Option<Transfer>,
+
+    borderTiles: [u8; 256 * 32],
+    borderMap: [u16; 32 * 32],
+    borderPalettes: [[u16; 16]; 4],
+
+    pub frame: [u16; SGB_WIDTH * SGB_HEIGHT],
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            lastSelect: 0x30,
+            receiving: false,
+            bitCount: 0,
+            packet: [0; 16],
+            packets: Vec::new(),
+
+            palettes: [DEFAULT_PALETTE; 4],
+            systemPalettes: [[0; 4]; 512],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attributeFiles: [0; 45 * 90],
+            mask: Mask::Cancel,
+            players: 1,
+            pendingTransfer: None,
+
+            borderTiles: [0; 256 * 32],
+            borderMap: [0; 32 * 32],
+            borderPalettes: [[0; 16]; 4],
+
+            frame: [DEFAULT_PALETTE[0]; SGB_WIDTH * SGB_HEIGHT],
+        }
+    }
+
+    // Every FF00 write goes through here, command packets are sent by pulsing P14 and P15
+    pub fn writeJoypad(&mut self, data: u8) {
+        let select = data & 0x30;
+        let previous = self.lastSelect;
+        self.lastSelect = select;
+
+        if select == 0x00 {
+            // reset pulse, a new packet starts
+            self.receiving = true;
+            self.bitCount = 0;
+            self.packet = [0; 16];
+            return;
+        }
+        if !self.receiving || previous != 0x30 || select == 0x30 {
+            return;
+        }
+
+        // P14 low sends a 0, P15 low sends a 1
+        let b = select == 0x10;
+        if self.bitCount == 128 {
+            // the stop bit has to be a 0
+            self.receiving = false;
+            if !b {
+                self.packetReceived();
+            }
+            return;
+        }
+        if b {
+            let i = self.bitCount / 8;
+            self.packet[i] = bit::set(self.packet[i], self.bitCount % 8);
+        }
+        self.bitCount += 1;
+    }
+
+    fn packetReceived(&mut self) {
+        self.packets.extend_from_slice(&self.packet);
+        let length = (self.packets[0] & 0x07).max(1) as usize;
+        if self.packets.len() >= length * 16 {
+            let packets = std::mem::take(&mut self.packets);
+            self.execute(&packets);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => {self.setPalettePair(0, 1, data)}, // PAL01
+            0x01 => {self.setPalettePair(2, 3, data)}, // PAL23
+            0x02 => {self.setPalettePair(0, 3, data)}, // PAL03
+            0x03 => {self.setPalettePair(1, 2, data)}, // PAL12
+            0x04 => {self.attributeBlocks(data)}, // ATTR_BLK
+            0x05 => {self.attributeLines(data)}, // ATTR_LIN
+            0x06 => {self.attributeDivide(data)}, // ATTR_DIV
+            0x07 => {self.attributeCharacters(data)}, // ATTR_CHR
+            0x0A => {self.paletteSet(data)}, // PAL_SET
+            0x0B => {self.pendingTransfer = Some(Transfer::Palettes)}, // PAL_TRN
+            0x11 => { // MLT_REQ
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+            },
+            0x13 => {self.pendingTransfer = Some(Transfer::BorderTiles((data[1] & 0x01) as usize))}, // CHR_TRN
+            0x14 => {self.pendingTransfer = Some(Transfer::BorderMap)}, // PCT_TRN
+            0x15 => {self.pendingTransfer = Some(Transfer::AttributeFiles)}, // ATTR_TRN
+            0x16 => { // ATTR_SET
+                self.applyAttributeFile((data[1] & 0x3F) as usize);
+                if bit::get(data[1], 6) {
+                    self.mask = Mask::Cancel;
+                }
+            },
+            0x17 => { // MASK_EN
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::Cancel,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            },
+            _ => {}
+        }
+    }
+
+    fn color(data: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([data[i], data[i + 1]]) & 0x7FFF
+    }
+
+    // Color 0 is shared by all four palettes, the last one written wins
+    fn setColor0(&mut self, c: u16) {
+        for p in self.palettes.iter_mut() {
+            p[0] = c;
+        }
+    }
+
+    fn setPalettePair(&mut self, a: usize, b: usize, data: &[u8]) {
+        self.setColor0(Self::color(data, 1));
+        for i in 0..3 {
+            self.palettes[a][i + 1] = Self::color(data, 3 + i * 2);
+            self.palettes[b][i + 1] = Self::color(data, 9 + i * 2);
+        }
+    }
+
+    fn paletteSet(&mut self, data: &[u8]) {
+        for p in 0..4 {
+            let n = (Self::color(data, 1 + p * 2) & 0x01FF) as usize;
+            self.palettes[p] = self.systemPalettes[n];
+        }
+        self.setColor0(self.palettes[0][0]);
+        if bit::get(data[9], 7) {
+            self.applyAttributeFile((data[9] & 0x3F) as usize);
+        }
+        if bit::get(data[9], 6) {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    fn setCell(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_X && y < CELLS_Y {
+            self.attributes[y * CELLS_X + x] = palette & 0x03;
+        }
+    }
+
+    fn attributeBlocks(&mut self, data: &[u8]) {
+        let sets = (data[1] & 0x1F) as usize;
+        for s in 0..sets {
+            let d = &data[2 + s * 6..];
+            if d.len() < 6 {
+                break;
+            }
+            let (control, palettes) = (d[0] & 0x07, d[1]);
+            let (x1, y1, x2, y2) = (d[2] as usize, d[3] as usize, d[4] as usize, d[5] as usize);
+            let inside = palettes & 0x03;
+            let outside = (palettes >> 4) & 0x03;
+            // with only inside or only outside selected the surrounding line follows it
+            let (changeLine, line) = match control {
+                0x01 => (true, inside),
+                0x04 => (true, outside),
+                _ => (bit::get(control, 1), (palettes >> 2) & 0x03),
+            };
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let strictlyInside = x > x1 && x < x2 && y > y1 && y < y2;
+                    if strictlyInside {
+                        if bit::get(control, 0) {self.setCell(x, y, inside)}
+                    } else if within {
+                        if changeLine {self.setCell(x, y, line)}
+                    } else if bit::get(control, 2) {
+                        self.setCell(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attributeLines(&mut self, data: &[u8]) {
+        let sets = data[1] as usize;
+        for s in 0..sets {
+            let d = match data.get(2 + s) {
+                Some(d) => *d,
+                None => break,
+            };
+            let line = (d & 0x1F) as usize;
+            let palette = (d >> 5) & 0x03;
+            if bit::get(d, 7) {
+                for x in 0..CELLS_X {self.setCell(x, line, palette)}
+            } else {
+                for y in 0..CELLS_Y {self.setCell(line, y, palette)}
+            }
+        }
+    }
+
+    fn attributeDivide(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let line = (data[1] >> 4) & 0x03;
+        let split = data[2] as usize;
+        let horizontal = bit::get(data[1], 6);
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal {y} else {x};
+                let palette = if position < split {before} else if position == split {line} else {after};
+                self.setCell(x, y, palette);
+            }
+        }
+    }
+
+    fn attributeCharacters(&mut self, data: &[u8]) {
+        let mut x = (data[1] as usize).min(CELLS_X - 1);
+        let mut y = (data[2] as usize).min(CELLS_Y - 1);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_X * CELLS_Y);
+        let topToBottom = data[5] & 0x01 == 1;
+        for i in 0..count {
+            let d = match data.get(6 + i / 4) {
+                Some(d) => *d,
+                None => break,
+            };
+            let palette = (d >> (6 - (i % 4) * 2)) & 0x03;
+            self.setCell(x, y, palette);
+            if topToBottom {
+                y += 1;
+                if y == CELLS_Y {y = 0; x = (x + 1) % CELLS_X}
+            } else {
+                x += 1;
+                if x == CELLS_X {x = 0; y = (y + 1) % CELLS_Y}
+            }
+        }
+    }
+
+    // Attribute files pack 4 cells per byte, most significant bits first
+    fn applyAttributeFile(&mut self, n: usize) {
+        if n >= 45 {
+            return;
+        }
+        for cell in 0..CELLS_X * CELLS_Y {
+            let d = self.attributeFiles[n * 90 + cell / 4];
+            self.attributes[cell] = (d >> (6 - (cell % 4) * 2)) & 0x03;
+        }
+    }
+
+    fn finishTransfer(&mut self, t: Transfer, data: &[u8]) {
+        match t {
+            Transfer::Palettes => {
+                for (n, p) in self.systemPalettes.iter_mut().enumerate() {
+                    for (i, color) in p.iter_mut().enumerate() {
+                        *color = Self::color(data, n * 8 + i * 2);
+                    }
+                }
+            },
+            Transfer::BorderTiles(half) => {
+                self.borderTiles[half * 4096..half * 4096 + 4096].copy_from_slice(&data[..4096]);
+            },
+            Transfer::BorderMap => {
+                for i in 0..32 * 32 {
+                    self.borderMap[i] = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+                }
+                for p in 0..4 {
+                    for i in 0..16 {
+                        self.borderPalettes[p][i] = Self::color(data, 0x800 + p * 32 + i * 2);
+                    }
+                }
+            },
+            Transfer::AttributeFiles => {
+                self.attributeFiles.copy_from_slice(&data[..45 * 90]);
+            },
+        }
+    }
+
+    // Called at the start of VBlank, VRAM transfers read the frame that was just displayed
+    pub fn frameDone(&mut self, gpu: &Gpu) {
+        if let Some(t) = self.pendingTransfer.take() {
+            let data = gpu.screenTileData();
+            self.finishTransfer(t, &data);
+        }
+        self.drawBorder();
+        self.drawScreen(gpu);
+    }
+
+    fn borderPixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.borderMap[(y / 8) * 32 + x / 8];
+        let tile = (entry & 0x00FF) as usize;
+        let palette = ((entry >> 10) & 0x03) as usize;
+        let tx = if bit::get((entry >> 8) as u8, 6) {7 - x % 8} else {x % 8};
+        let ty = if bit::get((entry >> 8) as u8, 7) {7 - y % 8} else {y % 8};
+        // SNES 4bpp tiles, planes 0/1 interleaved in the first half and 2/3 in the second
+        let t = &self.borderTiles[tile * 32..tile * 32 + 32];
+        let b = 7 - tx;
+        let index = (bit::get(t[ty * 2], b) as usize)
+            | (bit::get(t[ty * 2 + 1], b) as usize) << 1
+            | (bit::get(t[16 + ty * 2], b) as usize) << 2
+            | (bit::get(t[16 + ty * 2 + 1], b) as usize) << 3;
+        if index == 0 {None} else {Some(self.borderPalettes[palette][index])}
+    }
+
+    fn drawBorder(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                // the game screen is drawn separately so a frozen screen survives
+                let onScreen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x) && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                if onScreen {
+                    continue;
+                }
+                self.frame[y * SGB_WIDTH + x] = self.borderPixel(x, y).unwrap_or(backdrop);
+            }
+        }
+    }
+
+    fn drawScreen(&mut self, gpu: &Gpu) {
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let out = (y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X;
+                let color = match self.mask {
+                    Mask::Freeze => continue,
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => self.palettes[0][0],
+                    Mask::Cancel => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        self.palettes[palette][gpu.shadeBuffer[y * SCREEN_WIDTH + x] as usize]
+                    },
+                };
+                self.frame[out] = color;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sgb, Mask, Transfer, CELLS_X};
+
+    // Pulses the packets through FF00 one line change at a time
+    fn send(s: &mut Sgb, bytes: &[u8]) {
+        for packet in bytes.chunks(16) {
+            let mut p = [0; 16];
+            p[..packet.len()].copy_from_slice(packet);
+            s.writeJoypad(0x00);
+            s.writeJoypad(0x30);
+            for i in 0..128 {
+                let one = (p[i / 8] >> (i % 8)) & 0x01 == 1;
+                s.writeJoypad(if one {0x10} else {0x20});
+                s.writeJoypad(0x30);
+            }
+            s.writeJoypad(0x20);
+            s.writeJoypad(0x30);
+        }
+    }
+
+    fn cell(s: &Sgb, x: usize, y: usize) -> u8 {
+        s.attributes[y * CELLS_X + x]
+    }
+
+    #[test]
+    fn paletteNeedsResetAndStopBit() {
+        let mut s = Sgb::new();
+        // PAL01, color 0 then three colors each for palette 0 and 1
+        let pal01 = [0x01, 0x11, 0x11, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00, 0x05, 0x00, 0x06, 0x00];
+
+        // bits without a reset pulse are ignored
+        for _ in 0..129 {
+            s.writeJoypad(0x10);
+            s.writeJoypad(0x30);
+        }
+        assert_eq!(s.palettes[0][1], super::DEFAULT_PALETTE[1]);
+
+        send(&mut s, &pal01);
+        assert_eq!(s.palettes[0], [0x1111, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(s.palettes[1], [0x1111, 0x0004, 0x0005, 0x0006]);
+        // color 0 is shared
+        assert_eq!(s.palettes[3][0], 0x1111);
+    }
+
+    #[test]
+    fn stopBitMustBeZero() {
+        let mut s = Sgb::new();
+        s.writeJoypad(0x00);
+        s.writeJoypad(0x30);
+        // MASK_EN black, followed by a 1 where the stop bit goes
+        let p = [0xB9, 0x02];
+        for i in 0..128 {
+            let one = p.get(i / 8).is_some_and(|b| (b >> (i % 8)) & 0x01 == 1);
+            s.writeJoypad(if one {0x10} else {0x20});
+            s.writeJoypad(0x30);
+        }
+        s.writeJoypad(0x10);
+        s.writeJoypad(0x30);
+        assert_eq!(s.mask, Mask::Cancel);
+    }
+
+    #[test]
+    fn multiPacketWaitsForTheLastPacket() {
+        let mut s = Sgb::new();
+        // ATTR_BLK with three sets needs two packets, the third set only fills cell (0,0)
+        let mut data = vec![0x22, 0x03];
+        data.extend_from_slice(&[0x01, 0x01, 10, 0, 19, 17]);
+        data.extend_from_slice(&[0x01, 0x02, 0, 10, 9, 17]);
+        data.extend_from_slice(&[0x01, 0x03, 0, 0, 0, 0]);
+
+        send(&mut s, &data[..16]);
+        assert_eq!(cell(&s, 15, 5), 0);
+        send(&mut s, &data[16..]);
+        assert_eq!(cell(&s, 15, 5), 1);
+        assert_eq!(cell(&s, 5, 15), 2);
+        assert_eq!(cell(&s, 0, 0), 3);
+        assert_eq!(cell(&s, 5, 5), 0);
+    }
+
+    #[test]
+    fn attributeBlock() {
+        let mut s = Sgb::new();
+        // inside 1, line 2, outside 3 around the box (2,2)-(5,5)
+        send(&mut s, &[0x21, 0x01, 0x07, 0x39, 2, 2, 5, 5]);
+        assert_eq!(cell(&s, 3, 4), 1);
+        assert_eq!(cell(&s, 2, 3), 2);
+        assert_eq!(cell(&s, 5, 5), 2);
+        assert_eq!(cell(&s, 6, 3), 3);
+        assert_eq!(cell(&s, 19, 17), 3);
+
+        // only inside selected, the line takes the inside palette too
+        send(&mut s, &[0x21, 0x01, 0x01, 0x00, 10, 10, 12, 12]);
+        assert_eq!(cell(&s, 10, 10), 0);
+        assert_eq!(cell(&s, 11, 11), 0);
+        assert_eq!(cell(&s, 13, 13), 3);
+    }
+
+    #[test]
+    fn attributeLines() {
+        let mut s = Sgb::new();
+        // row 4 with palette 3, then column 1 with palette 2
+        send(&mut s, &[0x29, 0x02, 0xE4, 0x41]);
+        assert_eq!(cell(&s, 0, 4), 3);
+        assert_eq!(cell(&s, 1, 4), 2);
+        assert_eq!(cell(&s, 19, 4), 3);
+        assert_eq!(cell(&s, 1, 0), 2);
+        assert_eq!(cell(&s, 1, 17), 2);
+        assert_eq!(cell(&s, 2, 5), 0);
+    }
+
+    #[test]
+    fn attributeDivide() {
+        let mut s = Sgb::new();
+        // horizontal split at row 9, 1 above, 2 on the line, 3 below
+        send(&mut s, &[0x31, 0x67, 9]);
+        assert_eq!(cell(&s, 7, 8), 1);
+        assert_eq!(cell(&s, 7, 9), 2);
+        assert_eq!(cell(&s, 7, 10), 3);
+
+        // vertical split at column 0
+        send(&mut s, &[0x31, 0x2D, 0]);
+        assert_eq!(cell(&s, 0, 3), 2);
+        assert_eq!(cell(&s, 1, 3), 1);
+    }
+
+    #[test]
+    fn attributeCharactersWrap() {
+        let mut s = Sgb::new();
+        // four cells from (18,0) left to right, wrapping into the next row
+        send(&mut s, &[0x39, 18, 0, 4, 0, 0, 0x1B]);
+        assert_eq!(cell(&s, 18, 0), 0);
+        assert_eq!(cell(&s, 19, 0), 1);
+        assert_eq!(cell(&s, 0, 1), 2);
+        assert_eq!(cell(&s, 1, 1), 3);
+
+        // top to bottom, wrapping into the next column
+        send(&mut s, &[0x39, 5, 16, 3, 0, 1, 0xFC]);
+        assert_eq!(cell(&s, 5, 16), 3);
+        assert_eq!(cell(&s, 5, 17), 3);
+        assert_eq!(cell(&s, 6, 0), 3);
+    }
+
+    #[test]
+    fn paletteTransferAndSet() {
+        let mut s = Sgb::new();
+        send(&mut s, &[0xB9, 0x01]);
+        assert_eq!(s.mask, Mask::Freeze);
+
+        send(&mut s, &[0x59]);
+        assert_eq!(s.pendingTransfer, Some(Transfer::Palettes));
+        // system palette n holds n, n + 1, n + 2, n + 3
+        let mut vram = vec![0; 4096];
+        for n in 0..512 {
+            for i in 0..4 {
+                let c = ((n + i) as u16).to_le_bytes();
+                vram[n * 8 + i * 2] = c[0];
+                vram[n * 8 + i * 2 + 1] = c[1];
+            }
+        }
+        let t = s.pendingTransfer.take().unwrap();
+        s.finishTransfer(t, &vram);
+
+        // palettes 10, 20, 30 and 300, cancelling the mask
+        send(&mut s, &[0x51, 10, 0, 20, 0, 30, 0, 0x2C, 0x01, 0x40]);
+        assert_eq!(s.palettes[1], [10, 21, 22, 23]);
+        assert_eq!(s.palettes[3], [10, 301, 302, 303]);
+        assert_eq!(s.mask, Mask::Cancel);
+    }
+
+    #[test]
+    fn maskEnable() {
+        let mut s = Sgb::new();
+        for (data, mask) in [(0x02, Mask::Black), (0x03, Mask::Color0), (0x00, Mask::Cancel)] {
+            send(&mut s, &[0xB9, data]);
+            assert_eq!(s.mask, mask);
+        }
+    }
+
+    #[test]
+    fn multiplayerRequest() {
+        let mut s = Sgb::new();
+        send(&mut s, &[0x89, 0x01]);
+        assert_eq!(s.players, 2);
+        send(&mut s, &[0x89, 0x03]);
+        assert_eq!(s.players, 4);
+        send(&mut s, &[0x89, 0x00]);
+        assert_eq!(s.players, 1);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+// Labels from the RGBDS .sym or .map file next to the ROM. Addresses are qualified by
+// bank, bank 0 is used for memory that can't be switched.
+
+pub struct Symbols {
+    byName: HashMap<String, (u16, u16)>,
+    byAddr: BTreeMap<(u16, u16), String>,
+}
+
+// Memory areas labels can't reach past, a label in WRAM0 doesn't describe WRAMX
+fn region(addr: u16) -> u8 {
+    match addr {
+        0x0000..= 0x3FFF => {0},
+        0x4000..= 0x7FFF => {1},
+        0x8000..= 0x9FFF => {2},
+        0xA000..= 0xBFFF => {3},
+        0xC000..= 0xCFFF => {4},
+        0xD000..= 0xDFFF => {5},
+        0xE000..= 0xFDFF => {6},
+        0xFE00..= 0xFE9F => {7},
+        0xFEA0..= 0xFF7F => {8},
+        0xFF80..= 0xFFFE => {9},
+        0xFFFF => {10},
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self {
+            byName: HashMap::new(),
+            byAddr: BTreeMap::new(),
+        }
+    }
+
+    // game.gb loads game.sym, or game.map when there is no .sym
+    pub fn loadNextTo(romPath: &str) -> Self {
+        let mut s = Self::new();
+        let path = Path::new(romPath);
+        if let Ok(text) = fs::read_to_string(path.with_extension("sym")) {
+            s.loadSym(&text);
+        } else if let Ok(text) = fs::read_to_string(path.with_extension("map")) {
+            s.loadMap(&text);
+        }
+        s
+    }
+
+    pub fn add(&mut self, bank: u16, addr: u16, name: &str) {
+        self.byName.insert(String::from(name), (bank, addr));
+        // the first label at an address is kept, later ones are usually locals or aliases
+        self.byAddr.entry((bank, addr)).or_insert_with(|| String::from(name));
+    }
+
+    // "bank:addr label" lines, everything after ; is a comment
+    pub fn loadSym(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let (location, name) = match (words.next(), words.next()) {
+                (Some(l), Some(n)) => (l, n),
+                _ => continue,
+            };
+            let mut parts = location.split(':');
+            let (bank, addr) = match (parts.next(), parts.next()) {
+                (Some(b), Some(a)) => (u16::from_str_radix(b, 16), u16::from_str_radix(a, 16)),
+                _ => continue,
+            };
+            if let (Ok(bank), Ok(addr)) = (bank, addr) {
+                self.add(bank, addr, name);
+            }
+        }
+    }
+
+    // rgblink map files, "ROMX bank #2:" headers followed by "$4000 = Label" lines
+    pub fn loadMap(&mut self, text: &str) {
+        let mut bank = 0;
+        for line in text.lines() {
+            let line = line.trim();
+            if let Some(i) = line.find(" bank #") {
+                let number = line[i + 7..].trim_end_matches(':');
+                bank = number.parse().unwrap_or(0);
+                continue;
+            }
+            let mut parts = line.splitn(2, " = ");
+            let (addr, name) = match (parts.next(), parts.next()) {
+                (Some(a), Some(n)) if a.starts_with('$') => (a, n.trim()),
+                _ => continue,
+            };
+            if let Ok(addr) = u16::from_str_radix(&addr[1..], 16) {
+                self.add(bank, addr, name);
+            }
+        }
+    }
+
+    pub fn isEmpty(&self) -> bool {
+        self.byName.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.byName.get(name).copied()
+    }
+
+    pub fn label(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.byAddr.get(&(bank, addr)).map(|s| s.as_str())
+    }
+
+    // Closest label at or before addr, as Label or Label+offset
+    pub fn describe(&self, bank: u16, addr: u16) -> Option<String> {
+        let ((_, at), name) = self.byAddr.range((bank, 0)..= (bank, addr)).next_back()?;
+        if region(*at) != region(addr) {
+            return None;
+        }
+        if *at == addr {
+            Some(name.clone())
+        } else {
+            Some(format!("{}+{}", name, addr - at))
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use super::cpu::Z80;
+
+// Runs blargg and mooneye test ROMs without a display and decides pass or fail:
+// - mooneye ROMs execute LD B,B when done, with 3/5/8/13/21/34 in B-L on success
+// - blargg ROMs print their result over serial, newer ones also keep it at A004 in
+//   cartridge RAM behind the DE B0 61 signature, A000 holds 80 while running, then the result
+// - a ROM that locks the CPU up on an illegal opcode fails right away
+
+// M-cycles per emulated second in normal speed
+const CYCLES_PER_SECOND: u64 = 1024 * 1024;
+// cartridge RAM and serial output are checked about once a frame
+const CHECK_INTERVAL: u64 = 70224 / 4;
+
+const LD_B_B: u8 = 0x40;
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    Timeout,
+    Error(String),
+}
+
+pub struct RomResult {
+    pub name: String,
+    pub outcome: Outcome,
+    // wall clock time
+    pub seconds: f64,
+    pub output: String,
+}
+
+pub enum Report {
+    JUnit(String),
+    Markdown(String),
+}
+
+fn registers(c: &Z80) -> [u8; 6] {
+    [c.b, c.c, c.d, c.e, c.h, c.l]
+}
+
+// Result of a mooneye ROM stopped on LD B,B
+fn mooneyeOutcome(c: &Z80) -> Outcome {
+    let r = registers(c);
+    if r == FIBONACCI {
+        return Outcome::Passed;
+    }
+    if r.iter().all(|v| *v == 0x42) {
+        return Outcome::Failed(String::from("failure signature 42 in B-L"));
+    }
+    Outcome::Failed(format!("B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}", r[0], r[1], r[2], r[3], r[4], r[5]))
+}
+
+// Text blargg ROMs leave at A004, None while running or without the signature
+fn blarggMemoryOutcome(ram: &[u8]) -> Option<(Outcome, String)> {
+    if ram.len() < 5 || ram[1..4] != BLARGG_SIGNATURE || ram[0] == BLARGG_RUNNING {
+        return None;
+    }
+    let text: Vec<u8> = ram[4..].iter().take_while(|b| **b != 0).cloned().collect();
+    let text = String::from_utf8_lossy(&text).into_owned();
+    let outcome = match ram[0] {
+        0 => Outcome::Passed,
+        code => Outcome::Failed(format!("result code {}", code)),
+    };
+    Some((outcome, text))
+}
+
+fn serialOutcome(output: &str) -> Option<Outcome> {
+    if output.contains("Passed") {
+        Some(Outcome::Passed)
+    } else {
+        output.find("Failed").map(|i| Outcome::Failed(String::from(output[i..].lines().next().unwrap_or("Failed").trim())))
+    }
+}
+
+fn run(c: &mut Z80, timeout: u64) -> (Outcome, String) {
+    let limit = timeout * CYCLES_PER_SECOND;
+    let (mut cycles, mut nextCheck) = (0, CHECK_INTERVAL);
+    while cycles < limit {
+        cycles += c.step() as u64;
+        if c.isLockedUp() {
+            let reason = c.stopReason.take().map(|r| r.to_string()).unwrap_or_default();
+            return (Outcome::Failed(reason), String::from_utf8_lossy(&c.bus.serial.output).into_owned());
+        }
+        if c.peekByte(c.pc) == LD_B_B {
+            return (mooneyeOutcome(c), String::from_utf8_lossy(&c.bus.serial.output).into_owned());
+        }
+        if cycles >= nextCheck {
+            nextCheck += CHECK_INTERVAL;
+            if let Some(result) = blarggMemoryOutcome(c.bus.cartridgeRam()) {
+                return result;
+            }
+            let output = String::from_utf8_lossy(&c.bus.serial.output).into_owned();
+            if let Some(outcome) = serialOutcome(&output) {
+                return (outcome, output);
+            }
+        }
+    }
+    (Outcome::Timeout, String::from_utf8_lossy(&c.bus.serial.output).into_owned())
+}
+
+pub fn runRom(path: &Path, name: &str, boot: &dyn Fn(&str) -> Result<Z80, String>, timeout: u64) -> RomResult {
+    let start = Instant::now();
+    let path = path.to_string_lossy().into_owned();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        match boot(&path) {
+            Ok(mut c) => run(&mut c, timeout),
+            Err(e) => (Outcome::Error(e), String::new()),
+        }
+    }));
+    let (outcome, output) = match result {
+        Ok(r) => r,
+        Err(e) => {
+            let message = e.downcast_ref::<String>().cloned()
+                .or(e.downcast_ref::<&str>().map(|s| String::from(*s)))
+                .unwrap_or_default();
+            (Outcome::Error(message), String::new())
+        },
+    };
+    RomResult {
+        name: String::from(name),
+        outcome,
+        seconds: start.elapsed().as_secs_f64(),
+        output,
+    }
+}
+
+fn findRoms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        if path.is_dir() {
+            findRoms(&path, roms);
+        } else if path.extension().is_some_and(|e| e == "gb" || e == "gbc") {
+            roms.push(path);
+        }
+    }
+}
+
+fn describe(outcome: &Outcome, timeout: u64) -> String {
+    match outcome {
+        Outcome::Passed => {String::from("passed")},
+        Outcome::Failed(m) => {format!("failed: {}", m)},
+        Outcome::Timeout => {format!("timed out after {} s", timeout)},
+        Outcome::Error(m) => {format!("error: {}", m)},
+    }
+}
+
+fn escapeXml(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control() || *c == '\n' || *c == '\t').map(|c| match c {
+        '&' => String::from("&amp;"),
+        '<' => String::from("&lt;"),
+        '>' => String::from("&gt;"),
+        '"' => String::from("&quot;"),
+        _ => c.to_string(),
+    }).collect()
+}
+
+pub fn junit(results: &[RomResult], timeout: u64) -> String {
+    let failures = results.iter().filter(|r| matches!(r.outcome, Outcome::Failed(_) | Outcome::Timeout)).count();
+    let errors = results.iter().filter(|r| matches!(r.outcome, Outcome::Error(_))).count();
+    let time: f64 = results.iter().map(|r| r.seconds).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!("<testsuite name=\"test-roms\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n", results.len(), failures, errors, time);
+    for r in results.iter() {
+        xml += &format!("  <testcase classname=\"test-roms\" name=\"{}\" time=\"{:.3}\">\n", escapeXml(&r.name), r.seconds);
+        let message = escapeXml(&describe(&r.outcome, timeout));
+        match r.outcome {
+            Outcome::Passed => {},
+            Outcome::Failed(_) | Outcome::Timeout => {xml += &format!("    <failure message=\"{}\"/>\n", message)},
+            Outcome::Error(_) => {xml += &format!("    <error message=\"{}\"/>\n", message)},
+        }
+        if !r.output.is_empty() {
+            xml += &format!("    <system-out>{}</system-out>\n", escapeXml(&r.output));
+        }
+        xml += "  </testcase>\n";
+    }
+    xml += "</testsuite>\n";
+    xml
+}
+
+pub fn markdown(results: &[RomResult], timeout: u64) -> String {
+    let passed = results.iter().filter(|r| matches!(r.outcome, Outcome::Passed)).count();
+    let mut md = format!("**{}/{} passed**\n\n| ROM | Result | Time |\n| --- | --- | --- |\n", passed, results.len());
+    for r in results.iter() {
+        let mark = if matches!(r.outcome, Outcome::Passed) {"✅"} else {"❌"};
+        let result = describe(&r.outcome, timeout).replace('|', "\\|").replace('\n', " ");
+        md += &format!("| {} | {} {} | {:.1} s |\n", r.name, mark, result, r.seconds);
+    }
+    md
+}
+
+// A ROM or a directory searched recursively, timeout is in emulated seconds.
+// Prints a line per ROM and returns true when everything passed.
+pub fn runAll(path: &str, boot: &dyn Fn(&str) -> Result<Z80, String>, timeout: u64, reports: &[Report]) -> bool {
+    let root = Path::new(path);
+    let mut roms = Vec::new();
+    if root.is_dir() {
+        findRoms(root, &mut roms);
+    } else {
+        roms.push(root.to_path_buf());
+    }
+    roms.sort();
+
+    // panics are reported as errors, the default hook would print every one of them
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let mut results = Vec::new();
+    for rom in roms.iter() {
+        // relative to the directory given, a single ROM goes by its file name
+        let name = match rom.strip_prefix(root) {
+            Ok(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new(rom.file_name().unwrap_or_default()),
+        };
+        let r = runRom(rom, &name.to_string_lossy(), boot, timeout);
+        println!("{:<48} {}", r.name, describe(&r.outcome, timeout));
+        results.push(r);
+    }
+    panic::set_hook(hook);
+
+    let passed = results.iter().filter(|r| matches!(r.outcome, Outcome::Passed)).count();
+    println!("{} of {} passed", passed, results.len());
+    for report in reports.iter() {
+        let (file, text) = match report {
+            Report::JUnit(f) => (f, junit(&results, timeout)),
+            Report::Markdown(f) => (f, markdown(&results, timeout)),
+        };
+        if let Err(e) = fs::write(file, text) {
+            println!("{}: {}", file, e);
+        }
+    }
+    passed == results.len()
+}
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use super::cpu::Z80;
+use super::memory::Memory;
+use super::expression::parseNumber;
+
+// Instruction trace in the gameboy-doctor format, one line with the state before each instruction:
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+// --trace runs on the DMG unless --cgb or --sgb is given, so it lines up with gameboy-doctor's logs.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    // ROM bank the PC has to be in, only checked for PCs in ROM
+    pub bank: Option<u16>,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Filter {
+    // "[bank:]start[-end]", numbers need their 0x or $ prefix to be read as hex
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let number = |s: &str| parseNumber(s.trim()).map(|n| n as u16).ok_or(format!("bad number {}", s));
+        let (bank, range) = match text.find(':') {
+            Some(i) => (Some(number(&text[..i])?), &text[i + 1..]),
+            None => (None, text),
+        };
+        let (start, end) = match range.find('-') {
+            Some(i) => (number(&range[..i])?, number(&range[i + 1..])?),
+            None => {let a = number(range)?; (a, a)},
+        };
+        Ok(Self {bank, start, end})
+    }
+
+    pub fn matches(&self, bank: Option<u16>, pc: u16) -> bool {
+        let bankMatches = match (self.bank, bank) {
+            (Some(wanted), Some(b)) => wanted == b,
+            _ => true,
+        };
+        bankMatches && pc >= self.start && pc <= self.end
+    }
+}
+
+pub enum Sink {
+    File(BufWriter<File>),
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+pub struct Tracer {
+    sink: Sink,
+    pub filter: Option<Filter>,
+}
+
+impl Tracer {
+    pub fn toFile(path: &str, filter: Option<Filter>) -> io::Result<Self> {
+        Ok(Self {
+            sink: Sink::File(BufWriter::new(File::create(path)?)),
+            filter,
+        })
+    }
+
+    pub fn toCallback(callback: Box<dyn FnMut(&str)>, filter: Option<Filter>) -> Self {
+        Self {
+            sink: Sink::Callback(callback),
+            filter,
+        }
+    }
+
+    pub fn line<M: Memory>(c: &Z80<M>) -> String {
+        format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            c.a, c.f, c.b, c.c, c.d, c.e, c.h, c.l, c.sp, c.pc,
+            c.peekByte(c.pc), c.peekByte(c.pc.wrapping_add(1)), c.peekByte(c.pc.wrapping_add(2)), c.peekByte(c.pc.wrapping_add(3)))
+    }
+
+    pub fn record<M: Memory>(&mut self, c: &Z80<M>) -> io::Result<()> {
+        if let Some(f) = &self.filter {
+            if !f.matches(c.bus.romBank(c.pc), c.pc) {
+                return Ok(());
+            }
+        }
+        let line = Self::line(c);
+        match &mut self.sink {
+            Sink::File(w) => {writeln!(w, "{}", line)},
+            Sink::Callback(f) => {f(&line); Ok(())},
+        }
+    }
+}
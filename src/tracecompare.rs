@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+
+// Compares our gameboy-doctor trace with a reference log from another emulator and reports
+// the first line that differs. Both files are streamed, so multi gigabyte logs are fine.
+
+pub struct Options {
+    // lines shown before and after the divergence
+    pub context: usize,
+    // both logs are skipped up to the first line at this PC, which drops a boot ROM prefix
+    pub sync: Option<u16>,
+    // fields left out of the comparison, e.g. F on a known flag difference
+    pub ignore: Vec<String>,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self {
+            context: 5,
+            sync: Some(0x0100),
+            ignore: Vec::new(),
+        }
+    }
+}
+
+const FLAGS: [(&str, u8); 4] = [("Z", 7), ("N", 6), ("H", 5), ("C", 4)];
+
+struct Log<R: BufRead> {
+    name: String,
+    lines: Lines<R>,
+    number: usize,
+}
+
+impl Log<BufReader<File>> {
+    fn open(path: &str) -> io::Result<Self> {
+        Ok(Log::new(path, BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> Log<R> {
+    fn new(name: &str, r: R) -> Self {
+        Self {
+            name: String::from(name),
+            lines: r.lines(),
+            number: 0,
+        }
+    }
+
+    fn next(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.lines.next() {
+                Some(line) => {
+                    self.number += 1;
+                    let line = line?;
+                    // blank lines and comments some emulators add are skipped
+                    if !line.trim().is_empty() && !line.starts_with('#') {
+                        return Ok(Some(String::from(line.trim_end())));
+                    }
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Drops lines before the first one at pc, returns how many were skipped
+    fn syncTo(&mut self, pc: u16) -> io::Result<(usize, Option<String>)> {
+        let mut skipped = 0;
+        while let Some(line) = self.next()? {
+            if field(&line, "PC").and_then(|v| u16::from_str_radix(v, 16).ok()) == Some(pc) {
+                return Ok((skipped, Some(line)));
+            }
+            skipped += 1;
+        }
+        Ok((skipped, None))
+    }
+}
+
+fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace().filter_map(|w| {
+        let i = w.find(':')?;
+        Some((&w[..i], &w[i + 1..]))
+    }).collect()
+}
+
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    fields(line).into_iter().find(|(k, _)| *k == name).map(|(_, v)| v)
+}
+
+// Fields that differ as (name, ours, reference), a field only one side has counts as different
+fn differences<'a>(ours: &'a str, reference: &'a str, ignore: &[String]) -> Vec<(&'a str, &'a str, &'a str)> {
+    let (a, b) = (fields(ours), fields(reference));
+    let mut names: Vec<&str> = a.iter().map(|(k, _)| *k).collect();
+    for (k, _) in b.iter() {
+        if !names.contains(k) {
+            names.push(k);
+        }
+    }
+    names.into_iter()
+        .filter(|n| !ignore.iter().any(|i| i.eq_ignore_ascii_case(n)))
+        .filter_map(|n| {
+            let x = a.iter().find(|(k, _)| *k == n).map_or("-", |(_, v)| *v);
+            let y = b.iter().find(|(k, _)| *k == n).map_or("-", |(_, v)| *v);
+            if x.eq_ignore_ascii_case(y) {None} else {Some((n, x, y))}
+        }).collect()
+}
+
+fn describeFlags(ours: &str, reference: &str) -> String {
+    let (x, y) = match (u8::from_str_radix(ours, 16), u8::from_str_radix(reference, 16)) {
+        (Ok(x), Ok(y)) => (x, y),
+        _ => return String::new(),
+    };
+    let flags: Vec<String> = FLAGS.iter()
+        .filter(|(_, b)| (x >> b) & 1 != (y >> b) & 1)
+        .map(|(n, b)| format!("{} {}/{}", n, (x >> b) & 1, (y >> b) & 1))
+        .collect();
+    format!(" ({})", flags.join(", "))
+}
+
+// Prints a report and returns true when the logs match
+pub fn compare(oursPath: &str, referencePath: &str, options: &Options) -> io::Result<bool> {
+    let ours = Log::open(oursPath)?;
+    let reference = Log::open(referencePath)?;
+    compareLogs(ours, reference, options, &mut io::stdout())
+}
+
+// Logs that never reach the sync point or have nothing to compare don't match
+fn compareLogs<A: BufRead, B: BufRead>(mut ours: Log<A>, mut reference: Log<B>, options: &Options, out: &mut dyn Write) -> io::Result<bool> {
+    let (mut a, mut b) = match options.sync {
+        Some(pc) => {
+            let (skippedA, a) = ours.syncTo(pc)?;
+            let (skippedB, b) = reference.syncTo(pc)?;
+            for (log, line) in [(&ours.name, &a), (&reference.name, &b)] {
+                if line.is_none() {
+                    writeln!(out, "{} never reaches PC:{:04X}, use --sync=none to compare from the first line", log, pc)?;
+                }
+            }
+            if a.is_none() || b.is_none() {
+                return Ok(false);
+            }
+            if skippedA > 0 || skippedB > 0 {
+                writeln!(out, "synchronized at PC:{:04X}, skipped {} lines of {} and {} of {}", pc, skippedA, ours.name, skippedB, reference.name)?;
+            }
+            (a, b)
+        },
+        None => (ours.next()?, reference.next()?),
+    };
+
+    let mut before: VecDeque<(usize, String)> = VecDeque::new();
+    let mut compared = 0;
+    loop {
+        let (x, y) = match (&a, &b) {
+            (None, None) if compared == 0 => {
+                writeln!(out, "both logs are empty, nothing compared")?;
+                return Ok(false);
+            },
+            (None, None) => {
+                writeln!(out, "{} lines compared, no differences", compared)?;
+                return Ok(true);
+            },
+            (Some(x), Some(y)) => (x, y),
+            (Some(_), None) => {
+                writeln!(out, "{} ended after {} lines, {} goes on from line {}", reference.name, compared, ours.name, ours.number)?;
+                return Ok(false);
+            },
+            (None, Some(_)) => {
+                writeln!(out, "{} ended after {} lines, {} goes on from line {}", ours.name, compared, reference.name, reference.number)?;
+                return Ok(false);
+            },
+        };
+        let diff = differences(x, y, &options.ignore);
+        if !diff.is_empty() {
+            writeln!(out, "first difference after {} matching lines, line {} of {} and line {} of {}",
+                compared, ours.number, ours.name, reference.number, reference.name)?;
+            writeln!(out)?;
+            for (n, line) in before.iter() {
+                writeln!(out, "  {:>8}  {}", n, line)?;
+            }
+            writeln!(out, "- {:>8}  {}", ours.number, x)?;
+            writeln!(out, "+ {:>8}  {}", reference.number, y)?;
+            writeln!(out)?;
+            for (name, x, y) in diff.iter() {
+                let flags = if *name == "F" {describeFlags(x, y)} else {String::new()};
+                writeln!(out, "  {}: {} (ours) vs {} (reference){}", name, x, y, flags)?;
+            }
+            writeln!(out)?;
+            // what each side did next, usually enough to see where they went apart
+            for _ in 0..options.context {
+                let (x, y) = (ours.next()?, reference.next()?);
+                if x.is_none() && y.is_none() {
+                    break;
+                }
+                writeln!(out, "- {:>8}  {}", ours.number, x.unwrap_or_default())?;
+                writeln!(out, "+ {:>8}  {}", reference.number, y.unwrap_or_default())?;
+            }
+            return Ok(false);
+        }
+        compared += 1;
+        before.push_back((ours.number, a.take().unwrap()));
+        if before.len() > options.context {
+            before.pop_front();
+        }
+        a = ours.next()?;
+        b = reference.next()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compareLogs, Log, Options};
+
+    const BOOT: &str = "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0000 PCMEM:31,FE,FF,AF";
+    const LINES: [&str; 3] = [
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,CE",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:FE,11,20,03",
+    ];
+
+    // The report and whether the logs matched
+    fn run(ours: &[&str], reference: &[&str], options: &Options) -> (String, bool) {
+        let (a, b) = (ours.join("\n"), reference.join("\n"));
+        let mut out = Vec::new();
+        let same = compareLogs(Log::new("ours", a.as_bytes()), Log::new("ref", b.as_bytes()), options, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), same)
+    }
+
+    #[test]
+    fn identicalAfterSync() {
+        let (report, same) = run(&[BOOT, BOOT, LINES[0], LINES[1]], &LINES[..2], &Options::new());
+        assert!(same);
+        assert!(report.contains("skipped 2 lines of ours and 0 of ref"));
+        assert!(report.contains("2 lines compared, no differences"));
+    }
+
+    #[test]
+    fn firstDivergence() {
+        let changed = LINES[2].replace("A:01", "A:02").replace("F:B0", "F:30");
+        let (report, same) = run(&LINES, &[LINES[0], LINES[1], &changed], &Options::new());
+        assert!(!same);
+        assert!(report.contains("first difference after 2 matching lines, line 3 of ours and line 3 of ref"));
+        assert!(report.contains("A: 01 (ours) vs 02 (reference)"));
+        assert!(report.contains("F: B0 (ours) vs 30 (reference) (Z 1/0)"));
+    }
+
+    #[test]
+    fn flagBreakdown() {
+        let changed = LINES[0].replace("F:B0", "F:60");
+        let (report, _) = run(&LINES[..1], &[&changed], &Options::new());
+        assert!(report.contains("F: B0 (ours) vs 60 (reference) (Z 1/0, N 0/1, C 1/0)"));
+    }
+
+    #[test]
+    fn oneLogEndsEarly() {
+        let (report, same) = run(&LINES[..2], &LINES, &Options::new());
+        assert!(!same);
+        assert!(report.contains("ours ended after 2 lines, ref goes on from line 3"));
+    }
+
+    #[test]
+    fn ignoredFields() {
+        let changed = LINES[1].replace("F:B0", "F:00");
+        let mut options = Options::new();
+        assert!(!run(&LINES[..2], &[LINES[0], &changed], &options).1);
+        options.ignore = vec![String::from("f")];
+        assert!(run(&LINES[..2], &[LINES[0], &changed], &options).1);
+    }
+
+    #[test]
+    fn missingSyncPointFails() {
+        let (report, same) = run(&[BOOT], &[BOOT], &Options::new());
+        assert!(!same);
+        assert!(report.contains("ours never reaches PC:0100"));
+        assert!(report.contains("ref never reaches PC:0100"));
+
+        let mut options = Options::new();
+        options.sync = None;
+        assert!(run(&[BOOT], &[BOOT], &options).1);
+    }
+
+    #[test]
+    fn emptyLogsFail() {
+        let mut options = Options::new();
+        options.sync = None;
+        let (report, same) = run(&[], &["# nothing but a comment"], &options);
+        assert!(!same);
+        assert!(report.contains("nothing compared"));
+    }
+}
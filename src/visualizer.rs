@@ -1,9 +1,15 @@
 #![allow(non_snake_case)]
 extern crate sfml;
-use crate::cpu::{Z80, Flags, UNPREFIXED_INSTRUCTION_TABLE, PREFIXED_INSTRUCTION_TABLE};
+use crate::cpu::{Z80, Flags};
+use crate::opcodes;
+use crate::gpu::{colorToRgba, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::sgb::{SGB_WIDTH, SGB_HEIGHT};
+use crate::bus::Model;
+use crate::callstack::FrameKind;
+use crate::cdl;
 use sfml::{
     graphics::{
-        Text, RenderTarget, RenderWindow, Color, Font, Transformable
+        Text, RenderTarget, RenderWindow, Color, Font, Transformable, Texture, Sprite
     },
 };
 
@@ -13,11 +19,18 @@ pub fn showRam(c: &Z80, startIndex: u16, nRows: u16, nCols: u16) -> String {
     let mut nStr = String::new();
     let mut n: u16 = 0;
     for _i in 0..nRows {
-        let addr = startIndex + n;
+        let addr = startIndex.wrapping_add(n);
         nStr.push_str(&format!("{:#06X}:\t", addr));
+        let mut labels = Vec::new();
         for j in 0..nCols {
-            nStr.push_str(&format!("{:02x} ", c.readByte(addr + (j as u16))));
-            n += 1;
+            nStr.push_str(&format!("{:02x} ", c.peekByte(addr.wrapping_add(j))));
+            if let Some(label) = c.labelAt(addr.wrapping_add(j)) {
+                labels.push(label);
+            }
+            n = n.wrapping_add(1);
+        }
+        if !labels.is_empty() {
+            nStr.push_str(&format!("; {}", labels.join(" ")));
         }
         nStr.push('\n');
     }
@@ -47,29 +60,67 @@ pub fn showRegisters(c: &Z80) -> String {
 pub fn showCode(c: &Z80, startIndex: u16, nInstructions: u16) -> String{
     let mut nStr = String::new();
     let mut addr = startIndex;
-    let mut prefixed = false;
     let mut opcodeLen = 0;
     for _i in 0..nInstructions {
-        addr += opcodeLen;
+        addr = addr.wrapping_add(opcodeLen);
+        if let Some(label) = c.labelAt(addr) {
+            nStr.push_str(&format!("{}:\n", label));
+        }
         nStr.push_str(&format!("{:#06X}\t", addr));
-        let (name, length, cycles) = if prefixed {PREFIXED_INSTRUCTION_TABLE[c.readByte(addr) as usize]} else {UNPREFIXED_INSTRUCTION_TABLE[c.readByte(addr) as usize]};
-
-        if name == "CB" && !prefixed {
-            prefixed = true;
-        } else {
-            prefixed = false;
+        // bytes the code/data log only saw read are data, not instructions
+        let flags = c.bus.cdlFlags(addr);
+        if flags & (cdl::OPCODE | cdl::OPERAND) == 0 && flags & cdl::DATA != 0 {
+            nStr.push_str(&format!("DB #{:#04X}\n", c.peekByte(addr)));
+            opcodeLen = 1;
+            continue;
         }
-        match length {
-            3 => {nStr.push_str(&format!("{} #{:#06X} [{}]", name, c.readBytes(addr + 1), cycles))},
-            2 => {nStr.push_str(&format!("{} #{:#04X}[{}]", name, c.readByte(addr + 1), cycles))},
+        // a CB prefixed instruction is shown as one, its operand bytes follow the second opcode byte
+        let first = c.peekByte(addr);
+        let (o, operandAddr) = if first == 0xCB {
+            (opcodes::get(true, c.peekByte(addr.wrapping_add(1))), addr.wrapping_add(2))
+        } else {
+            (opcodes::get(false, first), addr.wrapping_add(1))
+        };
+        let name = o.name();
+        // M-cycles, taken/not taken for conditional instructions
+        let cycles = if o.isConditional() {format!("{}/{}", o.cycles, o.cyclesNotTaken)} else {o.cycles.to_string()};
+        match (o.length as u16).wrapping_sub(operandAddr.wrapping_sub(addr)) {
+            2 => {
+                let operand = c.peekBytes(operandAddr);
+                let label = c.labelAt(operand).map(|l| format!(" ({})", l)).unwrap_or_default();
+                nStr.push_str(&format!("{} #{:#06X}{} [{}]", name, operand, label, cycles))
+            },
+            1 => {nStr.push_str(&format!("{} #{:#04X}[{}]", name, c.peekByte(operandAddr), cycles))},
             _ => {nStr.push_str(&format!("{} [{}]", name, cycles))},
         }
-        opcodeLen = length as u16;
+        opcodeLen = o.length as u16;
         nStr.push('\n');
     }
     return nStr;
 }
 
+// Innermost frame first, each line shows where the frame was entered from
+pub fn showCallStack(c: &Z80) -> String {
+    let location = |addr: u16| match c.symbolAt(addr) {
+        Some(s) => format!("{:#06X} {}", addr, s),
+        None => format!("{:#06X}", addr),
+    };
+    let mut nStr = String::new();
+    nStr.push_str(&format!("#0 {}\n", location(c.pc)));
+    for (i, f) in c.callStack.frames().iter().rev().enumerate() {
+        let kind = match f.kind {
+            FrameKind::Call => "call",
+            FrameKind::Rst => "rst",
+            FrameKind::Interrupt => "interrupt",
+        };
+        nStr.push_str(&format!("#{} {} {} {}\n", i + 1, location(f.caller), kind, location(f.target)));
+    }
+    if let Some(m) = c.callStack.lastMismatch {
+        nStr.push_str(&format!("{} mismatched returns, last at {:#06X} to {:#06X}\n", c.callStack.mismatches, m.at, m.target));
+    }
+    nStr
+}
+
 pub fn showTimers(c: &Z80) -> String {
     let mut nStr = String::new();
     nStr.push_str(&format!("DIV [{:b}]\n", c.bus.timerRegisters.divRegister));
@@ -133,6 +184,13 @@ pub fn renderFullDissassembly(c: &Z80, ramPage1: u16, ramPage2: u16, f: &Font, w
     codeText.set_character_size(CHAR_SIZE);
     codeText.set_fill_color(Color::WHITE);
     codeText.set_position((timerText.position().x, timerText.local_bounds().height + timerText.position().y + 20.0));
+
+    let mut callStackText = Text::default();
+    callStackText.set_font(f);
+    callStackText.set_string(&showCallStack(c));
+    callStackText.set_character_size(CHAR_SIZE);
+    callStackText.set_fill_color(Color::WHITE);
+    callStackText.set_position((codeText.position().x + codeText.local_bounds().width + 20.0, codeText.position().y));
     
     /*
     let mut registerBinaryText = Text::default();
@@ -151,6 +209,31 @@ pub fn renderFullDissassembly(c: &Z80, ramPage1: u16, ramPage2: u16, f: &Font, w
     w.draw(&timerText);
     
     w.draw(&codeText);
+    w.draw(&callStackText);
     //w.draw(&registerBinaryText);
     
-}
\ No newline at end of file
+}
+
+// Draws a frame of 15 bit colors, scaled, with its top left corner at (x, y)
+pub fn renderFrame(frame: &[u16], width: usize, height: usize, x: f32, y: f32, scale: f32, w: &mut RenderWindow) {
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for color in frame.iter() {
+        pixels.extend_from_slice(&colorToRgba(*color));
+    }
+    let mut texture = Texture::new(width as u32, height as u32).unwrap();
+    unsafe {
+        texture.update_from_pixels(&pixels, width as u32, height as u32, 0, 0);
+    }
+    let mut sprite = Sprite::with_texture(&texture);
+    sprite.set_position((x, y));
+    sprite.set_scale((scale, scale));
+    w.draw(&sprite);
+}
+
+pub fn renderScreen(c: &Z80, x: f32, y: f32, w: &mut RenderWindow) {
+    if c.bus.model == Model::Sgb {
+        renderFrame(&c.bus.sgb.frame, SGB_WIDTH, SGB_HEIGHT, x, y, 2.0, w);
+    } else {
+        renderFrame(&c.bus.gpu.frameBuffer, SCREEN_WIDTH, SCREEN_HEIGHT, x, y, 2.0, w);
+    }
+}